@@ -22,10 +22,10 @@ fn unsafe_code() {
     handlers
         .into_iter()
         .for_each(|handle| handle.join().unwrap());
-    unsafe {
-        // every time a different result is returned -> data race
-        println!("{}", COUNTER);
-    }
+    // copy the value out, taking a reference to a static mut is itself an error in newer rust
+    let counter = unsafe { COUNTER };
+    // every time a different result is returned -> data race
+    println!("{}", counter);
 }
 
 fn atomic_safe_code() {
@@ -0,0 +1,109 @@
+// The syntax tree the parser builds. every expression knows its type (checked while parsing)
+// and where it came from in the source (its span), the evaluator and explain rely on both.
+
+// byte offsets into the policy source, end is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // from the start of self to the end of other
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    // allow * if ...
+    Any,
+    Named(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub effect: Effect,
+    pub action: Action,
+    // None for a rule without if, it always applies to its action
+    pub condition: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Str,
+    Bool,
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Str => write!(f, "string"),
+            Type::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+// everything a rule can look at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attribute {
+    // the fields of users::User
+    Username,
+    Email,
+    Role,
+    // the fields E3::SomethingElse adds
+    Name,
+    LoggedIn,
+    CanBeAdmin,
+    // resource.<anything>, given with the request
+    Resource(String),
+    // the action being asked for
+    Action,
+}
+
+impl Attribute {
+    pub fn ty(&self) -> Type {
+        match self {
+            Attribute::LoggedIn | Attribute::CanBeAdmin => Type::Bool,
+            _ => Type::Str,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    Str(String),
+    Bool(bool),
+    // an attribute that has no value, e.g. can_be_admin: None
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Attribute(Attribute),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    // None only for the literal none, it compares with anything
+    pub ty: Option<Type>,
+}
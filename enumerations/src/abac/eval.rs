@@ -0,0 +1,346 @@
+// Evaluates a parsed policy against one request.
+//
+// deny wins: if any deny rule matches the request is denied, otherwise the first allow
+// that matches grants it, and when nothing matches it is denied.
+// an attribute the subject does not have (E3::Admin has no username) is none,
+// none is false where a condition is expected, so can_be_admin: None never grants anything.
+// none is not equal to anything, not even to none, and not unequal either: == and != are
+// both false when a side is missing. otherwise resource.owner == user.username would let
+// a subject without a username edit every resource without an owner.
+
+use std::{collections::HashMap, fmt};
+
+use super::{
+    ast::{Action, Attribute, Effect, Expr, ExprKind, Literal},
+    Policy,
+};
+use crate::{E2, E3};
+
+// what a rule is evaluated against
+pub struct Request<'a> {
+    pub subject: &'a E3,
+    pub action: String,
+    pub resource: HashMap<String, String>,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(subject: &'a E3, action: &str) -> Request<'a> {
+        Request {
+            subject,
+            action: action.to_string(),
+            resource: HashMap::new(),
+        }
+    }
+
+    // Request::new(&subject, "edit").resource("owner", "bob")
+    pub fn resource(mut self, key: &str, value: &str) -> Request<'a> {
+        self.resource.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    fn attribute(&self, attribute: &Attribute) -> Value {
+        let user = match self.subject {
+            E3::SomethingElse { user, .. } => Some(user),
+            _ => None,
+        };
+        let string = |s: Option<&String>| s.map_or(Value::None, |s| Value::Str(s.clone()));
+        match (attribute, self.subject) {
+            (Attribute::Username, _) => string(user.map(|user| &user.username)),
+            (Attribute::Email, _) => string(user.map(|user| user.email.to_string()).as_ref()),
+            (Attribute::Role, E3::Admin) => Value::Str("admin".to_string()),
+            (Attribute::Role, E3::User) => Value::Str("user".to_string()),
+            (Attribute::Role, E3::SomethingElse { user, .. }) => Value::Str(user.role.to_string()),
+            (Attribute::Name, E3::SomethingElse { name, .. }) => Value::Str(name.clone()),
+            (Attribute::LoggedIn, E3::SomethingElse { login, .. }) => {
+                Value::Bool(*login == E2::LoggedIn)
+            }
+            (
+                Attribute::CanBeAdmin,
+                E3::SomethingElse {
+                    can_be_admin: Some(can),
+                    ..
+                },
+            ) => Value::Bool(*can),
+            (Attribute::Resource(key), _) => string(self.resource.get(key)),
+            (Attribute::Action, _) => Value::Str(self.action.clone()),
+            _ => Value::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    Bool(bool),
+    None,
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        *self == Value::Bool(true)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::None => write!(f, "none"),
+        }
+    }
+}
+
+// rule numbers are indexes into Policy::rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow { rule: usize },
+    // rule is None when no rule matched at all
+    Deny { rule: Option<usize> },
+}
+
+impl Decision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allow { .. })
+    }
+}
+
+// the decision and every step that led to it, printed by Display
+#[derive(Debug)]
+pub struct Explanation {
+    pub decision: Decision,
+    lines: Vec<String>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{line}")?;
+        }
+        match self.decision {
+            Decision::Allow { rule } => write!(f, "allowed by rule {}", rule + 1),
+            Decision::Deny { rule: Some(rule) } => write!(f, "denied by rule {}", rule + 1),
+            Decision::Deny { rule: None } => write!(f, "denied, no rule matched"),
+        }
+    }
+}
+
+impl Policy {
+    pub fn decide(&self, request: &Request) -> Decision {
+        self.run(request, None)
+    }
+
+    // evaluates every rule (decide stops at the first deny) and records each step
+    pub fn explain(&self, request: &Request) -> Explanation {
+        let mut lines = Vec::new();
+        let decision = self.run(request, Some(&mut lines));
+        Explanation { decision, lines }
+    }
+
+    fn run(&self, request: &Request, mut trace: Option<&mut Vec<String>>) -> Decision {
+        let mut decision = None;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if let Some(lines) = trace.as_deref_mut() {
+                lines.push(format!("rule {}: {}", index + 1, self.text(rule.span)));
+            }
+            let applies = match &rule.action {
+                Action::Any => true,
+                Action::Named(name) => *name == request.action,
+            };
+            let matched = applies
+                && match &rule.condition {
+                    Some(condition) => self.eval(condition, request, &mut trace, 1).is_true(),
+                    None => true,
+                };
+            if let Some(lines) = trace.as_deref_mut() {
+                lines.push(match (applies, matched) {
+                    (false, _) => format!("  skipped, the action is {:?}", request.action),
+                    (true, true) => "  matches".to_string(),
+                    (true, false) => "  does not match".to_string(),
+                });
+            }
+            if !matched {
+                continue;
+            }
+            match rule.effect {
+                Effect::Deny if trace.is_none() => return Decision::Deny { rule: Some(index) },
+                Effect::Deny => {
+                    // keep going so the explanation covers every rule, the first deny still wins
+                    if !matches!(decision, Some(Decision::Deny { .. })) {
+                        decision = Some(Decision::Deny { rule: Some(index) });
+                    }
+                }
+                Effect::Allow => {
+                    if decision.is_none() {
+                        decision = Some(Decision::Allow { rule: index });
+                    }
+                }
+            }
+        }
+        decision.unwrap_or(Decision::Deny { rule: None })
+    }
+
+    fn text(&self, span: super::ast::Span) -> &str {
+        &self.source[span.start..span.end]
+    }
+
+    fn eval(
+        &self,
+        expr: &Expr,
+        request: &Request,
+        trace: &mut Option<&mut Vec<String>>,
+        depth: usize,
+    ) -> Value {
+        let value = match &expr.kind {
+            ExprKind::Literal(Literal::Str(s)) => return Value::Str(s.clone()),
+            ExprKind::Literal(Literal::Bool(b)) => return Value::Bool(*b),
+            ExprKind::Literal(Literal::None) => return Value::None,
+            ExprKind::Attribute(attribute) => return request.attribute(attribute),
+            ExprKind::Not(operand) => {
+                Value::Bool(!self.eval(operand, request, trace, depth + 1).is_true())
+            }
+            // and/or stop as soon as the answer is known, the skipped side is not in the trace
+            ExprKind::And(left, right) => Value::Bool(
+                self.eval(left, request, trace, depth + 1).is_true()
+                    && self.eval(right, request, trace, depth + 1).is_true(),
+            ),
+            ExprKind::Or(left, right) => Value::Bool(
+                self.eval(left, request, trace, depth + 1).is_true()
+                    || self.eval(right, request, trace, depth + 1).is_true(),
+            ),
+            ExprKind::Eq(left, right) | ExprKind::Ne(left, right) => {
+                let l = self.eval(left, request, trace, depth + 1);
+                let r = self.eval(right, request, trace, depth + 1);
+                let result = if l == Value::None || r == Value::None {
+                    Value::Bool(false)
+                } else {
+                    Value::Bool((l == r) == matches!(expr.kind, ExprKind::Eq(..)))
+                };
+                if let Some(lines) = trace.as_deref_mut() {
+                    lines.push(format!(
+                        "{:indent$}{}  ({l} vs {r}) -> {result}",
+                        "",
+                        self.text(expr.span),
+                        indent = depth * 2
+                    ));
+                }
+                return result;
+            }
+        };
+        if let Some(lines) = trace.as_deref_mut() {
+            lines.push(format!(
+                "{:indent$}{} -> {value}",
+                "",
+                self.text(expr.span),
+                indent = depth * 2
+            ));
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use users::{Role, User};
+
+    const POLICY: &str = "
+        # owners and admins may edit
+        allow edit if user.role == admin or resource.owner == user.username
+        allow promote if subject.can_be_admin == true and subject.logged_in
+        deny * if not subject.logged_in
+    ";
+
+    fn someone(username: &str, role: Role, login: E2, can_be_admin: Option<bool>) -> E3 {
+        E3::SomethingElse {
+            name: username.to_string(),
+            user: User::new(username, &format!("{username}@localhost"), "password", role),
+            login,
+            can_be_admin,
+        }
+    }
+
+    #[test]
+    fn owners_and_admins_may_edit() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let bob = someone("bob", Role::User, E2::LoggedIn, None);
+        let alice = someone("alice", Role::User, E2::LoggedIn, None);
+
+        let edit_bobs = |subject: &E3| {
+            policy
+                .decide(&Request::new(subject, "edit").resource("owner", "bob"))
+                .is_allowed()
+        };
+        assert!(edit_bobs(&bob));
+        assert!(!edit_bobs(&alice));
+        assert!(edit_bobs(&someone("root", Role::Admin, E2::LoggedIn, None)));
+        // E3::Admin carries no login, none is not logged in
+        assert!(!edit_bobs(&E3::Admin));
+        assert_eq!(
+            policy.decide(&Request::new(&alice, "delete")),
+            Decision::Deny { rule: None }
+        );
+    }
+
+    #[test]
+    fn can_be_admin_none_and_false_never_grant() {
+        let policy = Policy::parse(POLICY).unwrap();
+        for (can_be_admin, allowed) in [(Some(true), true), (Some(false), false), (None, false)] {
+            let subject = someone("bob", Role::User, E2::LoggedIn, can_be_admin);
+            assert_eq!(
+                policy
+                    .decide(&Request::new(&subject, "promote"))
+                    .is_allowed(),
+                allowed,
+                "{can_be_admin:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_an_earlier_allow() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let bob = someone("bob", Role::Admin, E2::NotLoggedIn, None);
+        let request = Request::new(&bob, "edit").resource("owner", "bob");
+        assert_eq!(policy.decide(&request), Decision::Deny { rule: Some(2) });
+        assert_eq!(policy.explain(&request).decision, policy.decide(&request));
+    }
+
+    #[test]
+    fn none_is_neither_equal_nor_unequal() {
+        let policy = Policy::parse(
+            "allow edit if resource.owner == user.username\n\
+             allow view if resource.owner != user.username",
+        )
+        .unwrap();
+        // no owner on the request and no username on E3::Admin, none == none is not a match
+        for action in ["edit", "view"] {
+            assert!(!policy
+                .decide(&Request::new(&E3::Admin, action))
+                .is_allowed());
+        }
+        let bob = someone("bob", Role::User, E2::LoggedIn, None);
+        assert!(!policy.decide(&Request::new(&bob, "edit")).is_allowed());
+        assert!(!policy.decide(&Request::new(&bob, "view")).is_allowed());
+        assert!(policy
+            .decide(&Request::new(&bob, "view").resource("owner", "alice"))
+            .is_allowed());
+    }
+
+    #[test]
+    fn explain_shows_each_step() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let alice = someone("alice", Role::User, E2::LoggedIn, None);
+        let request = Request::new(&alice, "edit").resource("owner", "bob");
+        let explanation = policy.explain(&request).to_string();
+        assert!(explanation.contains("  user.role == admin  (\"user\" vs \"admin\") -> false"));
+        assert!(explanation
+            .contains("  resource.owner == user.username  (\"bob\" vs \"alice\") -> false"));
+        assert!(explanation.contains("rule 2: allow promote if"));
+        assert!(explanation.contains("  skipped, the action is \"edit\""));
+        assert!(
+            explanation.ends_with("denied, no rule matched"),
+            "{explanation}"
+        );
+    }
+}
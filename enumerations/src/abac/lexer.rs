@@ -0,0 +1,210 @@
+// Turns the policy text into tokens. keywords come out as Ident, the parser tells them apart.
+// # starts a comment that runs to the end of the line.
+
+use super::{ast::Span, ParseError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident(String),
+    Str(String),
+    Dot,
+    Star,
+    LParen,
+    RParen,
+    EqEq,
+    NotEq,
+    Eof,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::Ident(name) => write!(f, "'{name}'"),
+            TokenKind::Str(s) => write!(f, "\"{s}\""),
+            TokenKind::Dot => write!(f, "'.'"),
+            TokenKind::Star => write!(f, "'*'"),
+            TokenKind::LParen => write!(f, "'('"),
+            TokenKind::RParen => write!(f, "')'"),
+            TokenKind::EqEq => write!(f, "'=='"),
+            TokenKind::NotEq => write!(f, "'!='"),
+            TokenKind::Eof => write!(f, "the end of the policy"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+// actions like reset-password read better with a dash
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+// always ends with an Eof token so the parser never runs off the end
+pub fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let single = |kind| Token {
+            kind,
+            span: Span {
+                start,
+                end: start + 1,
+            },
+        };
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '.' => tokens.push(single(TokenKind::Dot)),
+            '*' => tokens.push(single(TokenKind::Star)),
+            '(' => tokens.push(single(TokenKind::LParen)),
+            ')' => tokens.push(single(TokenKind::RParen)),
+            '=' | '!' => {
+                let kind = if c == '=' {
+                    TokenKind::EqEq
+                } else {
+                    TokenKind::NotEq
+                };
+                if chars.next_if(|&(_, c)| c == '=').is_none() {
+                    let message = if c == '=' {
+                        "expected '==', a single '=' is not a comparison"
+                    } else {
+                        "expected '!=', use not to negate"
+                    };
+                    return Err(ParseError::new(
+                        source,
+                        Span {
+                            start,
+                            end: start + 1,
+                        },
+                        message,
+                    ));
+                }
+                tokens.push(Token {
+                    kind,
+                    span: Span {
+                        start,
+                        end: start + 2,
+                    },
+                });
+            }
+            '"' => {
+                let mut value = String::new();
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => break Some(i + 1),
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c @ ('"' | '\\'))) => value.push(c),
+                            Some((i, _)) => {
+                                return Err(ParseError::new(
+                                    source,
+                                    Span {
+                                        start: i - 1,
+                                        end: i + 1,
+                                    },
+                                    "only \\\" and \\\\ can be escaped",
+                                ))
+                            }
+                            None => break None,
+                        },
+                        Some((_, '\n')) | None => break None,
+                        Some((_, c)) => value.push(c),
+                    }
+                };
+                let Some(end) = end else {
+                    return Err(ParseError::new(
+                        source,
+                        Span {
+                            start,
+                            end: start + 1,
+                        },
+                        "this string is never closed",
+                    ));
+                };
+                tokens.push(Token {
+                    kind: TokenKind::Str(value),
+                    span: Span { start, end },
+                });
+            }
+            c if is_ident_start(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| is_ident_char(c)) {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Ident(source[start..end].to_string()),
+                    span: Span { start, end },
+                });
+            }
+            c => {
+                return Err(ParseError::new(
+                    source,
+                    Span {
+                        start,
+                        end: start + c.len_utf8(),
+                    },
+                    format!("unexpected character '{c}'"),
+                ))
+            }
+        }
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span {
+            start: source.len(),
+            end: source.len(),
+        },
+    });
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn splits_a_rule_into_tokens() {
+        let ident = |s: &str| TokenKind::Ident(s.to_string());
+        assert_eq!(
+            kinds("allow reset-password if (user.role != \"a \\\"b\\\"\") # why\n*"),
+            [
+                ident("allow"),
+                ident("reset-password"),
+                ident("if"),
+                TokenKind::LParen,
+                ident("user"),
+                TokenKind::Dot,
+                ident("role"),
+                TokenKind::NotEq,
+                TokenKind::Str("a \"b\"".to_string()),
+                TokenKind::RParen,
+                TokenKind::Star,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_character() {
+        let error = tokenize("allow edit if user.role = admin").unwrap_err();
+        assert_eq!(error.span, Span { start: 24, end: 25 });
+        let error = tokenize("allow edit\nif user.name == \"bob").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 17));
+        assert!(tokenize("allow edit if user.role == admin & true").is_err());
+    }
+}
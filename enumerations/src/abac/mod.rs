@@ -0,0 +1,77 @@
+// Attribute based access control, a small policy language for rules the fixed roles of
+// access.rs cannot express:
+//
+// allow edit if user.role == admin or resource.owner == user.username
+// allow promote if subject.can_be_admin == true
+// deny * if not subject.logged_in
+//
+// user.* are the fields of users::User, subject.* the fields E3::SomethingElse adds and
+// resource.* whatever the request brings along. the text goes through the lexer (tokens),
+// the parser (a typed ast) and is then evaluated, explain prints every step of the evaluation.
+
+use std::fmt;
+
+mod ast;
+mod eval;
+mod lexer;
+mod parser;
+
+pub use ast::{Action, Attribute, Effect, Expr, ExprKind, Literal, Rule, Span, Type};
+pub use eval::{Decision, Explanation, Request, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+    // 1 based, counted in characters, for people
+    pub line: usize,
+    pub column: usize,
+}
+
+impl ParseError {
+    fn new(source: &str, span: Span, message: impl Into<String>) -> ParseError {
+        let before = &source[..span.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .map_or(0, |line| line.chars().count())
+            + 1;
+        ParseError {
+            message: message.into(),
+            span,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+pub struct Policy {
+    // kept for explain, which quotes the rules
+    source: String,
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(source: &str) -> Result<Policy, ParseError> {
+        let tokens = lexer::tokenize(source)?;
+        let rules = parser::Parser::new(source, tokens).policy()?;
+        Ok(Policy {
+            source: source.to_string(),
+            rules,
+        })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
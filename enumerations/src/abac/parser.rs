@@ -0,0 +1,392 @@
+// A recursive descent parser, one function per level of the grammar, lowest precedence first:
+//
+// policy     = rule*
+// rule       = ("allow" | "deny") (action | "*") ("if" or)?
+// or         = and ("or" and)*
+// and        = not ("and" not)*
+// not        = "not" not | comparison
+// comparison = primary (("==" | "!=") primary)?
+// primary    = string | true | false | none | attribute | word | "(" or ")"
+//
+// types are checked on the way, a policy that parses never fails to evaluate.
+// a word that is not a keyword or attribute (admin in user.role == admin) is a string.
+// every ( and not, and every and/or in a chain, makes the tree one level deeper. parsing
+// and evaluating recurse once per level, so the depth is limited (MAX_DEPTH), a policy
+// file full of ((((( would otherwise overflow the stack.
+
+use super::{
+    ast::{Action, Attribute, Effect, Expr, ExprKind, Literal, Rule, Span, Type},
+    lexer::{Token, TokenKind},
+    ParseError,
+};
+
+const MAX_DEPTH: usize = 128;
+
+const KEYWORDS: &[&str] = &[
+    "allow", "deny", "if", "and", "or", "not", "true", "false", "none",
+];
+
+pub struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a str, tokens: Vec<Token>) -> Parser<'a> {
+        Parser {
+            source,
+            tokens,
+            position: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        // stay on Eof once there
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(name) if name == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> Option<Span> {
+        self.is_keyword(keyword).then(|| self.next().span)
+    }
+
+    // one level deeper, the caller puts self.depth back when it is done
+    fn deeper(&mut self, span: Span) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(
+                span,
+                format!("nested too deeply, at most {MAX_DEPTH} levels of (), not, and, or"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> ParseError {
+        ParseError::new(self.source, span, message)
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let token = self.peek();
+        self.error(
+            token.span,
+            format!("expected {expected}, found {}", token.kind),
+        )
+    }
+
+    pub fn policy(&mut self) -> Result<Vec<Rule>, ParseError> {
+        let mut rules = Vec::new();
+        while self.peek().kind != TokenKind::Eof {
+            rules.push(self.rule()?);
+        }
+        Ok(rules)
+    }
+
+    fn rule(&mut self) -> Result<Rule, ParseError> {
+        let (effect, start) = if let Some(span) = self.eat_keyword("allow") {
+            (Effect::Allow, span)
+        } else if let Some(span) = self.eat_keyword("deny") {
+            (Effect::Deny, span)
+        } else {
+            return Err(self.unexpected("allow or deny"));
+        };
+
+        let token = self.next();
+        let action = match token.kind {
+            TokenKind::Star => Action::Any,
+            TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => Action::Named(name),
+            kind => {
+                return Err(self.error(token.span, format!("expected an action or *, found {kind}")))
+            }
+        };
+        let mut end = token.span;
+
+        let condition = match self.eat_keyword("if") {
+            Some(_) => {
+                let condition = self.or()?;
+                self.expect_bool(&condition)?;
+                end = condition.span;
+                Some(condition)
+            }
+            None => None,
+        };
+        Ok(Rule {
+            effect,
+            action,
+            condition,
+            span: start.to(end),
+        })
+    }
+
+    fn expect_bool(&self, expr: &Expr) -> Result<(), ParseError> {
+        match expr.ty {
+            Some(Type::Bool) => Ok(()),
+            Some(ty) => Err(self.error(
+                expr.span,
+                format!("expected a condition (bool), found a {ty}"),
+            )),
+            None => Err(self.error(expr.span, "expected a condition (bool), found none")),
+        }
+    }
+
+    fn binary(&self, kind: fn(Box<Expr>, Box<Expr>) -> ExprKind, left: Expr, right: Expr) -> Expr {
+        Expr {
+            span: left.span.to(right.span),
+            kind: kind(Box::new(left), Box::new(right)),
+            ty: Some(Type::Bool),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let mut left = self.and()?;
+        while let Some(span) = self.eat_keyword("or") {
+            self.deeper(span)?;
+            let right = self.and()?;
+            self.expect_bool(&left)?;
+            self.expect_bool(&right)?;
+            left = self.binary(ExprKind::Or, left, right);
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let mut left = self.not()?;
+        while let Some(span) = self.eat_keyword("and") {
+            self.deeper(span)?;
+            let right = self.not()?;
+            self.expect_bool(&left)?;
+            self.expect_bool(&right)?;
+            left = self.binary(ExprKind::And, left, right);
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        match self.eat_keyword("not") {
+            Some(start) => {
+                self.deeper(start)?;
+                let operand = self.not()?;
+                self.depth -= 1;
+                self.expect_bool(&operand)?;
+                Ok(Expr {
+                    span: start.to(operand.span),
+                    kind: ExprKind::Not(Box::new(operand)),
+                    ty: Some(Type::Bool),
+                })
+            }
+            None => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let left = self.primary()?;
+        let kind: fn(Box<Expr>, Box<Expr>) -> ExprKind = match self.peek().kind {
+            TokenKind::EqEq => ExprKind::Eq,
+            TokenKind::NotEq => ExprKind::Ne,
+            _ => return Ok(left),
+        };
+        self.next();
+        let right = self.primary()?;
+        if let (Some(l), Some(r)) = (left.ty, right.ty) {
+            if l != r {
+                return Err(self.error(
+                    left.span.to(right.span),
+                    format!("cannot compare a {l} with a {r}"),
+                ));
+            }
+        }
+        Ok(self.binary(kind, left, right))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let token = self.next();
+        let literal = |literal: Literal, ty| {
+            Ok(Expr {
+                kind: ExprKind::Literal(literal),
+                span: token.span,
+                ty,
+            })
+        };
+        match &token.kind {
+            TokenKind::Str(s) => literal(Literal::Str(s.clone()), Some(Type::Str)),
+            TokenKind::LParen => {
+                self.deeper(token.span)?;
+                let inner = self.or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Token {
+                        kind: TokenKind::RParen,
+                        span,
+                    } => Ok(Expr {
+                        span: token.span.to(span),
+                        ..inner
+                    }),
+                    _ => Err(self.error(token.span, "this ( is never closed")),
+                }
+            }
+            TokenKind::Ident(name) => match name.as_str() {
+                "true" => literal(Literal::Bool(true), Some(Type::Bool)),
+                "false" => literal(Literal::Bool(false), Some(Type::Bool)),
+                "none" => literal(Literal::None, None),
+                "action" => Ok(Expr {
+                    kind: ExprKind::Attribute(Attribute::Action),
+                    span: token.span,
+                    ty: Some(Type::Str),
+                }),
+                "user" | "subject" | "resource" => self.attribute(name, token.span),
+                keyword if KEYWORDS.contains(&keyword) => {
+                    Err(self.error(token.span, format!("expected a value, found '{keyword}'")))
+                }
+                word => literal(Literal::Str(word.to_string()), Some(Type::Str)),
+            },
+            kind => Err(self.error(token.span, format!("expected a value, found {kind}"))),
+        }
+    }
+
+    // user.role, subject.can_be_admin, resource.owner
+    fn attribute(&mut self, root: &str, start: Span) -> Result<Expr, ParseError> {
+        if self.peek().kind != TokenKind::Dot {
+            return Err(self.unexpected(&format!("'.' after {root}")));
+        }
+        self.next();
+        let token = self.next();
+        let TokenKind::Ident(field) = token.kind else {
+            return Err(self.error(
+                token.span,
+                format!("expected a field of {root}, found {}", token.kind),
+            ));
+        };
+        let span = start.to(token.span);
+        let attribute = match (root, field.as_str()) {
+            ("user", "username") => Attribute::Username,
+            ("user", "email") => Attribute::Email,
+            ("user", "role") => Attribute::Role,
+            ("subject", "name") => Attribute::Name,
+            ("subject", "logged_in") => Attribute::LoggedIn,
+            ("subject", "can_be_admin") => Attribute::CanBeAdmin,
+            ("resource", _) => Attribute::Resource(field),
+            ("user", _) => {
+                return Err(self.error(
+                    span,
+                    format!("user has no field '{field}', it has username, email and role"),
+                ))
+            }
+            _ => {
+                return Err(self.error(
+                    span,
+                    format!(
+                        "subject has no field '{field}', it has name, logged_in and can_be_admin"
+                    ),
+                ))
+            }
+        };
+        Ok(Expr {
+            ty: Some(attribute.ty()),
+            kind: ExprKind::Attribute(attribute),
+            span,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Policy;
+    use super::*;
+
+    fn error(source: &str) -> ParseError {
+        Policy::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn precedence_is_not_then_and_then_or() {
+        let policy =
+            Policy::parse("allow edit if not subject.logged_in and true or false").unwrap();
+        let condition = policy.rules()[0].condition.as_ref().unwrap();
+        let ExprKind::Or(left, _) = &condition.kind else {
+            panic!("{condition:?}")
+        };
+        let ExprKind::And(not, _) = &left.kind else {
+            panic!("{left:?}")
+        };
+        assert!(matches!(not.kind, ExprKind::Not(_)));
+        assert_eq!(left.span, Span { start: 14, end: 44 });
+    }
+
+    #[test]
+    fn words_are_strings_and_attributes_are_typed() {
+        let policy = Policy::parse("allow edit if user.role == admin deny *").unwrap();
+        let rules = policy.rules();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1].action, Action::Any);
+        let ExprKind::Eq(left, right) = &rules[0].condition.as_ref().unwrap().kind else {
+            panic!()
+        };
+        assert_eq!(left.kind, ExprKind::Attribute(Attribute::Role));
+        assert_eq!(
+            right.kind,
+            ExprKind::Literal(Literal::Str("admin".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors_carry_the_span_of_the_problem() {
+        let e = error("allow edit if user.role == true");
+        assert_eq!(e.message, "cannot compare a string with a bool");
+        assert_eq!(e.span, Span { start: 14, end: 31 });
+
+        let e = error("allow edit if user.name == \"bob\"");
+        assert_eq!(e.span, Span { start: 14, end: 23 });
+        assert!(e.message.starts_with("user has no field 'name'"));
+
+        let e = error("allow edit if user.role");
+        assert_eq!(e.message, "expected a condition (bool), found a string");
+
+        let e = error("allow edit if (true\ndeny edit");
+        assert_eq!((e.line, e.column), (1, 15));
+
+        let e = error("permit edit");
+        assert_eq!(e.message, "expected allow or deny, found 'permit'");
+
+        let e = error("allow if true");
+        assert_eq!(e.message, "expected an action or *, found 'if'");
+
+        assert_eq!(
+            error("allow edit if").message,
+            "expected a value, found the end of the policy"
+        );
+        assert!(error("allow edit if subject.can_be_admin and none")
+            .message
+            .contains("none"));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_not_a_stack_overflow() {
+        let parens = format!("allow edit if {}true", "(".repeat(100_000));
+        assert!(error(&parens).message.starts_with("nested too deeply"));
+        let nots = format!("allow edit if {}true", "not ".repeat(100_000));
+        assert!(error(&nots).message.starts_with("nested too deeply"));
+        let chain = format!("allow edit if true{}", " or true".repeat(100_000));
+        assert!(error(&chain).message.starts_with("nested too deeply"));
+
+        let fine = format!("allow edit if {}true{}", "(".repeat(50), ")".repeat(50));
+        assert!(Policy::parse(&fine).is_ok());
+    }
+}
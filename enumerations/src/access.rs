@@ -0,0 +1,440 @@
+// Role based access control, decides whether a request ends up as E4::Granted or E4::Denied.
+//
+// a role has permissions, "resource:action" strings where either side may be * for any,
+// and inherits every permission of the roles listed in inherits (admin inherits user).
+// a policy is written in toml, see policy.toml next to Cargo.toml:
+//
+// [roles.user]
+// permissions = ["profile:read"]
+//
+// [roles.admin]
+// inherits = ["user"]
+// permissions = ["users:*"]
+//
+// a grant carries the rule that matched, a denial a reason a program can match on.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{E2, E3, E4};
+
+// the policy used when none is loaded
+const DEFAULT_POLICY: &str = include_str!("../policy.toml");
+
+// the permission that granted access and the role it belongs to.
+// role is where the permission was found, for an inherited one that is not the subject's role.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rule {
+    pub role: String,
+    pub resource: String,
+    pub action: String,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}:{}", self.role, self.resource, self.action)
+    }
+}
+
+// serialized as {"reason": "no_permission", "role": ..., ...}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum DenyReason {
+    NotLoggedIn,
+    UnknownRole {
+        role: String,
+    },
+    NoPermission {
+        role: String,
+        resource: String,
+        action: String,
+    },
+}
+
+impl DenyReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DenyReason::NotLoggedIn => "not_logged_in",
+            DenyReason::UnknownRole { .. } => "unknown_role",
+            DenyReason::NoPermission { .. } => "no_permission",
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyReason::NotLoggedIn => write!(f, "not logged in"),
+            DenyReason::UnknownRole { role } => write!(f, "role '{role}' is not in the policy"),
+            DenyReason::NoPermission {
+                role,
+                resource,
+                action,
+            } => write!(f, "role '{role}' may not {action} {resource}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Io { path: PathBuf, source: io::Error },
+    Parse(String),
+    InvalidPermission { role: String, permission: String },
+    // a role inherits from a role that is not defined
+    UnknownRole { role: String, inherits: String },
+    // admin -> user -> admin, listed in the order they were followed
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            PolicyError::Parse(message) => write!(f, "invalid policy: {message}"),
+            PolicyError::InvalidPermission { role, permission } => write!(
+                f,
+                "role '{role}': permission '{permission}' is not resource:action"
+            ),
+            PolicyError::UnknownRole { role, inherits } => {
+                write!(f, "role '{role}' inherits unknown role '{inherits}'")
+            }
+            PolicyError::Cycle(roles) => {
+                write!(f, "roles inherit in a circle: {}", roles.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    roles: HashMap<String, RoleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleFile {
+    #[serde(default)]
+    inherits: Vec<String>,
+    #[serde(default)]
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Permission {
+    resource: String,
+    action: String,
+}
+
+impl Permission {
+    fn parse(s: &str) -> Option<Permission> {
+        let (resource, action) = s.split_once(':')?;
+        if resource.is_empty() || action.is_empty() || action.contains(':') {
+            return None;
+        }
+        Some(Permission {
+            resource: resource.to_string(),
+            action: action.to_string(),
+        })
+    }
+
+    fn matches(&self, resource: &str, action: &str) -> bool {
+        (self.resource == "*" || self.resource == resource)
+            && (self.action == "*" || self.action == action)
+    }
+}
+
+#[derive(Debug)]
+struct RoleEntry {
+    inherits: Vec<String>,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    // sorted so errors and listings come out the same every time
+    roles: BTreeMap<String, RoleEntry>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::from_toml(DEFAULT_POLICY).expect("policy.toml is valid")
+    }
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Policy, PolicyError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|source| PolicyError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Policy::from_toml(&contents)
+    }
+
+    // every mistake is caught here, check never fails
+    pub fn from_toml(contents: &str) -> Result<Policy, PolicyError> {
+        let file: PolicyFile =
+            toml::from_str(contents).map_err(|e| PolicyError::Parse(e.message().to_string()))?;
+        let mut roles = BTreeMap::new();
+        for (name, role) in file.roles {
+            let permissions = role
+                .permissions
+                .iter()
+                .map(|permission| {
+                    Permission::parse(permission).ok_or_else(|| PolicyError::InvalidPermission {
+                        role: name.clone(),
+                        permission: permission.clone(),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            roles.insert(
+                name,
+                RoleEntry {
+                    inherits: role.inherits,
+                    permissions,
+                },
+            );
+        }
+        let policy = Policy { roles };
+        for (name, role) in &policy.roles {
+            if let Some(unknown) = role
+                .inherits
+                .iter()
+                .find(|parent| !policy.roles.contains_key(*parent))
+            {
+                return Err(PolicyError::UnknownRole {
+                    role: name.clone(),
+                    inherits: unknown.clone(),
+                });
+            }
+        }
+        let mut done = HashSet::new();
+        for name in policy.roles.keys() {
+            policy.check_cycle(&mut vec![name.clone()], &mut done)?;
+        }
+        Ok(policy)
+    }
+
+    // depth first. done holds the roles whose ancestors have all been walked already,
+    // without it a chain of diamonds (b and c inherit d, a inherits b and c, ...) is walked
+    // once for every path through it.
+    fn check_cycle(
+        &self,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> Result<(), PolicyError> {
+        let current = path.last().expect("the path starts with a role").clone();
+        if done.contains(&current) {
+            return Ok(());
+        }
+        for parent in &self.roles[&current].inherits {
+            if path.contains(parent) {
+                let mut cycle = path.clone();
+                cycle.push(parent.clone());
+                return Err(PolicyError::Cycle(cycle));
+            }
+            path.push(parent.clone());
+            self.check_cycle(path, done)?;
+            path.pop();
+        }
+        done.insert(current);
+        Ok(())
+    }
+
+    pub fn roles(&self) -> impl Iterator<Item = &str> {
+        self.roles.keys().map(String::as_str)
+    }
+
+    // the role's own permissions first, then those of the roles it inherits in the order listed.
+    // a role reached a second time had nothing the first time, seen skips it (see check_cycle)
+    fn find_rule(
+        &self,
+        role: &str,
+        resource: &str,
+        action: &str,
+        seen: &mut HashSet<String>,
+    ) -> Option<Rule> {
+        if !seen.insert(role.to_string()) {
+            return None;
+        }
+        let entry = self.roles.get(role)?;
+        entry
+            .permissions
+            .iter()
+            .find(|permission| permission.matches(resource, action))
+            .map(|permission| Rule {
+                role: role.to_string(),
+                resource: permission.resource.clone(),
+                action: permission.action.clone(),
+            })
+            .or_else(|| {
+                entry
+                    .inherits
+                    .iter()
+                    .find_map(|parent| self.find_rule(parent, resource, action, seen))
+            })
+    }
+
+    // the subject is moved into the decision, Granted hands it back
+    pub fn check(&self, subject: E3, resource: &str, action: &str) -> E4 {
+        let role = match &subject {
+            E3::Admin => "admin".to_string(),
+            E3::User => "user".to_string(),
+            // waiting for mfa, locked, expired... anything but LoggedIn
+            E3::SomethingElse { login, .. } if *login != E2::LoggedIn => {
+                return E4::Denied(DenyReason::NotLoggedIn)
+            }
+            E3::SomethingElse { user, .. } => user.role.to_string(),
+        };
+        if !self.roles.contains_key(&role) {
+            return E4::Denied(DenyReason::UnknownRole { role });
+        }
+        match self.find_rule(&role, resource, action, &mut HashSet::new()) {
+            Some(rule) => E4::Granted(subject, rule),
+            None => E4::Denied(DenyReason::NoPermission {
+                role,
+                resource: resource.to_string(),
+                action: action.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use users::{Role, User};
+
+    fn someone(role: Role, login: E2) -> E3 {
+        E3::SomethingElse {
+            name: "Someone".to_string(),
+            user: User::new("someone", "someone@localhost", "password", role),
+            login,
+            can_be_admin: None,
+        }
+    }
+
+    #[test]
+    fn admin_inherits_the_permissions_of_user() {
+        let policy = Policy::default();
+        match policy.check(E3::Admin, "profile", "read") {
+            E4::Granted(E3::Admin, rule) => assert_eq!(rule.to_string(), "user: profile:read"),
+            other => panic!("{other:?}"),
+        }
+        match policy.check(E3::Admin, "users", "delete") {
+            E4::Granted(_, rule) => assert_eq!(rule.to_string(), "admin: users:*"),
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(
+            policy.check(E3::User, "users", "delete"),
+            E4::Denied(DenyReason::NoPermission {
+                role: "user".to_string(),
+                resource: "users".to_string(),
+                action: "delete".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn the_role_of_something_else_comes_from_its_user() {
+        let policy = Policy::default();
+        assert!(matches!(
+            policy.check(someone(Role::Admin, E2::LoggedIn), "users", "list"),
+            E4::Granted(E3::SomethingElse { .. }, _)
+        ));
+        for login in [E2::NotLoggedIn, E2::PendingMfa, E2::Locked] {
+            match policy.check(someone(Role::Admin, login), "users", "list") {
+                E4::Denied(reason) => assert_eq!(reason.code(), "not_logged_in"),
+                other => panic!("{other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn policies_load_from_toml() {
+        let policy = Policy::from_toml(
+            r#"
+            [roles.auditor]
+            permissions = ["*:read"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.roles().collect::<Vec<_>>(), ["auditor"]);
+        assert!(matches!(
+            policy.check(E3::User, "profile", "read"),
+            E4::Denied(DenyReason::UnknownRole { .. })
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.toml");
+        fs::write(&path, DEFAULT_POLICY).unwrap();
+        assert!(Policy::load(&path).is_ok());
+        assert!(matches!(
+            Policy::load(dir.path().join("missing.toml")),
+            Err(PolicyError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn broken_policies_are_rejected_on_load() {
+        let cycle = r#"
+            [roles.a]
+            inherits = ["b"]
+            [roles.b]
+            inherits = ["a"]
+        "#;
+        match Policy::from_toml(cycle) {
+            Err(PolicyError::Cycle(roles)) => assert_eq!(roles, ["a", "b", "a"]),
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(
+            Policy::from_toml("[roles.a]\ninherits = [\"ghost\"]"),
+            Err(PolicyError::UnknownRole { .. })
+        ));
+        assert!(matches!(
+            Policy::from_toml("[roles.a]\npermissions = [\"everything\"]"),
+            Err(PolicyError::InvalidPermission { .. })
+        ));
+        assert!(matches!(
+            Policy::from_toml("[roles.a]\nallow = []"),
+            Err(PolicyError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn a_chain_of_diamonds_is_walked_once() {
+        // user inherits left0 and right0, both inherit level1, which inherits left1 and
+        // right1 ... 2^40 paths from user to the top, 121 roles
+        let mut toml = String::from("[roles.user]\ninherits = [\"left0\", \"right0\"]\n");
+        for i in 0..40 {
+            for side in ["left", "right"] {
+                toml += &format!("[roles.{side}{i}]\ninherits = [\"level{}\"]\n", i + 1);
+            }
+            if i < 39 {
+                toml += &format!(
+                    "[roles.level{0}]\ninherits = [\"left{0}\", \"right{0}\"]\n",
+                    i + 1
+                );
+            }
+        }
+        toml += "[roles.level40]\npermissions = [\"profile:read\"]\n";
+        let policy = Policy::from_toml(&toml).unwrap();
+
+        match policy.check(E3::User, "profile", "read") {
+            E4::Granted(_, rule) => assert_eq!(rule.role, "level40"),
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(
+            policy.check(E3::User, "users", "delete"),
+            E4::Denied(DenyReason::NoPermission { .. })
+        ));
+    }
+}
@@ -0,0 +1,614 @@
+// Temporary admin rights, asked for and handed out through E3::SomethingElse::can_be_admin.
+//
+//   request  a user with can_be_admin: Some(true) asks to be admin for a while (2 hours...)
+//   approve  an admin agrees, the user is admin until the time runs out
+//   reject   an admin says no, with a reason
+//   expire   the time ran out, the user is back to their own role
+//
+// can_be_admin None (nobody decided) and Some(false) are both a no, those requests are
+// refused. only admins by role approve, a user who is admin through a grant cannot hand
+// out grants of their own.
+// nothing runs in the background, every call first expires the grants whose time is up,
+// so an expired grant is never seen as active.
+// every step, refusals included, is kept in history() with who did it and when, and with
+// with_audit also in the audit log (users/src/audit.rs) as an elevation entry.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use users::{
+    audit::{Action, AuditLog, Event, Outcome},
+    clock::{Clock, SystemClock},
+    Role,
+};
+
+use crate::{E2, E3};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Pending,
+    Approved { by: String, until: SystemTime },
+    Rejected { by: String, reason: String },
+    // was approved, the time is up
+    Expired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElevationRequest {
+    pub id: RequestId,
+    pub username: String,
+    pub reason: String,
+    pub duration: Duration,
+    pub requested_at: SystemTime,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElevationError {
+    // can_be_admin is None or Some(false), or the subject has no details at all (E3::User)
+    NotEligible { username: String },
+    // waiting for mfa, locked, expired... asking is for users who are logged in
+    NotLoggedIn { username: String },
+    AlreadyAdmin { username: String },
+    AlreadyPending { username: String, id: RequestId },
+    TooLong { requested: Duration, max: Duration },
+    // approving and rejecting is for admins by role who are logged in
+    NotAnAdmin { username: String },
+    UnknownRequest(RequestId),
+    NotPending(RequestId),
+}
+
+impl fmt::Display for ElevationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElevationError::NotEligible { username } => {
+                write!(f, "{username} is not allowed to become admin")
+            }
+            ElevationError::NotLoggedIn { username } => write!(f, "{username} is not logged in"),
+            ElevationError::AlreadyAdmin { username } => write!(f, "{username} is already admin"),
+            ElevationError::AlreadyPending { username, id } => {
+                write!(f, "{username} already has request {id} waiting")
+            }
+            ElevationError::TooLong { requested, max } => write!(
+                f,
+                "{}m asked for, grants last at most {}m",
+                requested.as_secs() / 60,
+                max.as_secs() / 60
+            ),
+            ElevationError::NotAnAdmin { username } => {
+                write!(
+                    f,
+                    "{username} is not an admin and cannot decide on requests"
+                )
+            }
+            ElevationError::UnknownRequest(id) => write!(f, "there is no request {id}"),
+            ElevationError::NotPending(id) => write!(f, "request {id} was already decided"),
+        }
+    }
+}
+
+impl std::error::Error for ElevationError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Requested { id: RequestId, duration: Duration },
+    Refused(ElevationError),
+    Approved { id: RequestId, until: SystemTime },
+    Rejected { id: RequestId, reason: String },
+    Expired { id: RequestId },
+}
+
+// one line of the history, actor is who did it ("system" for expiry)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub at: SystemTime,
+    pub actor: String,
+    pub step: Step,
+}
+
+// the name a subject goes by in the history
+fn name(subject: &E3) -> String {
+    match subject {
+        E3::Admin => "admin".to_string(),
+        E3::User => "user".to_string(),
+        E3::SomethingElse { user, .. } => user.username.clone(),
+    }
+}
+
+pub struct Elevation<C: Clock = SystemClock> {
+    clock: C,
+    // the longest grant that can be asked for
+    pub max_duration: Duration,
+    next_id: u64,
+    requests: BTreeMap<RequestId, ElevationRequest>,
+    history: Vec<Record>,
+    audit: Option<AuditLog>,
+}
+
+impl Elevation {
+    pub fn new() -> Elevation {
+        Elevation::with_clock(SystemClock)
+    }
+}
+
+impl Default for Elevation {
+    fn default() -> Self {
+        Elevation::new()
+    }
+}
+
+impl<C: Clock> Elevation<C> {
+    pub fn with_clock(clock: C) -> Elevation<C> {
+        Elevation {
+            clock,
+            max_duration: Duration::from_secs(8 * 60 * 60),
+            next_id: 1,
+            requests: BTreeMap::new(),
+            history: Vec::new(),
+            audit: None,
+        }
+    }
+
+    pub fn with_audit(mut self, log: AuditLog) -> Elevation<C> {
+        self.audit = Some(log);
+        self
+    }
+
+    fn record(&mut self, actor: String, step: Step) {
+        if let Some(log) = &mut self.audit {
+            // whose admin rights it is about, the refused subject is the actor
+            let target = match &step {
+                Step::Requested { id, .. }
+                | Step::Approved { id, .. }
+                | Step::Rejected { id, .. }
+                | Step::Expired { id } => self.requests[id].username.clone(),
+                Step::Refused(_) => actor.clone(),
+            };
+            let (outcome, detail) = match &step {
+                Step::Requested { id, duration } => (
+                    Outcome::Success,
+                    format!("requested {id} for {}m", duration.as_secs() / 60),
+                ),
+                Step::Refused(e) => (Outcome::Failure, format!("refused: {e}")),
+                Step::Approved { id, .. } => (Outcome::Success, format!("approved {id}")),
+                Step::Rejected { id, reason } => {
+                    (Outcome::Success, format!("rejected {id}: {reason}"))
+                }
+                Step::Expired { id } => (Outcome::Success, format!("{id} expired")),
+            };
+            let event = Event::new(&actor, Action::Elevation, &target, outcome).detail(detail);
+            // the step has happened either way, a log that cannot be written is a warning
+            if let Err(e) = log.record(event) {
+                eprintln!("warning: elevation not recorded in the audit log: {e}");
+            }
+        }
+        self.history.push(Record {
+            at: self.clock.now(),
+            actor,
+            step,
+        });
+    }
+
+    // records a refusal on the way out
+    fn refuse<T>(&mut self, actor: String, error: ElevationError) -> Result<T, ElevationError> {
+        self.record(actor, Step::Refused(error.clone()));
+        Err(error)
+    }
+
+    // grants whose time is up go back to being nothing
+    fn expire(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<RequestId> = self
+            .requests
+            .values()
+            .filter(
+                |request| matches!(request.status, Status::Approved { until, .. } if until <= now),
+            )
+            .map(|request| request.id)
+            .collect();
+        for id in expired {
+            if let Some(request) = self.requests.get_mut(&id) {
+                request.status = Status::Expired;
+            }
+            self.record("system".to_string(), Step::Expired { id });
+        }
+    }
+
+    fn active_grant(&self, username: &str) -> Option<&ElevationRequest> {
+        self.requests.values().find(|request| {
+            request.username == username && matches!(request.status, Status::Approved { .. })
+        })
+    }
+
+    pub fn request(
+        &mut self,
+        subject: &E3,
+        duration: Duration,
+        reason: &str,
+    ) -> Result<RequestId, ElevationError> {
+        self.expire();
+        let actor = name(subject);
+        let user = match subject {
+            E3::SomethingElse { login, .. } if *login != E2::LoggedIn => {
+                return self.refuse(
+                    actor.clone(),
+                    ElevationError::NotLoggedIn { username: actor },
+                )
+            }
+            E3::SomethingElse {
+                user,
+                can_be_admin: Some(true),
+                ..
+            } => user,
+            E3::Admin => {
+                return self.refuse(
+                    actor.clone(),
+                    ElevationError::AlreadyAdmin { username: actor },
+                )
+            }
+            _ => {
+                return self.refuse(
+                    actor.clone(),
+                    ElevationError::NotEligible { username: actor },
+                )
+            }
+        };
+        if user.role == Role::Admin || self.active_grant(&user.username).is_some() {
+            return self.refuse(
+                actor.clone(),
+                ElevationError::AlreadyAdmin { username: actor },
+            );
+        }
+        let pending = self
+            .requests
+            .values()
+            .find(|request| request.username == user.username && request.status == Status::Pending);
+        if let Some(pending) = pending {
+            let error = ElevationError::AlreadyPending {
+                username: actor.clone(),
+                id: pending.id,
+            };
+            return self.refuse(actor, error);
+        }
+        if duration > self.max_duration {
+            let error = ElevationError::TooLong {
+                requested: duration,
+                max: self.max_duration,
+            };
+            return self.refuse(actor, error);
+        }
+
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.requests.insert(
+            id,
+            ElevationRequest {
+                id,
+                username: user.username.clone(),
+                reason: reason.to_string(),
+                duration,
+                requested_at: self.clock.now(),
+                status: Status::Pending,
+            },
+        );
+        self.record(actor, Step::Requested { id, duration });
+        Ok(id)
+    }
+
+    // the checks approve and reject share
+    fn decide(&mut self, admin: &E3, id: RequestId) -> Result<(), ElevationError> {
+        self.expire();
+        let actor = name(admin);
+        let is_admin = match admin {
+            E3::Admin => true,
+            // admins by role never hold a grant, with one the role came from effective()
+            E3::SomethingElse { user, login, .. } => {
+                user.role == Role::Admin
+                    && *login == E2::LoggedIn
+                    && self.active_grant(&user.username).is_none()
+            }
+            E3::User => false,
+        };
+        if !is_admin {
+            return self.refuse(
+                actor.clone(),
+                ElevationError::NotAnAdmin { username: actor },
+            );
+        }
+        match self.requests.get(&id).map(|request| &request.status) {
+            Some(Status::Pending) => Ok(()),
+            Some(_) => self.refuse(actor, ElevationError::NotPending(id)),
+            None => self.refuse(actor, ElevationError::UnknownRequest(id)),
+        }
+    }
+
+    // the grant starts now, not when it was asked for
+    pub fn approve(&mut self, admin: &E3, id: RequestId) -> Result<SystemTime, ElevationError> {
+        self.decide(admin, id)?;
+        let request = self.requests.get_mut(&id).expect("checked in decide");
+        let until = self.clock.now() + request.duration;
+        request.status = Status::Approved {
+            by: name(admin),
+            until,
+        };
+        self.record(name(admin), Step::Approved { id, until });
+        Ok(until)
+    }
+
+    pub fn reject(
+        &mut self,
+        admin: &E3,
+        id: RequestId,
+        reason: &str,
+    ) -> Result<(), ElevationError> {
+        self.decide(admin, id)?;
+        let request = self.requests.get_mut(&id).expect("checked in decide");
+        request.status = Status::Rejected {
+            by: name(admin),
+            reason: reason.to_string(),
+        };
+        let step = Step::Rejected {
+            id,
+            reason: reason.to_string(),
+        };
+        self.record(name(admin), step);
+        Ok(())
+    }
+
+    // when the user's current grant runs out, None without one
+    pub fn granted_until(&mut self, username: &str) -> Option<SystemTime> {
+        self.expire();
+        match self.active_grant(username)?.status {
+            Status::Approved { until, .. } => Some(until),
+            _ => None,
+        }
+    }
+
+    // the subject as access::Policy should see it: admin while a grant is active
+    pub fn effective(&mut self, subject: E3) -> E3 {
+        match subject {
+            E3::SomethingElse {
+                name,
+                mut user,
+                login,
+                can_be_admin,
+            } => {
+                if self.granted_until(&user.username).is_some() {
+                    user.role = Role::Admin;
+                }
+                E3::SomethingElse {
+                    name,
+                    user,
+                    login,
+                    can_be_admin,
+                }
+            }
+            subject => subject,
+        }
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &ElevationRequest> {
+        self.requests
+            .values()
+            .filter(|request| request.status == Status::Pending)
+    }
+
+    pub fn get(&self, id: RequestId) -> Option<&ElevationRequest> {
+        self.requests.get(&id)
+    }
+
+    pub fn history(&self) -> &[Record] {
+        &self.history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{access::Policy, E4};
+    use std::sync::Arc;
+    use users::{
+        audit::{self, Query},
+        clock::ManualClock,
+        User,
+    };
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn someone(username: &str, role: Role, can_be_admin: Option<bool>) -> E3 {
+        E3::SomethingElse {
+            name: username.to_string(),
+            user: User::new(username, &format!("{username}@localhost"), "password", role),
+            login: E2::LoggedIn,
+            can_be_admin,
+        }
+    }
+
+    fn elevation() -> (Arc<ManualClock>, Elevation<Arc<ManualClock>>) {
+        let clock = Arc::new(ManualClock::at_unix(1_700_000_000));
+        (clock.clone(), Elevation::with_clock(clock))
+    }
+
+    #[test]
+    fn approved_grants_end_by_themselves() {
+        let (clock, mut elevation) = elevation();
+        let policy = Policy::default();
+        let bob = someone("bob", Role::User, Some(true));
+
+        let id = elevation
+            .request(&bob, 2 * HOUR, "migrating the users file")
+            .unwrap();
+        assert_eq!(elevation.pending().count(), 1);
+        // asking is not enough
+        let bob = elevation.effective(bob);
+        assert!(matches!(
+            policy.check(bob, "users", "delete"),
+            E4::Denied(_)
+        ));
+
+        clock.advance(HOUR);
+        let until = elevation.approve(&E3::Admin, id).unwrap();
+        assert_eq!(until, clock.now() + 2 * HOUR);
+        let bob = elevation.effective(someone("bob", Role::User, Some(true)));
+        assert!(matches!(
+            policy.check(bob, "users", "delete"),
+            E4::Granted(..)
+        ));
+
+        clock.advance(2 * HOUR);
+        let bob = elevation.effective(someone("bob", Role::User, Some(true)));
+        assert!(matches!(
+            policy.check(bob, "users", "delete"),
+            E4::Denied(_)
+        ));
+        assert_eq!(elevation.get(id).unwrap().status, Status::Expired);
+
+        let steps: Vec<(&str, &Step)> = elevation
+            .history()
+            .iter()
+            .map(|record| (record.actor.as_str(), &record.step))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (
+                    "bob",
+                    &Step::Requested {
+                        id,
+                        duration: 2 * HOUR
+                    }
+                ),
+                ("admin", &Step::Approved { id, until }),
+                ("system", &Step::Expired { id }),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_eligible_users_can_ask() {
+        let (_, mut elevation) = elevation();
+        for can_be_admin in [None, Some(false)] {
+            assert_eq!(
+                elevation.request(&someone("eve", Role::User, can_be_admin), HOUR, "please"),
+                Err(ElevationError::NotEligible {
+                    username: "eve".to_string()
+                })
+            );
+        }
+        assert!(matches!(
+            elevation.request(&E3::Admin, HOUR, ""),
+            Err(ElevationError::AlreadyAdmin { .. })
+        ));
+        for login in [E2::NotLoggedIn, E2::PendingMfa, E2::Locked] {
+            let mut mallory = someone("mallory", Role::User, Some(true));
+            if let E3::SomethingElse { login: l, .. } = &mut mallory {
+                *l = login;
+            }
+            assert_eq!(
+                elevation.request(&mallory, HOUR, "please"),
+                Err(ElevationError::NotLoggedIn {
+                    username: "mallory".to_string()
+                })
+            );
+        }
+        let bob = someone("bob", Role::User, Some(true));
+        assert!(matches!(
+            elevation.request(&bob, 24 * HOUR, ""),
+            Err(ElevationError::TooLong { .. })
+        ));
+        let id = elevation.request(&bob, HOUR, "").unwrap();
+        assert_eq!(
+            elevation.request(&bob, HOUR, ""),
+            Err(ElevationError::AlreadyPending {
+                username: "bob".to_string(),
+                id
+            })
+        );
+        // refusals are part of the history too
+        let refused = elevation
+            .history()
+            .iter()
+            .filter(|record| matches!(record.step, Step::Refused(_)))
+            .count();
+        assert_eq!(refused, 8);
+    }
+
+    #[test]
+    fn only_admins_decide() {
+        let (_, mut elevation) = elevation();
+        let bob = someone("bob", Role::User, Some(true));
+        let carol = someone("carol", Role::User, Some(true));
+        let bobs = elevation.request(&bob, HOUR, "").unwrap();
+        let carols = elevation.request(&carol, HOUR, "").unwrap();
+
+        assert!(matches!(
+            elevation.approve(&carol, bobs),
+            Err(ElevationError::NotAnAdmin { .. })
+        ));
+        let alice = someone("alice", Role::Admin, None);
+        elevation.approve(&alice, bobs).unwrap();
+        // bob is admin now, but only by a grant
+        let bob = elevation.effective(bob);
+        assert!(matches!(
+            elevation.approve(&bob, carols),
+            Err(ElevationError::NotAnAdmin { .. })
+        ));
+
+        elevation.reject(&alice, carols, "not this week").unwrap();
+        assert_eq!(
+            elevation.approve(&alice, carols),
+            Err(ElevationError::NotPending(carols))
+        );
+        assert_eq!(
+            elevation.approve(&alice, RequestId(99)),
+            Err(ElevationError::UnknownRequest(RequestId(99)))
+        );
+        assert!(elevation.granted_until("carol").is_none());
+        assert!(elevation.granted_until("bob").is_some());
+    }
+
+    #[test]
+    fn steps_go_into_the_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let (clock, elevation) = elevation();
+        let mut elevation = elevation.with_audit(AuditLog::open(&path).unwrap());
+        let bob = someone("bob", Role::User, Some(true));
+        let id = elevation.request(&bob, HOUR, "").unwrap();
+        elevation.approve(&E3::Admin, id).unwrap();
+        elevation
+            .request(&someone("eve", Role::User, None), HOUR, "")
+            .unwrap_err();
+        clock.advance(2 * HOUR);
+        elevation.granted_until("bob");
+        drop(elevation);
+
+        let entries = audit::query(&path, &Query::new().action(Action::Elevation)).unwrap();
+        let logged: Vec<(&str, &str, Outcome)> = entries
+            .iter()
+            .map(|entry| (entry.actor.as_str(), entry.target.as_str(), entry.outcome))
+            .collect();
+        assert_eq!(
+            logged,
+            [
+                ("bob", "bob", Outcome::Success),
+                ("admin", "bob", Outcome::Success),
+                ("eve", "eve", Outcome::Failure),
+                ("system", "bob", Outcome::Success),
+            ]
+        );
+        assert_eq!(entries[0].detail.as_deref(), Some("requested #1 for 60m"));
+    }
+}
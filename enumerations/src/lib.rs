@@ -0,0 +1,57 @@
+// The enums of this lesson live in a library so code can be built on top of them,
+// main.rs is still the lesson and uses them from here (use enumerations::E1).
+// access decides whether E4 is Granted or Denied from roles,
+// abac from rules written in a small policy language.
+// session moves an E2 from one login state to the next.
+// elevation turns can_be_admin into temporary admin rights.
+
+use users::User;
+
+pub mod abac;
+pub mod access;
+pub mod elevation;
+pub mod session;
+
+use access::{DenyReason, Rule};
+
+// pub allows it to be used outside the library.
+pub enum E1 {
+    LoggedIn,
+    NotLoggedIn,
+}
+
+// adding this, the compiler will generate code to allow comparison between enums
+// given it is a simple enum
+// Clone and Copy let it be copied like an integer, it holds no data that could not be.
+// the variants after NotLoggedIn are the rest of a session's life, see session.rs
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum E2 {
+    LoggedIn,
+    NotLoggedIn,
+    PendingMfa,
+    Locked,
+    Expired,
+    LoggedOut,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum E3 {
+    Admin,
+    User,
+    // can have a enum value which represents some object.
+    // this is a complex enum
+    // the user itself is the shared users::User, the variant only adds what is specific to it.
+    SomethingElse {
+        name: String,
+        user: User,
+        login: E2,
+        can_be_admin: Option<bool>,
+    },
+}
+
+// variants can hold more than one value, like a tuple
+#[derive(PartialEq, Debug)]
+pub enum E4 {
+    Granted(E3, Rule), // complex enum with enum inside, and the rule that granted it
+    Denied(DenyReason),
+}
@@ -1,42 +1,18 @@
 // Enums in rust are powerful than C-Enums
 // They can specify the most basic to complex structural types.
 
-// pub allows it to be used outside the library.
-pub enum E1 {
-    LoggedIn,
-    NotLoggedIn,
-}
-
-// adding this, the compiler will generate code to allow comparison between enums
-// given it is a simple enum
-#[derive(PartialEq, Eq, Debug)]
-pub enum E2 {
-    LoggedIn,
-    NotLoggedIn,
-}
-
-#[derive(PartialEq, Debug)]
-pub enum E3 {
-    Admin,
-    User,
-    // can have a enum value which represents some object.
-    // this is a complex enum
-    SomethingElse {
-        name: String,
-        username: String,
-        password: String,
-        email: String,
-        role: E2,
-        can_be_admin: Option<bool>,
-    },
-}
-
-#[derive(PartialEq, Debug)]
-pub enum E4 {
-    Granted(E3), // complex enum with enum inside
-    Denied,
-}
+// the enums themselves are in lib.rs so other code can use them too
+use enumerations::{
+    abac,
+    access::Policy,
+    elevation::Elevation,
+    session::{LoginOutcome, Session},
+    E1, E2, E3, E4,
+};
+use users::{audit::AuditLog, Role, User};
 
+// comparing a value with itself is the point of the lesson below, clippy would flag it.
+#[allow(clippy::eq_op)]
 fn main() {
     // create an enum variable, mut to be changeable
     let mut _x = E1::LoggedIn;
@@ -55,39 +31,106 @@ fn main() {
 
     // complex enum
     let _x = E3::Admin;
-    let _y = E4::Granted(_x);
+    // the policy (policy.toml) decides which E4 it is, Policy::load reads another one
+    let policy = Policy::default();
+    let _y = policy.check(_x, "users", "delete");
+
+    // a complex variant is built like a struct
+    let _w = policy.check(
+        E3::SomethingElse {
+            name: "Someone".to_string(),
+            user: User::new("someone", "someone@localhost", "password", Role::User),
+            login: E2::LoggedIn,
+            can_be_admin: Some(false),
+        },
+        "users",
+        "delete",
+    );
+    println!("{:?}", _w);
 
     // pattern matching on enums
     // match gives error if every case is not handled
     // for E4 we have to handle Granted and Denied both
     match _y {
-        // _z variable here captures whatever Granted(E3) is
-        E4::Granted(_z) => match _z {
+        // _z variable here captures whatever E3 Granted holds, _rule the Rule next to it
+        E4::Granted(_z, _rule) => match _z {
             // handle cases for E3
             E3::Admin => println!("Admin"),
             E3::User => println!("User"),
             E3::SomethingElse {
                 name,
-                username,
-                password,
-                email,
-                role,
+                user,
+                login,
                 can_be_admin: _c,
             } => {
                 println!(
-                    "SomethingElse {name} {username} {password} {email} {role:?} {}",
+                    "SomethingElse {name} {} {} {} {login:?} {}",
+                    user.username,
+                    user.email,
+                    user.role,
                     _c.is_some()
                 );
             }
         },
         // can also match a more granular enum
-        // E4::Granted(E3::Admin) => println!("Admin"),
-        // E4::Granted(E3::User) => println!("User"),
+        // E4::Granted(E3::Admin, _) => println!("Admin"),
+        // E4::Granted(E3::User, _) => println!("User"),
         // if you uncomment above two, you get unreachable code as match already contains  a case to handle both.
-        E4::Denied => {
+        E4::Denied(reason) => {
             // do nothing
-            println!("Denied")
+            println!("Denied: {reason}")
+        }
+    }
+
+    // rules can also look at the fields inside the enum, explain prints how it decided
+    let rules = abac::Policy::parse(
+        "allow edit if user.role == admin or resource.owner == user.username\n\
+         allow promote if subject.can_be_admin == true",
+    )
+    .expect("the rules are valid");
+    let someone = E3::SomethingElse {
+        name: "Someone".to_string(),
+        user: User::new("someone", "someone@localhost", "password", Role::User),
+        login: E2::LoggedIn,
+        can_be_admin: None,
+    };
+    println!(
+        "{}",
+        rules.explain(&abac::Request::new(&someone, "edit").resource("owner", "someone"))
+    );
+
+    // E2 changes only through a session's transitions, each one is printed by the closure
+    let session = Session::new("someone", |event| {
+        println!("{:?} -> {:?} ({})", event.from, event.to, event.transition)
+    });
+    if let LoginOutcome::PendingMfa(session) = session.login(true) {
+        // session.logout().verify_mfa() would not compile, a logged out session has no mfa step
+        session.verify_mfa().logout();
+    }
+
+    // can_be_admin: Some(true) lets a user ask to be admin for a while, an admin decides.
+    // a None or Some(false) would be refused, see elevation.rs
+    // every step goes into the same audit log the other lessons write
+    let mut elevation = match AuditLog::open("audit.log") {
+        Ok(log) => Elevation::new().with_audit(log),
+        Err(e) => {
+            eprintln!("warning: elevation is not audited: {e}");
+            Elevation::new()
         }
+    };
+    let hopeful = E3::SomethingElse {
+        name: "Hopeful".to_string(),
+        user: User::new("hopeful", "hopeful@localhost", "password", Role::User),
+        login: E2::LoggedIn,
+        can_be_admin: Some(true),
+    };
+    let two_hours = std::time::Duration::from_secs(2 * 60 * 60);
+    if let Ok(id) = elevation.request(&hopeful, two_hours, "fixing a broken account") {
+        let _ = elevation.approve(&E3::Admin, id);
+    }
+    match policy.check(elevation.effective(hopeful), "users", "delete") {
+        E4::Granted(_, rule) => println!("granted by {rule} until the grant runs out"),
+        E4::Denied(reason) => println!("Denied: {reason}"),
     }
 
     // A very common Enum is an Option Enum
@@ -0,0 +1,430 @@
+// The life of a login session as a state machine over E2.
+//
+//   NotLoggedIn / LoggedOut / Expired --login--> LoggedIn, or PendingMfa when mfa is required
+//   PendingMfa --verify_mfa--> LoggedIn
+//   PendingMfa / LoggedIn --timeout--> Expired,  --logout--> LoggedOut
+//   anything but Locked --lock--> Locked --unlock--> NotLoggedIn
+//
+// next_state below is the only place these rules are written down. two ways to use them:
+// - Session<S> where S is the state as a type, a transition is a method that only exists
+//   on the states it is allowed from, so session.verify_mfa() on a logged in session
+//   does not compile. (the typestate pattern)
+// - AnySession holds the state as a plain E2 value, for when it is only known at runtime
+//   (e.g. read back from a store), apply returns an error for an illegal transition.
+// either way every transition is reported to the listener as an Event.
+
+use std::{fmt, marker::PhantomData, time::SystemTime};
+
+use crate::E2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Login { mfa_required: bool },
+    VerifyMfa,
+    Timeout,
+    Logout,
+    Lock,
+    Unlock,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transition::Login { .. } => write!(f, "login"),
+            Transition::VerifyMfa => write!(f, "verify mfa"),
+            Transition::Timeout => write!(f, "timeout"),
+            Transition::Logout => write!(f, "logout"),
+            Transition::Lock => write!(f, "lock"),
+            Transition::Unlock => write!(f, "unlock"),
+        }
+    }
+}
+
+// None when the transition is not allowed from that state
+pub fn next_state(from: E2, transition: Transition) -> Option<E2> {
+    use E2::*;
+    match (from, transition) {
+        (NotLoggedIn | LoggedOut | Expired, Transition::Login { mfa_required }) => {
+            Some(if mfa_required { PendingMfa } else { LoggedIn })
+        }
+        (PendingMfa, Transition::VerifyMfa) => Some(LoggedIn),
+        (PendingMfa | LoggedIn, Transition::Timeout) => Some(Expired),
+        (PendingMfa | LoggedIn, Transition::Logout) => Some(LoggedOut),
+        (Locked, Transition::Lock) => None,
+        (_, Transition::Lock) => Some(Locked),
+        (Locked, Transition::Unlock) => Some(NotLoggedIn),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub username: String,
+    pub transition: Transition,
+    pub from: E2,
+    pub to: E2,
+    pub at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionError {
+    pub from: E2,
+    pub transition: Transition,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot {} a session that is {:?}",
+            self.transition, self.from
+        )
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+// called with every event, e.g. to print it or append it to a log
+pub type Listener = Box<dyn FnMut(&Event)>;
+
+// what both kinds of session share
+struct Core {
+    username: String,
+    listener: Listener,
+}
+
+impl Core {
+    fn step(&mut self, from: E2, transition: Transition) -> Result<E2, TransitionError> {
+        let to = next_state(from, transition).ok_or(TransitionError { from, transition })?;
+        (self.listener)(&Event {
+            username: self.username.clone(),
+            transition,
+            from,
+            to,
+            at: SystemTime::now(),
+        });
+        Ok(to)
+    }
+}
+
+// the states as types, they only exist at compile time (no fields, no size)
+pub mod state {
+    use crate::E2;
+
+    pub trait State {
+        const E2: E2;
+    }
+
+    pub struct NotLoggedIn;
+    pub struct PendingMfa;
+    pub struct LoggedIn;
+    pub struct Locked;
+    pub struct Expired;
+    pub struct LoggedOut;
+
+    impl State for NotLoggedIn {
+        const E2: E2 = E2::NotLoggedIn;
+    }
+    impl State for PendingMfa {
+        const E2: E2 = E2::PendingMfa;
+    }
+    impl State for LoggedIn {
+        const E2: E2 = E2::LoggedIn;
+    }
+    impl State for Locked {
+        const E2: E2 = E2::Locked;
+    }
+    impl State for Expired {
+        const E2: E2 = E2::Expired;
+    }
+    impl State for LoggedOut {
+        const E2: E2 = E2::LoggedOut;
+    }
+
+    // which states allow which transition, the same table as next_state
+    pub trait CanLogin: State {}
+    impl CanLogin for NotLoggedIn {}
+    impl CanLogin for LoggedOut {}
+    impl CanLogin for Expired {}
+
+    pub trait Active: State {}
+    impl Active for PendingMfa {}
+    impl Active for LoggedIn {}
+
+    pub trait CanLock: State {}
+    impl CanLock for NotLoggedIn {}
+    impl CanLock for PendingMfa {}
+    impl CanLock for LoggedIn {}
+    impl CanLock for Expired {}
+    impl CanLock for LoggedOut {}
+}
+
+use state::State;
+
+pub struct Session<S: State> {
+    core: Core,
+    state: PhantomData<S>,
+}
+
+impl<S: State> fmt::Debug for Session<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("username", &self.core.username)
+            .field("state", &S::E2)
+            .finish()
+    }
+}
+
+// login either finishes or waits for the second factor, which one is only known at runtime
+#[derive(Debug)]
+pub enum LoginOutcome {
+    LoggedIn(Session<state::LoggedIn>),
+    PendingMfa(Session<state::PendingMfa>),
+}
+
+impl Session<state::NotLoggedIn> {
+    pub fn new(username: &str, listener: impl FnMut(&Event) + 'static) -> Self {
+        Session {
+            core: Core {
+                username: username.to_string(),
+                listener: Box::new(listener),
+            },
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S: State> Session<S> {
+    pub fn username(&self) -> &str {
+        &self.core.username
+    }
+
+    pub fn state(&self) -> E2 {
+        S::E2
+    }
+
+    // the type guarantees the transition is allowed, so step cannot fail here
+    fn go<T: State>(mut self, transition: Transition) -> Session<T> {
+        let to = self
+            .core
+            .step(S::E2, transition)
+            .expect("the typestate only offers allowed transitions");
+        debug_assert_eq!(to, T::E2);
+        Session {
+            core: self.core,
+            state: PhantomData,
+        }
+    }
+
+    // gives up the compile time checks, e.g. to keep sessions in different states in one Vec
+    pub fn into_any(self) -> AnySession {
+        AnySession {
+            core: self.core,
+            state: S::E2,
+        }
+    }
+}
+
+impl<S: state::CanLogin> Session<S> {
+    pub fn login(self, mfa_required: bool) -> LoginOutcome {
+        let transition = Transition::Login { mfa_required };
+        if mfa_required {
+            LoginOutcome::PendingMfa(self.go(transition))
+        } else {
+            LoginOutcome::LoggedIn(self.go(transition))
+        }
+    }
+}
+
+impl Session<state::PendingMfa> {
+    pub fn verify_mfa(self) -> Session<state::LoggedIn> {
+        self.go(Transition::VerifyMfa)
+    }
+}
+
+impl<S: state::Active> Session<S> {
+    pub fn timeout(self) -> Session<state::Expired> {
+        self.go(Transition::Timeout)
+    }
+
+    pub fn logout(self) -> Session<state::LoggedOut> {
+        self.go(Transition::Logout)
+    }
+}
+
+impl<S: state::CanLock> Session<S> {
+    pub fn lock(self) -> Session<state::Locked> {
+        self.go(Transition::Lock)
+    }
+}
+
+impl Session<state::Locked> {
+    pub fn unlock(self) -> Session<state::NotLoggedIn> {
+        self.go(Transition::Unlock)
+    }
+}
+
+// a session whose state is only known at runtime
+pub struct AnySession {
+    core: Core,
+    state: E2,
+}
+
+impl fmt::Debug for AnySession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnySession")
+            .field("username", &self.core.username)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl AnySession {
+    pub fn new(username: &str, state: E2, listener: impl FnMut(&Event) + 'static) -> AnySession {
+        AnySession {
+            core: Core {
+                username: username.to_string(),
+                listener: Box::new(listener),
+            },
+            state,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.core.username
+    }
+
+    pub fn state(&self) -> E2 {
+        self.state
+    }
+
+    // an illegal transition leaves the state as it was and emits nothing
+    pub fn apply(&mut self, transition: Transition) -> Result<E2, TransitionError> {
+        self.state = self.core.step(self.state, transition)?;
+        Ok(self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    // a listener that keeps the events so the test can look at them afterwards
+    fn recorder() -> (Rc<RefCell<Vec<Event>>>, impl FnMut(&Event) + 'static) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&events);
+        (events, move |event: &Event| {
+            sink.borrow_mut().push(event.clone())
+        })
+    }
+
+    #[test]
+    fn typed_session_goes_through_mfa_and_every_step_is_an_event() {
+        let (events, listener) = recorder();
+        let session = Session::new("admin", listener);
+        let LoginOutcome::PendingMfa(session) = session.login(true) else {
+            panic!("mfa was required")
+        };
+        let session = session.verify_mfa();
+        assert_eq!(session.state(), E2::LoggedIn);
+        let session = session.lock().unlock();
+        let LoginOutcome::LoggedIn(session) = session.login(false) else {
+            panic!("no mfa this time")
+        };
+        let session = session.timeout();
+        assert_eq!(session.state(), E2::Expired);
+
+        let steps: Vec<(E2, Transition, E2)> = events
+            .borrow()
+            .iter()
+            .map(|event| (event.from, event.transition, event.to))
+            .collect();
+        use E2::*;
+        assert_eq!(
+            steps,
+            [
+                (
+                    NotLoggedIn,
+                    Transition::Login { mfa_required: true },
+                    PendingMfa
+                ),
+                (PendingMfa, Transition::VerifyMfa, LoggedIn),
+                (LoggedIn, Transition::Lock, Locked),
+                (Locked, Transition::Unlock, NotLoggedIn),
+                (
+                    NotLoggedIn,
+                    Transition::Login {
+                        mfa_required: false
+                    },
+                    LoggedIn
+                ),
+                (LoggedIn, Transition::Timeout, Expired),
+            ]
+        );
+        assert!(events
+            .borrow()
+            .iter()
+            .all(|event| event.username == "admin"));
+    }
+
+    #[test]
+    fn runtime_session_rejects_illegal_transitions() {
+        let (events, listener) = recorder();
+        let mut session = AnySession::new("user", E2::Locked, listener);
+        let error = session.apply(Transition::Logout).unwrap_err();
+        assert_eq!(error.to_string(), "cannot logout a session that is Locked");
+        assert_eq!(session.state(), E2::Locked);
+        assert!(events.borrow().is_empty());
+
+        assert_eq!(session.apply(Transition::Unlock), Ok(E2::NotLoggedIn));
+        assert!(session.apply(Transition::VerifyMfa).is_err());
+        assert_eq!(
+            session.apply(Transition::Login {
+                mfa_required: false
+            }),
+            Ok(E2::LoggedIn)
+        );
+        assert_eq!(session.apply(Transition::Logout), Ok(E2::LoggedOut));
+        assert_eq!(events.borrow().len(), 3);
+    }
+
+    #[test]
+    fn the_typestate_agrees_with_the_table() {
+        // a typed session turned into AnySession keeps its state and its listener
+        let (events, listener) = recorder();
+        let mut session = Session::new("user", listener).lock().into_any();
+        assert_eq!(session.state(), E2::Locked);
+        assert!(session.apply(Transition::Lock).is_err());
+        session.apply(Transition::Unlock).unwrap();
+        assert_eq!(events.borrow().len(), 2);
+
+        // every state allows exactly what its Session<S> has methods for
+        use E2::*;
+        let allowed = |from| {
+            [
+                Transition::Login {
+                    mfa_required: false,
+                },
+                Transition::VerifyMfa,
+                Transition::Timeout,
+                Transition::Logout,
+                Transition::Lock,
+                Transition::Unlock,
+            ]
+            .into_iter()
+            .filter(|transition| next_state(from, *transition).is_some())
+            .map(|transition| transition.to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(allowed(NotLoggedIn), ["login", "lock"]);
+        assert_eq!(
+            allowed(PendingMfa),
+            ["verify mfa", "timeout", "logout", "lock"]
+        );
+        assert_eq!(allowed(LoggedIn), ["timeout", "logout", "lock"]);
+        assert_eq!(allowed(Locked), ["unlock"]);
+        assert_eq!(allowed(Expired), ["login", "lock"]);
+        assert_eq!(allowed(LoggedOut), ["login", "lock"]);
+    }
+}
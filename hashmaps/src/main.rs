@@ -1,34 +1,13 @@
 use std::collections::HashMap;
 
-#[derive(Debug, PartialEq)]
-struct User {
-    username: String,
-    email: String,
-    password: String,
-    role: String,
-}
-
-impl User {
-    fn new(username: String, email: String, password: String, role: String) -> User {
-        User {
-            username,
-            email,
-            password,
-            role,
-        }
-    }
-}
+// User and Role come from the shared users crate
+use users::{Role, User};
 
 fn get_users() -> HashMap<String, User> {
     let mut users = HashMap::new();
     users.insert(
         "admin".to_string(),
-        User::new(
-            "admin".to_string(),
-            "admin@localhost".to_string(),
-            "admin".to_string(),
-            "admin".to_string(),
-        ),
+        User::new("admin", "admin@localhost", "admin", Role::Admin),
     );
     users
 }
@@ -0,0 +1,75 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    // the input ended in the middle of something
+    UnexpectedEof,
+    // expected says what would have been fine, e.g. "',' or ']'"
+    UnexpectedByte { found: u8, expected: &'static str },
+    InvalidNumber,
+    // does not fit in a f64, e.g. 1e400
+    NumberOutOfRange,
+    InvalidEscape,
+    // \ud800 without its second half, or the second half alone
+    LoneSurrogate,
+    // raw bytes below 0x20 (newline, tab ...) have to be escaped inside strings
+    ControlCharacterInString,
+    InvalidUtf8,
+    // something after the one top level value
+    TrailingCharacters,
+    // more than MAX_DEPTH objects/arrays open at once
+    TooDeep,
+}
+
+// offset is the position of the offending byte counted from the start of the input,
+// with a multi gigabyte file "line 1 column 4000000000" is not much help.
+#[derive(Debug)]
+pub struct Error {
+    pub offset: u64,
+    pub kind: ErrorKind,
+}
+
+impl Error {
+    pub(crate) fn new(offset: u64, kind: ErrorKind) -> Error {
+        Error { offset, kind }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Io(e) => write!(f, "io error: {e}")?,
+            ErrorKind::UnexpectedEof => write!(f, "unexpected end of input")?,
+            ErrorKind::UnexpectedByte { found, expected } => {
+                if found.is_ascii_graphic() {
+                    write!(f, "expected {expected}, found '{}'", *found as char)?
+                } else {
+                    write!(f, "expected {expected}, found byte 0x{found:02x}")?
+                }
+            }
+            ErrorKind::InvalidNumber => write!(f, "invalid number")?,
+            ErrorKind::NumberOutOfRange => write!(f, "number out of range")?,
+            ErrorKind::InvalidEscape => write!(f, "invalid escape")?,
+            ErrorKind::LoneSurrogate => write!(f, "lone surrogate in \\u escape")?,
+            ErrorKind::ControlCharacterInString => {
+                write!(f, "control character in string, it has to be escaped")?
+            }
+            ErrorKind::InvalidUtf8 => write!(f, "invalid utf-8 in string")?,
+            ErrorKind::TrailingCharacters => write!(f, "trailing characters")?,
+            ErrorKind::TooDeep => {
+                write!(f, "nested deeper than {} objects/arrays", crate::MAX_DEPTH)?
+            }
+        }
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// A json reader and writer written by hand, without serde_json or any other dependency.
+// it was built for users.json and the exports made from it, some of which are far too big
+// to load at once. so the core is a pull parser (reader.rs) that hands out one event at a time
+// and a writer (writer.rs) that takes the same events, Value and from_str/to_string are
+// built on top of those two for the small files.
+//
+// let mut parser = Parser::new(File::open("users.json")?);
+// while let Some(event) = parser.next_event()? { ... }
+
+use std::{collections::BTreeMap, io::Read};
+
+mod error;
+mod reader;
+mod writer;
+
+pub use error::{Error, ErrorKind};
+pub use reader::{Event, Parser, MAX_DEPTH};
+pub use writer::Writer;
+
+// the same split serde_json makes: integers stay exact as long as they fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    PosInt(u64),
+    NegInt(i64),
+    Float(f64),
+}
+
+// objects are sorted by key like serde_json's default Map
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+// reads exactly one value, anything but whitespace after it is an error
+pub fn from_reader(reader: impl Read) -> Result<Value, Error> {
+    let mut parser = Parser::new(reader);
+    let value = parser.next_value()?;
+    // drains the parser, which checks for trailing characters
+    if let Some(event) = parser.next_event()? {
+        unreachable!("the top level value is complete, got {event:?}");
+    }
+    // an empty input gives no value, next_event already reported it
+    value.ok_or_else(|| Error::new(0, ErrorKind::UnexpectedEof))
+}
+
+pub fn from_str(s: &str) -> Result<Value, Error> {
+    from_reader(s.as_bytes())
+}
+
+pub fn to_string(value: &Value) -> String {
+    let mut writer = Writer::new(Vec::new());
+    // writing a Value into a Vec cannot fail
+    writer.value(value).expect("writing to a Vec");
+    String::from_utf8(writer.into_inner()).expect("the writer only writes utf-8")
+}
+
+pub fn to_string_pretty(value: &Value) -> String {
+    let mut writer = Writer::pretty(Vec::new());
+    writer.value(value).expect("writing to a Vec");
+    String::from_utf8(writer.into_inner()).expect("the writer only writes utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // compare through serde_json's own Value so "agree" means exactly that
+    fn to_serde(value: &Value) -> serde_json::Value {
+        match value {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::Number(Number::PosInt(n)) => serde_json::Value::from(*n),
+            Value::Number(Number::NegInt(n)) => serde_json::Value::from(*n),
+            Value::Number(Number::Float(f)) => serde_json::Number::from_f64(*f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::String(s) => serde_json::Value::String(s.clone()),
+            Value::Array(values) => values.iter().map(to_serde).collect(),
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| (key.clone(), to_serde(value)))
+                .collect(),
+        }
+    }
+
+    const VALID: &[&str] = &[
+        "null",
+        "true",
+        "false",
+        "0",
+        "-0",
+        "-1",
+        "42",
+        "1.5",
+        "-0.0",
+        "1e10",
+        "1E-7",
+        "2.5e+3",
+        "123.456e-2",
+        "1e-400",
+        "18446744073709551615",
+        "18446744073709551616",
+        "-9223372036854775808",
+        "-9223372036854775809",
+        r#""""#,
+        r#""hello""#,
+        r#""\"\\\/\b\f\n\r\t""#,
+        r#""éA😀""#,
+        "\"é ü 日本 😀\"",
+        "[]",
+        "{}",
+        " \t\r\n [ 1 , 2 ] \n",
+        r#"[1, "a", null, true, [], {}, [[[]]]]"#,
+        r#"{"a": {"b": {"c": [1, {"d": null}]}}}"#,
+        r#"{"a": 1, "a": 2}"#,
+        r#"{"": "", "key with space": "\u0000"}"#,
+    ];
+
+    const INVALID: &[&str] = &[
+        "",
+        " ",
+        "[",
+        "]",
+        "{",
+        "[1,]",
+        "[,1]",
+        "[1 2]",
+        r#"{"a":1,}"#,
+        r#"{"a" 1}"#,
+        r#"{"a":}"#,
+        "{1:2}",
+        "{'a':1}",
+        "01",
+        "1.",
+        ".5",
+        "-",
+        "+1",
+        "1e",
+        "1e+",
+        "1e400",
+        "-1e400",
+        "NaN",
+        "Infinity",
+        "tru",
+        "nul",
+        "True",
+        r#""abc"#,
+        "\"tab\there\"",
+        r#""\q""#,
+        r#""\u12""#,
+        r#""\ud800""#,
+        r#""\udc00""#,
+        r#""\ud800A""#,
+        "1 2",
+        "[1]]",
+        "{} {}",
+    ];
+
+    #[test]
+    fn agrees_with_serde_json_on_valid_documents() {
+        for document in VALID {
+            let ours = from_str(document).unwrap_or_else(|e| panic!("{document}: {e}"));
+            let theirs: serde_json::Value = serde_json::from_str(document).unwrap();
+            assert_eq!(to_serde(&ours), theirs, "{document}");
+        }
+    }
+
+    #[test]
+    fn agrees_with_serde_json_on_invalid_documents() {
+        for document in INVALID {
+            assert!(
+                serde_json::from_str::<serde_json::Value>(document).is_err(),
+                "serde_json accepts {document}"
+            );
+            assert!(from_str(document).is_err(), "we accept {document}");
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        let document = b"\"\xff\"";
+        assert!(serde_json::from_slice::<serde_json::Value>(document).is_err());
+        let error = from_reader(&document[..]).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::InvalidUtf8));
+        assert_eq!(error.offset, 1);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_byte() {
+        let cases = [
+            ("[1,]", 3),
+            (r#"{"a" 1}"#, 5),
+            ("[1 2]", 3),
+            ("\"ab\ncd\"", 3),
+            (r#""\q""#, 2),
+            ("1 x", 2),
+            ("[", 1),
+            (r#"["\ud800"]"#, 2),
+        ];
+        for (document, offset) in cases {
+            let error = from_str(document).unwrap_err();
+            assert_eq!(error.offset, offset, "{document}: {error}");
+        }
+        let error = from_str("[1,]").unwrap_err();
+        assert_eq!(error.to_string(), "expected a value, found ']' at byte 3");
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_not_a_stack_overflow() {
+        let deep = format!("{}{}", "[".repeat(1_000_000), "]".repeat(1_000_000));
+        let error = from_str(&deep).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::TooDeep));
+        assert_eq!(error.offset, MAX_DEPTH as u64);
+        // serde_json stops at the same depth
+        assert!(serde_json::from_str::<serde_json::Value>(&deep).is_err());
+
+        let fine = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        let value = from_str(&fine).unwrap();
+        assert_eq!(to_string(&value), fine);
+    }
+
+    #[test]
+    fn agrees_with_serde_json_on_the_users_fixture() {
+        let fixture = include_str!("../../serialize-deserialize/users.json");
+        let ours = from_str(fixture).unwrap();
+        let theirs: serde_json::Value = serde_json::from_str(fixture).unwrap();
+        assert_eq!(to_serde(&ours), theirs);
+        assert_eq!(to_string(&ours), serde_json::to_string(&theirs).unwrap());
+        assert_eq!(
+            to_string_pretty(&ours),
+            serde_json::to_string_pretty(&theirs).unwrap()
+        );
+    }
+
+    #[test]
+    fn written_documents_read_back_the_same() {
+        for document in VALID {
+            let value = from_str(document).unwrap();
+            let written = to_string(&value);
+            let theirs: serde_json::Value = serde_json::from_str(&written).unwrap();
+            assert_eq!(to_serde(&value), theirs, "{document} -> {written}");
+            assert_eq!(from_str(&to_string_pretty(&value)).unwrap(), value);
+        }
+        // strings and integers come out byte for byte like serde_json writes them
+        let value = from_str(r#"["a\u0001\"\\\n\u001f/é", -5, 18446744073709551615]"#).unwrap();
+        let theirs: serde_json::Value = serde_json::from_str(&to_string(&value)).unwrap();
+        assert_eq!(to_string(&value), serde_json::to_string(&theirs).unwrap());
+    }
+
+    // produces a users export of any size on the fly, nothing is ever held in memory
+    struct Export {
+        users: usize,
+        next: usize,
+        pending: Vec<u8>,
+    }
+
+    impl Read for Export {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                self.pending = if self.next == 0 {
+                    b"{\"schema_version\": 3, \"users\": {".to_vec()
+                } else if self.next <= self.users {
+                    let i = self.next;
+                    let comma = if i == self.users { "" } else { "," };
+                    format!(
+                        "\"user{i}\": {{\"username\": \"user{i}\", \"email\": \"user{i}@localhost\", \"role\": \"user\"}}{comma}"
+                    )
+                    .into_bytes()
+                } else if self.next == self.users + 1 {
+                    b"}}".to_vec()
+                } else {
+                    return Ok(0);
+                };
+                self.next += 1;
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streams_users_one_at_a_time() {
+        let users = 200_000;
+        let mut parser = Parser::new(Export {
+            users,
+            next: 0,
+            pending: Vec::new(),
+        });
+
+        // walk down to the users object
+        assert_eq!(parser.next_event().unwrap(), Some(Event::StartObject));
+        while let Some(event) = parser.next_event().unwrap() {
+            if event == Event::Key("users".to_string()) {
+                break;
+            }
+            parser.next_value().unwrap();
+        }
+        assert_eq!(parser.next_event().unwrap(), Some(Event::StartObject));
+
+        // then pull one user at a time, only that one user is in memory
+        let mut count = 0;
+        while let Some(user) = parser.next_value().unwrap() {
+            count += 1;
+            assert_eq!(
+                user.get("username").and_then(Value::as_str),
+                Some(format!("user{count}").as_str())
+            );
+        }
+        assert_eq!(count, users);
+        assert_eq!(parser.next_event().unwrap(), Some(Event::EndObject));
+        assert_eq!(parser.next_event().unwrap(), None);
+        assert!(parser.offset() > 10_000_000);
+    }
+
+    #[test]
+    fn writer_rejects_events_out_of_place() {
+        let mut writer = Writer::new(Vec::new());
+        writer.event(&Event::StartObject).unwrap();
+        assert!(writer.event(&Event::Null).is_err());
+
+        let mut writer = Writer::new(Vec::new());
+        writer.event(&Event::StartArray).unwrap();
+        assert!(writer.event(&Event::EndObject).is_err());
+    }
+}
@@ -0,0 +1,515 @@
+// The pull parser.
+// instead of building the whole document in memory it hands out one Event at a time,
+// the caller pulls the next one when it is ready. only the current string or number and
+// a stack of open objects/arrays are kept, so the size of the input does not matter.
+// how deep they nest does, a bit: at most MAX_DEPTH may be open at once. the parser itself
+// would not mind, but a Value that deep is dropped (and written) recursively and a
+// document of a million '[' would overflow the call stack doing it.
+
+use std::{collections::BTreeMap, io::Read};
+
+use crate::{Error, ErrorKind, Number, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    StartObject,
+    EndObject,
+    StartArray,
+    EndArray,
+    // an object key, the next event is its value
+    Key(String),
+    String(String),
+    Number(Number),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Object,
+    Array,
+}
+
+// what the parser expects next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // the top level value
+    Start,
+    // right after '[' the array may end at once
+    FirstInArray,
+    // after ',' in an array
+    ValueInArray,
+    // right after '{' the object may end at once
+    FirstInObject,
+    // after ',' in an object
+    KeyInObject,
+    // after a key (the ':' is read with the key)
+    ValueInObject,
+    // after any value: ',' or the end of the container
+    AfterValue,
+    // the top level value is done, only whitespace may follow
+    End,
+    // end reached or an error happened, nothing more comes out
+    Finished,
+}
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// what serde_json allows too
+pub const MAX_DEPTH: usize = 128;
+
+pub struct Parser<R> {
+    reader: R,
+    buffer: Box<[u8]>,
+    // buffer[pos..len] has not been looked at yet
+    pos: usize,
+    len: usize,
+    // bytes that went through the buffer before the current fill, for error offsets
+    consumed: u64,
+    stack: Vec<Container>,
+    state: State,
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(reader: R) -> Parser<R> {
+        Parser {
+            reader,
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            consumed: 0,
+            stack: Vec::new(),
+            state: State::Start,
+        }
+    }
+
+    // position of the next byte in the input
+    pub fn offset(&self) -> u64 {
+        self.consumed + self.pos as u64
+    }
+
+    // how many objects/arrays are open right now
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn error(&mut self, kind: ErrorKind) -> Error {
+        self.state = State::Finished;
+        Error::new(self.offset(), kind)
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        if self.pos == self.len {
+            self.consumed += self.len as u64;
+            self.pos = 0;
+            self.len = 0;
+            loop {
+                match self.reader.read(&mut self.buffer) {
+                    Ok(n) => {
+                        self.len = n;
+                        break;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(self.error(ErrorKind::Io(e))),
+                }
+            }
+            if self.len == 0 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.buffer[self.pos]))
+    }
+
+    // the next byte, running out of input is an error
+    fn next_byte(&mut self) -> Result<u8, Error> {
+        match self.peek()? {
+            Some(byte) => {
+                self.pos += 1;
+                Ok(byte)
+            }
+            None => Err(self.error(ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), Error> {
+        while let Some(b' ' | b'\n' | b'\r' | b'\t') = self.peek()? {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn unexpected(&mut self, found: u8, expected: &'static str) -> Error {
+        // point at the byte itself, it was already consumed
+        self.pos -= 1;
+        self.error(ErrorKind::UnexpectedByte { found, expected })
+    }
+
+    fn expect(&mut self, wanted: u8, expected: &'static str) -> Result<(), Error> {
+        match self.next_byte()? {
+            byte if byte == wanted => Ok(()),
+            byte => Err(self.unexpected(byte, expected)),
+        }
+    }
+
+    // the next event, None once the whole document has been read
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            match self.state {
+                State::Finished => return Ok(None),
+                State::End => {
+                    self.skip_whitespace()?;
+                    if self.peek()?.is_some() {
+                        return Err(self.error(ErrorKind::TrailingCharacters));
+                    }
+                    self.state = State::Finished;
+                    return Ok(None);
+                }
+                State::Start | State::ValueInArray | State::ValueInObject => {
+                    return self.parse_value().map(Some);
+                }
+                State::FirstInArray => {
+                    self.skip_whitespace()?;
+                    if self.peek()? == Some(b']') {
+                        self.pos += 1;
+                        return Ok(Some(self.close()));
+                    }
+                    return self.parse_value().map(Some);
+                }
+                State::FirstInObject | State::KeyInObject => {
+                    self.skip_whitespace()?;
+                    let byte = self.next_byte()?;
+                    if byte == b'}' && self.state == State::FirstInObject {
+                        return Ok(Some(self.close()));
+                    }
+                    if byte != b'"' {
+                        let expected = if self.state == State::FirstInObject {
+                            "a string key or '}'"
+                        } else {
+                            "a string key"
+                        };
+                        return Err(self.unexpected(byte, expected));
+                    }
+                    let key = self.parse_string()?;
+                    self.skip_whitespace()?;
+                    self.expect(b':', "':'")?;
+                    self.state = State::ValueInObject;
+                    return Ok(Some(Event::Key(key)));
+                }
+                State::AfterValue => {
+                    self.skip_whitespace()?;
+                    match self.stack.last() {
+                        None => self.state = State::End,
+                        Some(Container::Array) => match self.next_byte()? {
+                            b',' => self.state = State::ValueInArray,
+                            b']' => return Ok(Some(self.close())),
+                            byte => return Err(self.unexpected(byte, "',' or ']'")),
+                        },
+                        Some(Container::Object) => match self.next_byte()? {
+                            b',' => self.state = State::KeyInObject,
+                            b'}' => return Ok(Some(self.close())),
+                            byte => return Err(self.unexpected(byte, "',' or '}'")),
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    fn close(&mut self) -> Event {
+        self.state = State::AfterValue;
+        match self.stack.pop() {
+            Some(Container::Object) => Event::EndObject,
+            _ => Event::EndArray,
+        }
+    }
+
+    // a '{' or '[' was just read
+    fn open(&mut self, container: Container) -> Result<(), Error> {
+        if self.stack.len() == MAX_DEPTH {
+            // point at the bracket
+            self.pos -= 1;
+            return Err(self.error(ErrorKind::TooDeep));
+        }
+        self.stack.push(container);
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Event, Error> {
+        self.skip_whitespace()?;
+        let byte = self.next_byte()?;
+        let event = match byte {
+            b'{' => {
+                self.open(Container::Object)?;
+                self.state = State::FirstInObject;
+                return Ok(Event::StartObject);
+            }
+            b'[' => {
+                self.open(Container::Array)?;
+                self.state = State::FirstInArray;
+                return Ok(Event::StartArray);
+            }
+            b'"' => Event::String(self.parse_string()?),
+            b't' => {
+                self.parse_literal(b"rue")?;
+                Event::Bool(true)
+            }
+            b'f' => {
+                self.parse_literal(b"alse")?;
+                Event::Bool(false)
+            }
+            b'n' => {
+                self.parse_literal(b"ull")?;
+                Event::Null
+            }
+            b'-' | b'0'..=b'9' => Event::Number(self.parse_number(byte)?),
+            _ => return Err(self.unexpected(byte, "a value")),
+        };
+        self.state = State::AfterValue;
+        Ok(event)
+    }
+
+    fn parse_literal(&mut self, rest: &[u8]) -> Result<(), Error> {
+        for &wanted in rest {
+            let byte = self.next_byte()?;
+            if byte != wanted {
+                return Err(self.unexpected(byte, "a value"));
+            }
+        }
+        Ok(())
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn parse_number(&mut self, first: u8) -> Result<Number, Error> {
+        let start = self.offset() - 1;
+        let mut text = String::new();
+        text.push(first as char);
+
+        let mut first_digit = first;
+        if first == b'-' {
+            first_digit = self.next_byte()?;
+            if !first_digit.is_ascii_digit() {
+                return Err(self.unexpected(first_digit, "a digit"));
+            }
+            text.push(first_digit as char);
+        }
+        // no leading zeros, 0 stands alone
+        if first_digit != b'0' {
+            self.push_digits(&mut text)?;
+        }
+
+        let mut is_float = false;
+        if self.peek()? == Some(b'.') {
+            self.pos += 1;
+            text.push('.');
+            if self.push_digits(&mut text)? == 0 {
+                return Err(self.error(ErrorKind::InvalidNumber));
+            }
+            is_float = true;
+        }
+        if let Some(e @ (b'e' | b'E')) = self.peek()? {
+            self.pos += 1;
+            text.push(e as char);
+            if let Some(sign @ (b'+' | b'-')) = self.peek()? {
+                self.pos += 1;
+                text.push(sign as char);
+            }
+            if self.push_digits(&mut text)? == 0 {
+                return Err(self.error(ErrorKind::InvalidNumber));
+            }
+            is_float = true;
+        }
+
+        // integers stay integers as long as they fit, like serde_json
+        if !is_float {
+            if first == b'-' {
+                match text.parse::<i64>() {
+                    // serde_json reads -0 as the float -0.0
+                    Ok(0) => return Ok(Number::Float(-0.0)),
+                    Ok(n) => return Ok(Number::NegInt(n)),
+                    Err(_) => {}
+                }
+            } else if let Ok(n) = text.parse::<u64>() {
+                return Ok(Number::PosInt(n));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(Number::Float(f)),
+            _ => Err(Error::new(start, ErrorKind::NumberOutOfRange)),
+        }
+    }
+
+    // returns how many digits were read
+    fn push_digits(&mut self, text: &mut String) -> Result<usize, Error> {
+        let mut count = 0;
+        while let Some(byte @ b'0'..=b'9') = self.peek()? {
+            self.pos += 1;
+            text.push(byte as char);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // the opening quote is already read
+    fn parse_string(&mut self) -> Result<String, Error> {
+        let start = self.offset();
+        let mut bytes = Vec::new();
+        loop {
+            match self.next_byte()? {
+                b'"' => break,
+                b'\\' => self.parse_escape(&mut bytes)?,
+                byte if byte < 0x20 => {
+                    self.pos -= 1;
+                    return Err(self.error(ErrorKind::ControlCharacterInString));
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|e| {
+            self.state = State::Finished;
+            Error::new(
+                start + e.utf8_error().valid_up_to() as u64,
+                ErrorKind::InvalidUtf8,
+            )
+        })
+    }
+
+    fn parse_escape(&mut self, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        let escaped = match self.next_byte()? {
+            b'"' => b'"',
+            b'\\' => b'\\',
+            b'/' => b'/',
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'u' => {
+                let c = self.parse_unicode_escape()?;
+                let mut utf8 = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                return Ok(());
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.error(ErrorKind::InvalidEscape));
+            }
+        };
+        bytes.push(escaped);
+        Ok(())
+    }
+
+    // \uXXXX, characters outside the basic plane come as two of them (a surrogate pair)
+    fn parse_unicode_escape(&mut self) -> Result<char, Error> {
+        let start = self.offset() - 2;
+        let first = self.parse_hex4()?;
+        let code = match first {
+            0xd800..=0xdbff => {
+                if self.next_byte()? != b'\\' || self.next_byte()? != b'u' {
+                    return Err(self.surrogate_error(start));
+                }
+                let second = self.parse_hex4()?;
+                if !(0xdc00..=0xdfff).contains(&second) {
+                    return Err(self.surrogate_error(start));
+                }
+                0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+            }
+            0xdc00..=0xdfff => return Err(self.surrogate_error(start)),
+            _ => first,
+        };
+        // every value left is a valid char
+        char::from_u32(code).ok_or_else(|| self.surrogate_error(start))
+    }
+
+    fn surrogate_error(&mut self, offset: u64) -> Error {
+        self.state = State::Finished;
+        Error::new(offset, ErrorKind::LoneSurrogate)
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, Error> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.next_byte()?;
+            let digit = match (byte as char).to_digit(16) {
+                Some(digit) => digit,
+                None => return Err(self.unexpected(byte, "a hex digit")),
+            };
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    // reads the next complete value, handy to pull out one user at a time
+    // while walking a big document with next_event.
+    // None when the current object/array ended (or the document did) instead.
+    pub fn next_value(&mut self) -> Result<Option<Value>, Error> {
+        match self.next_event()? {
+            None | Some(Event::EndObject | Event::EndArray) => Ok(None),
+            Some(Event::Key(_)) => self.next_value(),
+            Some(event) => self.value_from(event).map(Some),
+        }
+    }
+
+    // builds a value starting at event, nested values are collected with a stack instead
+    // of recursion. (dropping the value does recurse, MAX_DEPTH keeps that safe)
+    fn value_from(&mut self, event: Event) -> Result<Value, Error> {
+        enum Partial {
+            Array(Vec<Value>),
+            Object(BTreeMap<String, Value>, Option<String>),
+        }
+
+        let mut partials: Vec<Partial> = Vec::new();
+        let mut event = event;
+        loop {
+            let value = match event {
+                Event::StartObject => {
+                    partials.push(Partial::Object(BTreeMap::new(), None));
+                    None
+                }
+                Event::StartArray => {
+                    partials.push(Partial::Array(Vec::new()));
+                    None
+                }
+                Event::Key(key) => {
+                    if let Some(Partial::Object(_, pending)) = partials.last_mut() {
+                        *pending = Some(key);
+                    }
+                    None
+                }
+                Event::EndObject | Event::EndArray => match partials.pop() {
+                    Some(Partial::Array(values)) => Some(Value::Array(values)),
+                    Some(Partial::Object(map, _)) => Some(Value::Object(map)),
+                    None => None,
+                },
+                Event::String(s) => Some(Value::String(s)),
+                Event::Number(n) => Some(Value::Number(n)),
+                Event::Bool(b) => Some(Value::Bool(b)),
+                Event::Null => Some(Value::Null),
+            };
+
+            if let Some(value) = value {
+                match partials.last_mut() {
+                    None => return Ok(value),
+                    Some(Partial::Array(values)) => values.push(value),
+                    // a repeated key keeps the last value, like serde_json
+                    Some(Partial::Object(map, pending)) => {
+                        map.insert(pending.take().unwrap_or_default(), value);
+                    }
+                }
+            }
+
+            event = match self.next_event()? {
+                Some(event) => event,
+                None => return Err(self.error(ErrorKind::UnexpectedEof)),
+            };
+        }
+    }
+}
+
+// for event in Parser::new(file) { ... }
+impl<R: Read> Iterator for Parser<R> {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
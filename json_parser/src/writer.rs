@@ -0,0 +1,205 @@
+// The writer, the mirror image of the pull parser.
+// it takes the same Events and writes them out as they come,
+// adding the commas and colons itself, so a document can be written without building it first.
+
+use std::io::{self, Write};
+
+use crate::{Event, Number, Value};
+
+struct Frame {
+    is_object: bool,
+    // nothing written inside yet, no comma needed
+    first: bool,
+}
+
+pub struct Writer<W> {
+    writer: W,
+    frames: Vec<Frame>,
+    // Some(width) writes one value per line indented by width spaces per level
+    indent: Option<usize>,
+    // a key was written, its value comes next
+    after_key: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Writer<W> {
+        Writer {
+            writer,
+            frames: Vec::new(),
+            indent: None,
+            after_key: false,
+        }
+    }
+
+    // same layout as serde_json::to_string_pretty
+    pub fn pretty(writer: W) -> Writer<W> {
+        Writer {
+            indent: Some(2),
+            ..Self::new(writer)
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn newline(&mut self) -> io::Result<()> {
+        if let Some(width) = self.indent {
+            write!(self.writer, "\n{:1$}", "", width * self.frames.len())?;
+        }
+        Ok(())
+    }
+
+    // the comma and newline in front of an array element or object key
+    fn separator(&mut self) -> io::Result<()> {
+        if let Some(frame) = self.frames.last_mut() {
+            let first = std::mem::replace(&mut frame.first, false);
+            if !first {
+                self.writer.write_all(b",")?;
+            }
+            self.newline()?;
+        }
+        Ok(())
+    }
+
+    fn before_value(&mut self) -> io::Result<()> {
+        match self.frames.last() {
+            Some(frame) if frame.is_object => {
+                if !std::mem::replace(&mut self.after_key, false) {
+                    return Err(invalid("a value inside an object needs a key first"));
+                }
+                Ok(())
+            }
+            _ => self.separator(),
+        }
+    }
+
+    pub fn event(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::StartObject | Event::StartArray => {
+                self.before_value()?;
+                let is_object = *event == Event::StartObject;
+                self.writer.write_all(if is_object { b"{" } else { b"[" })?;
+                self.frames.push(Frame {
+                    is_object,
+                    first: true,
+                });
+            }
+            Event::EndObject | Event::EndArray => {
+                let is_object = *event == Event::EndObject;
+                match self.frames.pop() {
+                    Some(frame) if frame.is_object == is_object && !self.after_key => {
+                        // empty containers stay on one line: {} and []
+                        if !frame.first {
+                            self.newline()?;
+                        }
+                    }
+                    _ => return Err(invalid("end does not match an open object/array")),
+                }
+                self.writer.write_all(if is_object { b"}" } else { b"]" })?;
+            }
+            Event::Key(key) => {
+                match self.frames.last() {
+                    Some(frame) if frame.is_object && !self.after_key => {}
+                    _ => return Err(invalid("a key can only appear inside an object")),
+                }
+                self.separator()?;
+                write_string(&mut self.writer, key)?;
+                let colon: &[u8] = if self.indent.is_some() { b": " } else { b":" };
+                self.writer.write_all(colon)?;
+                self.after_key = true;
+            }
+            Event::String(s) => {
+                self.before_value()?;
+                write_string(&mut self.writer, s)?;
+            }
+            Event::Number(n) => {
+                self.before_value()?;
+                write_number(&mut self.writer, n)?;
+            }
+            Event::Bool(b) => {
+                self.before_value()?;
+                write!(self.writer, "{b}")?;
+            }
+            Event::Null => {
+                self.before_value()?;
+                self.writer.write_all(b"null")?;
+            }
+        }
+        Ok(())
+    }
+
+    // writes a whole value by turning it back into events. recursive, one call per level:
+    // fine for anything the parser made (MAX_DEPTH), a Value built in code is up to its maker
+    pub fn value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Null => self.event(&Event::Null),
+            Value::Bool(b) => self.event(&Event::Bool(*b)),
+            Value::Number(n) => self.event(&Event::Number(*n)),
+            Value::String(s) => self.event(&Event::String(s.clone())),
+            Value::Array(values) => {
+                self.event(&Event::StartArray)?;
+                for value in values {
+                    self.value(value)?;
+                }
+                self.event(&Event::EndArray)
+            }
+            Value::Object(map) => {
+                self.event(&Event::StartObject)?;
+                for (key, value) in map {
+                    self.event(&Event::Key(key.clone()))?;
+                    self.value(value)?;
+                }
+                self.event(&Event::EndObject)
+            }
+        }
+    }
+}
+
+// escapes what json requires and nothing more, like serde_json: non ascii text is written as is
+fn write_string(writer: &mut impl Write, s: &str) -> io::Result<()> {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    writer.write_all(b"\"")?;
+    let bytes = s.as_bytes();
+    let mut start = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escape: &[u8] = match byte {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x08 => b"\\b",
+            0x0c => b"\\f",
+            0x00..=0x1f => &[
+                b'\\',
+                b'u',
+                b'0',
+                b'0',
+                HEX[(byte >> 4) as usize],
+                HEX[(byte & 0xf) as usize],
+            ],
+            _ => continue,
+        };
+        writer.write_all(&bytes[start..i])?;
+        writer.write_all(escape)?;
+        start = i + 1;
+    }
+    writer.write_all(&bytes[start..])?;
+    writer.write_all(b"\"")
+}
+
+fn write_number(writer: &mut impl Write, n: &Number) -> io::Result<()> {
+    match n {
+        Number::PosInt(n) => write!(writer, "{n}"),
+        Number::NegInt(n) => write!(writer, "{n}"),
+        // Debug prints the shortest text that reads back to the same f64 and keeps the ".0"
+        Number::Float(f) if f.is_finite() => write!(writer, "{f:?}"),
+        // json has no NaN or infinity, serde_json writes null too
+        Number::Float(_) => writer.write_all(b"null"),
+    }
+}
@@ -2,72 +2,310 @@
 // -F is the feature flag to install required features.
 // cargo add serde_json also to get json serial/deserial
 
-use std::{collections::HashMap, fs, path::Path};
-
-// import serde
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    process::ExitCode,
+};
 
+// User derives Serialize and Deserialize (from serde) in the shared users crate.
 // add traits to auto generate code for serialize/deserialize
 // compiler detects the format and auto generates code
 // if a complex type is present inside struct then that struct also would need these traits
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    username: String,
-    email: String,
-    password: String,
-    role: String,
+// (that is why Role derives them too)
+// the reading/writing (serde_json::from_str / to_string) lives in users::file
+// serde is not tied to json, users::formats writes the same users as toml, yaml, csv,
+// messagepack and cbor just by calling a different library's to_string/from_str.
+use users::{
+    audit::{self, Action, AuditLog, Event, Outcome, Query},
+    encrypted::{self, KeySource},
+    file, formats, User, UsersError,
+};
+
+const USERS_PATH: &str = "users.json";
+const AUDIT_PATH: &str = "audit.log";
+
+// every login and every change to the users goes into the audit log.
+// a log that cannot be written (or no longer verifies) is reported but does not stop the
+// program, --verify-audit says what is wrong with it.
+// the log is opened (and verified) on the first event and kept open for the rest of the run.
+#[derive(Default)]
+struct Audit {
+    log: Option<AuditLog>,
+}
+
+impl Audit {
+    fn record(&mut self, event: Event) {
+        let log = match self.log.take() {
+            Some(log) => Ok(log),
+            None => AuditLog::open(AUDIT_PATH),
+        };
+        match log.and_then(|mut log| log.record(event).map(|_| log)) {
+            Ok(log) => self.log = Some(log),
+            // opened again next time, that reports what is wrong with it again
+            Err(e) => eprintln!("warning: not recorded in {AUDIT_PATH}: {e}"),
+        }
+    }
+}
+
+// instead of unwrap every failure is returned as a UsersError through Result
+// so the caller decides what to do with a broken file.
+// with recover a corrupt file is moved aside and the defaults are written instead.
+fn get_users(recover: bool) -> Result<HashMap<String, User>, UsersError> {
+    if recover {
+        let recovered = file::load_or_recover(USERS_PATH)?;
+        if let Some(quarantined) = recovered.quarantined {
+            eprintln!(
+                "{USERS_PATH} was corrupt, moved it to {} and restored the default users",
+                quarantined.display()
+            );
+        }
+        Ok(recovered.users)
+    } else {
+        file::load_or_create(USERS_PATH)
+    }
 }
 
-impl User {
-    fn new(username: String, email: String, password: String, role: String) -> User {
-        User {
-            username,
-            email,
-            password,
-            role,
+// users.json written before passwords were hashed still holds plaintext.
+// the first successful login replaces it with a hash and saves the file.
+// the check and the save happen under one lock (modify_users) so another process
+// changing the file at the same time does not lose its change.
+fn login(
+    users: &HashMap<String, User>,
+    username: &str,
+    password: &str,
+) -> Result<bool, UsersError> {
+    match users.get(username) {
+        Some(user) if user.needs_password_upgrade() => {
+            file::modify_users(USERS_PATH, |users| match users.get_mut(username) {
+                Some(user) => Ok(user.upgrade_password(password) || user.check_password(password)),
+                None => Ok(false),
+            })
         }
+        Some(user) => Ok(user.check_password(password)),
+        None => Ok(false),
     }
 }
 
-fn get_default_users() -> HashMap<String, User> {
-    let mut users = HashMap::new();
-    users.insert(
-        "admin".to_string(),
-        User::new(
-            "admin".to_string(),
-            "admin@localhost".to_string(),
-            "admin".to_string(),
-            "admin".to_string(),
-        ),
-    );
-    users.insert(
-        "user".to_string(),
-        User::new(
-            "user".to_string(),
-            "user@localhost".to_string(),
-            "user".to_string(),
-            "user".to_string(),
-        ),
-    );
-    users
+// same as try_read_line in the stdin-out lesson, with the question printed first.
+// None once the input has ended (ctrl-d, a closed pipe)
+fn prompt(question: &str) -> io::Result<Option<String>> {
+    print!("{question}: ");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
 }
 
-fn get_users() -> HashMap<String, User> {
-    let users_path = Path::new("users.json");
-    if users_path.exists() {
-        // read the file
-        let contents = fs::read_to_string(users_path).unwrap();
-        // deserialize
-        let users: HashMap<String, User> = serde_json::from_str(&contents).unwrap();
-        users
+// every answer goes into the builder, which lists everything that was wrong at once
+fn add_user(audit: &mut Audit) -> Result<(), UsersError> {
+    let mut answers = Vec::new();
+    for question in ["username", "email", "password", "role (admin/user)"] {
+        let answer = prompt(question).map_err(|source| UsersError::Io {
+            path: "<stdin>".into(),
+            source,
+        })?;
+        match answer {
+            Some(answer) => answers.push(answer),
+            None => {
+                eprintln!("\ninput ended, no user added");
+                return Ok(());
+            }
+        }
+    }
+    let [username, email, password, role]: [String; 4] =
+        answers.try_into().expect("an answer to every question");
+    let built = User::builder()
+        .username(username)
+        .email(email)
+        .password(password)
+        .role_name(role)
+        .build();
+    let user = match built {
+        Ok(user) => user,
+        Err(e) => {
+            eprintln!("cannot add this user:");
+            for error in e.errors {
+                eprintln!("- {error}");
+            }
+            return Ok(());
+        }
+    };
+    let username = user.username.clone();
+    let added = file::modify_users(USERS_PATH, |users| {
+        if users.contains_key(&username) {
+            return Ok::<_, UsersError>(false);
+        }
+        users.insert(username.clone(), user);
+        Ok(true)
+    })?;
+    let outcome = if added {
+        println!("added {username}");
+        Outcome::Success
     } else {
-        let users = get_default_users();
-        let contents = serde_json::to_string(&users).unwrap();
-        fs::write(users_path, contents).unwrap();
-        users
+        eprintln!("{username} already exists");
+        Outcome::Failure
+    };
+    audit.record(Event::new("cli", Action::UserCreate, &username, outcome));
+    Ok(())
+}
+
+// ? can be used in main too when it returns a Result,
+// here we print the error ourselves and exit with a failure code.
+fn run() -> Result<(), UsersError> {
+    // cargo run -- [--recover] <username> <password>
+    // cargo run -- --migrate [--dry-run]
+    // cargo run -- --add   (asks for the new user's fields)
+    // cargo run -- --export <path> | --import <path>   (format from the extension)
+    // cargo run -- --new-key <keyfile> | --encrypt <keyfile> | --decrypt <keyfile>
+    // cargo run -- --rotate-key <old keyfile> <new keyfile>
+    // cargo run -- --verify-audit | --audit <username>
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let has_flag = |args: &mut Vec<String>, flag: &str| {
+        let found = args.iter().any(|arg| arg == flag);
+        args.retain(|arg| arg != flag);
+        found
+    };
+    let recover = has_flag(&mut args, "--recover");
+    let migrate = has_flag(&mut args, "--migrate");
+    let dry_run = has_flag(&mut args, "--dry-run");
+    let add = has_flag(&mut args, "--add");
+    let verify_audit = has_flag(&mut args, "--verify-audit");
+    let mut audit = Audit::default();
+
+    if verify_audit {
+        match audit::verify(AUDIT_PATH) {
+            Ok(verified) => println!(
+                "{AUDIT_PATH} is intact, {} entries, head {}",
+                verified.entries, verified.head
+            ),
+            Err(e) => eprintln!("{e}"),
+        }
+        return Ok(());
+    }
+    if let [flag, username] = args.as_slice() {
+        if flag == "--audit" {
+            match audit::query(AUDIT_PATH, &Query::new().user(username)) {
+                Ok(entries) => {
+                    for entry in entries {
+                        let detail = entry.detail.as_deref().unwrap_or_default();
+                        println!(
+                            "{:>4} {} {} {} {} {:?} {detail}",
+                            entry.seq,
+                            entry.at,
+                            entry.actor,
+                            entry.action,
+                            entry.target,
+                            entry.outcome
+                        );
+                    }
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+            return Ok(());
+        }
+    }
+
+    if migrate {
+        let report = file::migrate_file(USERS_PATH, dry_run)?;
+        if dry_run {
+            println!("dry run, {USERS_PATH} was not changed");
+        } else {
+            audit.record(
+                Event::new("cli", Action::FileRewrite, USERS_PATH, Outcome::Success)
+                    .detail("migrate"),
+            );
+        }
+        println!("{report}");
+        return Ok(());
+    }
+
+    // the encrypted file is handled by users::encrypted, the rest of this lesson
+    // keeps working on the plain users.json
+    let result = match args.as_slice() {
+        [flag, key] if flag == "--new-key" => Some(encrypted::generate_key_file(key)),
+        [flag, key] if flag == "--encrypt" => Some(encrypted::encrypt_file(
+            USERS_PATH,
+            &KeySource::KeyFile(key.into()),
+        )),
+        [flag, key] if flag == "--decrypt" => Some(encrypted::decrypt_file(
+            USERS_PATH,
+            &KeySource::KeyFile(key.into()),
+        )),
+        [flag, old, new] if flag == "--rotate-key" => Some(encrypted::rotate_key(
+            USERS_PATH,
+            &KeySource::KeyFile(old.into()),
+            &KeySource::KeyFile(new.into()),
+        )),
+        _ => None,
+    };
+    if let Some(result) = result {
+        match result {
+            Ok(()) => println!("done"),
+            Err(e) => eprintln!("{}: {e}", args[0]),
+        }
+        return Ok(());
+    }
+    // checked before --recover gets a chance to mistake it for a corrupt file
+    let contents = std::fs::read(USERS_PATH).unwrap_or_default();
+    if encrypted::is_encrypted(&contents) {
+        eprintln!("{USERS_PATH} is encrypted, run with --decrypt <keyfile> first");
+        return Ok(());
     }
+
+    let users = get_users(recover)?;
+    if add {
+        return add_user(&mut audit);
+    }
+
+    match args.as_slice() {
+        [flag, path] if flag == "--export" => {
+            if let Err(e) = formats::export_file(path, &users) {
+                eprintln!("export failed: {e}");
+            }
+            return Ok(());
+        }
+        [flag, path] if flag == "--import" => {
+            match formats::import_file(path) {
+                Ok(imported) => {
+                    file::save_users(USERS_PATH, &imported)?;
+                    println!("imported {} users from {path}", imported.len());
+                    audit.record(
+                        Event::new("cli", Action::FileRewrite, USERS_PATH, Outcome::Success)
+                            .detail(format!("import from {path}")),
+                    );
+                }
+                Err(e) => eprintln!("import failed: {e}"),
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if let [username, password] = args.as_slice() {
+        let outcome = if login(&users, username, password)? {
+            println!("Logged in");
+            Outcome::Success
+        } else {
+            println!("Invalid username or password");
+            Outcome::Failure
+        };
+        audit.record(Event::new(username, Action::Login, username, outcome));
+    }
+    Ok(())
 }
 
-fn main() {
-    get_users();
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            if e.is_corrupt() {
+                eprintln!("run with --recover to move the broken file aside and start over");
+            }
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,178 @@
+// Turning the bytes a terminal sends into keys.
+//
+// printable characters come as their utf-8 bytes, ctrl+letter as the letter's position in
+// the alphabet (ctrl-a is 1), and the keys without a character as escape sequences:
+// ESC [ D is left, ESC [ 3 ~ is delete, ESC [ 1 ; 5 C is ctrl+right. alt+key is ESC
+// followed by the key.
+
+use std::io::{self, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    // always the lowercase letter, Ctrl('a') is ctrl-a
+    Ctrl(char),
+    Alt(char),
+    Enter,
+    Tab,
+    Backspace,
+    AltBackspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    Escape,
+    // a sequence this editor does not know, it is ignored
+    Unknown,
+}
+
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// None once the input has ended
+pub fn read_key(input: &mut dyn Read) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => escape(input)?,
+        1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0..=31 => Key::Unknown,
+        _ => utf8(byte, input)?,
+    };
+    Ok(Some(key))
+}
+
+fn utf8(first: u8, input: &mut dyn Read) -> io::Result<Key> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match read_byte(input)? {
+            Some(byte) => bytes.push(byte),
+            None => break,
+        }
+    }
+    Ok(std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+        .map_or(Key::Unknown, Key::Char))
+}
+
+// after an ESC. a lone escape key waits for the next byte, there is no timeout.
+fn escape(input: &mut dyn Read) -> io::Result<Key> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(Key::Escape);
+    };
+    match byte {
+        b'[' => csi(input),
+        // ESC O H and ESC O F, what some terminals send for home and end
+        b'O' => Ok(match read_byte(input)? {
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
+            _ => Key::Unknown,
+        }),
+        0x7f | 0x08 => Ok(Key::AltBackspace),
+        0x1b => Ok(Key::Escape),
+        byte if byte.is_ascii_graphic() => Ok(Key::Alt(byte.to_ascii_lowercase() as char)),
+        _ => Ok(Key::Unknown),
+    }
+}
+
+// ESC [ then numbers separated by ; and one final byte (a letter or ~)
+fn csi(input: &mut dyn Read) -> io::Result<Key> {
+    let mut params = String::new();
+    let last = loop {
+        match read_byte(input)? {
+            Some(byte @ 0x40..=0x7e) => break byte,
+            Some(byte) => params.push(byte as char),
+            None => return Ok(Key::Unknown),
+        }
+    };
+    // 5 is ctrl: ESC [ 1 ; 5 C
+    let ctrl = params.ends_with(";5");
+    Ok(match (last, params.as_str()) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) if ctrl => Key::WordRight,
+        (b'D', _) if ctrl => Key::WordLeft,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) | (b'~', "1" | "7") => Key::Home,
+        (b'F', _) | (b'~', "4" | "8") => Key::End,
+        (b'~', "3") => Key::Delete,
+        _ => Key::Unknown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(mut bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut bytes).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn decodes_what_a_terminal_sends() {
+        assert_eq!(
+            keys("aé€😀\r\t\x7f".as_bytes()),
+            [
+                Key::Char('a'),
+                Key::Char('é'),
+                Key::Char('€'),
+                Key::Char('😀'),
+                Key::Enter,
+                Key::Tab,
+                Key::Backspace
+            ]
+        );
+        assert_eq!(
+            keys(b"\x01\x12\x05"),
+            [Key::Ctrl('a'), Key::Ctrl('r'), Key::Ctrl('e')]
+        );
+        assert_eq!(
+            keys(b"\x1b[A\x1b[D\x1b[1;5C\x1b[3~\x1b[H\x1bOF\x1b[4~\x1b[15~"),
+            [
+                Key::Up,
+                Key::Left,
+                Key::WordRight,
+                Key::Delete,
+                Key::Home,
+                Key::End,
+                Key::End,
+                Key::Unknown
+            ]
+        );
+        assert_eq!(
+            keys(b"\x1bb\x1bF\x1b\x7f\x1b"),
+            [Key::Alt('b'), Key::Alt('f'), Key::AltBackspace, Key::Escape]
+        );
+    }
+}
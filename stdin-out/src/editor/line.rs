@@ -0,0 +1,251 @@
+// The text being edited and where the cursor is in it.
+//
+// the cursor moves by grapheme, what a reader sees as one character: é may be e plus a
+// combining accent (two chars), 👍🏽 is a thumb plus a skin tone, and 🇩🇪 two regional
+// letters. moving or deleting by char would split them. the cursor is a byte offset into
+// text that is always on a grapheme boundary.
+// a word is a run of letters and digits, like in emacs.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    text: String,
+    cursor: usize,
+}
+
+fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(char::is_alphanumeric)
+}
+
+impl Line {
+    // the cursor at the end
+    pub fn new(text: &str) -> Line {
+        Line {
+            text: text.to_string(),
+            cursor: text.len(),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    // how many columns the text before the cursor takes on screen, 😀 takes two
+    pub fn cursor_width(&self) -> usize {
+        self.text[..self.cursor].width()
+    }
+
+    fn prev(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    // the start of the word before the cursor, spaces before it skipped
+    fn word_start(&self) -> usize {
+        let mut seen_word = false;
+        for (i, g) in self.text[..self.cursor].grapheme_indices(true).rev() {
+            if is_word(g) {
+                seen_word = true;
+            } else if seen_word {
+                return i + g.len();
+            }
+        }
+        0
+    }
+
+    // the end of the word after the cursor
+    fn word_end(&self) -> usize {
+        let mut end = self.cursor;
+        let mut seen_word = false;
+        for g in self.text[self.cursor..].graphemes(true) {
+            if is_word(g) {
+                seen_word = true;
+            } else if seen_word {
+                break;
+            }
+            end += g.len();
+        }
+        end
+    }
+
+    pub fn insert(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+        // typed before a combining mark, the mark now belongs to what was typed
+        self.cursor = self
+            .text
+            .grapheme_indices(true)
+            .map(|(i, _)| i)
+            .chain([self.text.len()])
+            .find(|&boundary| boundary >= self.cursor)
+            .unwrap_or(self.text.len());
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.prev();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next();
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn word_left(&mut self) {
+        self.cursor = self.word_start();
+    }
+
+    pub fn word_right(&mut self) {
+        self.cursor = self.word_end();
+    }
+
+    // removes start..end and returns it
+    fn cut(&mut self, start: usize, end: usize) -> String {
+        let removed: String = self.text.drain(start..end).collect();
+        self.cursor = start;
+        removed
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.prev();
+        self.cut(start, self.cursor);
+    }
+
+    pub fn delete(&mut self) {
+        let cursor = self.cursor;
+        let end = self.next();
+        self.cut(cursor, end);
+    }
+
+    // the kill_ functions return what they removed, for yanking back
+    pub fn kill_to_end(&mut self) -> String {
+        self.cut(self.cursor, self.text.len())
+    }
+
+    pub fn kill_to_start(&mut self) -> String {
+        self.cut(0, self.cursor)
+    }
+
+    pub fn kill_word_back(&mut self) -> String {
+        let start = self.word_start();
+        self.cut(start, self.cursor)
+    }
+
+    pub fn kill_word_forward(&mut self) -> String {
+        let cursor = self.cursor;
+        let end = self.word_end();
+        self.cut(cursor, end)
+    }
+
+    // swaps the two graphemes around the cursor (the last two at the end of the line)
+    pub fn transpose(&mut self) {
+        let cursor = self.cursor;
+        if self.cursor == self.text.len() {
+            self.left();
+        }
+        let middle = self.cursor;
+        let start = self.prev();
+        let end = self.next();
+        // at the start, or fewer than two graphemes
+        if start == middle || middle == end {
+            self.cursor = cursor;
+            return;
+        }
+        let swapped = format!("{}{}", &self.text[middle..end], &self.text[start..middle]);
+        self.text.replace_range(start..end, &swapped);
+        self.cursor = end;
+    }
+
+    // replaces start..cursor, for completion
+    pub fn replace_before_cursor(&mut self, start: usize, with: &str) {
+        self.text.replace_range(start..self.cursor, with);
+        self.cursor = start + with.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_and_deletes_whole_graphemes() {
+        // e + combining acute, a thumb with a skin tone, a flag
+        let mut line = Line::new("ae\u{301}👍🏽🇩🇪");
+        line.left();
+        assert_eq!(line.text()[line.cursor()..], *"🇩🇪");
+        line.backspace();
+        assert_eq!(line.text(), "ae\u{301}🇩🇪");
+        line.left();
+        assert_eq!(line.cursor(), 1);
+        line.delete();
+        assert_eq!(line.text(), "a🇩🇪");
+        line.end();
+        line.backspace();
+        assert_eq!(line.text(), "a");
+        assert_eq!(Line::new("日本").cursor_width(), 4);
+
+        // typing a combining mark keeps the cursor after the whole grapheme
+        let mut line = Line::new("e");
+        line.insert("\u{301}");
+        assert_eq!(line.cursor(), line.text().len());
+        line.backspace();
+        assert_eq!(line.text(), "");
+    }
+
+    #[test]
+    fn words_kills_and_transpose() {
+        let mut line = Line::new("role  alice admin");
+        line.word_left();
+        assert_eq!(line.cursor(), 12);
+        line.word_left();
+        line.word_left();
+        assert_eq!(line.cursor(), 0);
+        line.word_right();
+        assert_eq!(line.cursor(), 4);
+        assert_eq!(line.kill_word_forward(), "  alice");
+        assert_eq!(line.text(), "role admin");
+        line.end();
+        assert_eq!(line.kill_word_back(), "admin");
+        assert_eq!(line.kill_word_back(), "role ");
+        assert_eq!(line.text(), "");
+
+        let mut line = Line::new("find bob");
+        line.home();
+        line.word_right();
+        assert_eq!(line.kill_to_end(), " bob");
+        assert_eq!(line.kill_to_start(), "find");
+
+        let mut line = Line::new("lsit");
+        line.left();
+        line.left();
+        line.transpose();
+        assert_eq!(line.text(), "list");
+        line.end();
+        line.transpose();
+        assert_eq!(line.text(), "lits");
+        let mut line = Line::new("x");
+        line.transpose();
+        assert_eq!(line.text(), "x");
+    }
+}
@@ -0,0 +1,552 @@
+// A line editor for terminals, read_line with arrow keys, history and search.
+//
+// the terminal is switched to raw mode (terminal.rs) so every key arrives as it is pressed,
+// the keys are decoded (key.rs) and applied to the line (line.rs), and after each key the
+// line is drawn again: back to the start of the row, prompt, text, clear the rest, move the
+// cursor to its column. a line longer than the terminal is wide is not handled.
+//
+// the keys are the emacs ones bash uses too:
+//   ctrl-a/ctrl-e   start/end of line         ctrl-b/ctrl-f   one character left/right
+//   alt-b/alt-f     one word left/right       ctrl-k/ctrl-u   cut to the end/start
+//   ctrl-w          cut the word before       alt-d           cut the word after
+//   ctrl-y          paste what was cut        ctrl-t          swap two characters
+//   up/ctrl-p       older history             down/ctrl-n     newer history
+//   ctrl-r          search the history        ctrl-g          leave the search
+//   tab             complete                  ctrl-l          clear the screen
+//   ctrl-c          drop the line             ctrl-d          end (on an empty line)
+// arrows, home, end and delete work as expected.
+//
+// when stdin is not a terminal (a pipe, a file) there is nothing to edit, the line is read
+// with try_read_line as it is.
+// read_secret is for passwords, the terminal's own line editing without showing the line.
+
+use std::io::{self, BufRead, Read, Write};
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::history::History;
+
+mod key;
+mod line;
+mod terminal;
+
+use key::{read_key, Key};
+use line::Line;
+use terminal::{is_tty, NoEcho, RawMode, CTRL_C};
+
+// what the word before the cursor could become, see Shell::complete
+pub type Completer<'a> = &'a dyn Fn(&str) -> (usize, Vec<String>);
+
+// what a key did to the line
+enum Step {
+    Continue,
+    Accept,
+    Cancel,
+    Eof,
+    Clear,
+}
+
+struct Search {
+    query: String,
+    // index into the history of the entry shown
+    found: Option<usize>,
+    failed: bool,
+    // put back when the search is left with ctrl-g
+    original: Line,
+}
+
+// one read_line
+struct State<'h> {
+    line: Line,
+    history: &'h [String],
+    // the history entry shown, history.len() is the line being typed
+    index: usize,
+    // the line being typed, kept while looking at the history
+    draft: String,
+    search: Option<Search>,
+    // completions to show under the line on the next render
+    listing: Option<String>,
+}
+
+fn common_prefix(words: &[String]) -> &str {
+    let first = &words[0];
+    let mut len = first.len();
+    for word in &words[1..] {
+        len = first
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(word.len()), |((i, _), _)| i.min(len));
+    }
+    &first[..len]
+}
+
+impl<'h> State<'h> {
+    fn new(history: &'h [String]) -> State<'h> {
+        State {
+            line: Line::default(),
+            history,
+            index: history.len(),
+            draft: String::new(),
+            search: None,
+            listing: None,
+        }
+    }
+
+    fn older(&mut self) {
+        if self.index == 0 {
+            return;
+        }
+        if self.index == self.history.len() {
+            self.draft = self.line.text().to_string();
+        }
+        self.index -= 1;
+        self.line = Line::new(&self.history[self.index]);
+    }
+
+    fn newer(&mut self) {
+        if self.index >= self.history.len() {
+            return;
+        }
+        self.index += 1;
+        self.line = match self.history.get(self.index) {
+            Some(entry) => Line::new(entry),
+            None => Line::new(&self.draft),
+        };
+    }
+
+    // the newest entry before `before` that contains the query
+    fn find(&mut self, before: usize) {
+        let history = self.history;
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        match (0..before)
+            .rev()
+            .find(|&i| history[i].contains(&search.query))
+        {
+            Some(i) => {
+                search.found = Some(i);
+                search.failed = false;
+            }
+            None => search.failed = true,
+        }
+    }
+
+    // None when the key ends the search and should then be handled as usual
+    fn search_key(&mut self, key: Key) -> Option<Step> {
+        let search = self.search.as_mut()?;
+        let newest = self.history.len();
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                // the entry shown may still match
+                let before = search.found.map_or(newest, |i| i + 1);
+                self.find(before);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                self.find(newest);
+            }
+            Key::Ctrl('r') => {
+                let before = search.found.unwrap_or(newest);
+                self.find(before);
+            }
+            Key::Ctrl('g') | Key::Escape => {
+                self.line = search.original.clone();
+                self.search = None;
+            }
+            _ => {
+                if let Some(i) = search.found {
+                    self.line = Line::new(&self.history[i]);
+                    self.index = i;
+                }
+                self.search = None;
+                return None;
+            }
+        }
+        Some(Step::Continue)
+    }
+
+    fn complete(&mut self, completer: Completer) {
+        let cursor = self.line.cursor();
+        let (start, candidates) = completer(&self.line.text()[..cursor]);
+        match candidates.as_slice() {
+            [] => {}
+            [only] => self.line.replace_before_cursor(start, &format!("{only} ")),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > cursor - start {
+                    self.line.replace_before_cursor(start, prefix);
+                } else {
+                    self.listing = Some(candidates.join("  "));
+                }
+            }
+        }
+    }
+
+    fn render(&mut self, prompt: &str, out: &mut dyn Write) -> io::Result<()> {
+        let mut screen = String::from("\r");
+        if let Some(listing) = self.listing.take() {
+            screen.push_str(&format!("\x1b[K{listing}\r\n"));
+        }
+        let (prompt, line) = match &self.search {
+            Some(search) => (
+                format!(
+                    "({}reverse-i-search)'{}': ",
+                    if search.failed { "failed " } else { "" },
+                    search.query
+                ),
+                search
+                    .found
+                    .map_or_else(Line::default, |i| Line::new(&self.history[i])),
+            ),
+            None => (prompt.to_string(), self.line.clone()),
+        };
+        screen.push_str(&prompt);
+        screen.push_str(line.text());
+        // clear what is left of a longer line drawn before
+        screen.push_str("\x1b[K\r");
+        let column = prompt.width() + line.cursor_width();
+        if column > 0 {
+            screen.push_str(&format!("\x1b[{column}C"));
+        }
+        out.write_all(screen.as_bytes())?;
+        out.flush()
+    }
+}
+
+// a password or anything else that should not be on the screen, None once the input has
+// ended. from a pipe it is read as it is, there is nothing to hide.
+pub fn read_secret(prompt: &str) -> io::Result<Option<String>> {
+    print!("{prompt}");
+    io::stdout().flush()?;
+    if !is_tty(libc::STDIN_FILENO) {
+        return crate::try_read_line();
+    }
+    let line = {
+        let _no_echo = NoEcho::enable()?;
+        read_until_enter_or_cancel()
+    };
+    // the enter was not echoed either
+    println!();
+    line
+}
+
+// try_read_line, except that a line ended by ctrl-c is None as well. read_line would
+// keep waiting for a \n that the terminal is not going to send.
+fn read_until_enter_or_cancel() -> io::Result<Option<String>> {
+    let mut stdin = io::stdin().lock();
+    let mut bytes = Vec::new();
+    loop {
+        let buffer = stdin.fill_buf()?;
+        if buffer.is_empty() {
+            // ctrl-d
+            if bytes.is_empty() {
+                return Ok(None);
+            }
+            break;
+        }
+        match buffer.iter().position(|&b| b == b'\n' || b == CTRL_C) {
+            Some(end) => {
+                let cancelled = buffer[end] == CTRL_C;
+                bytes.extend_from_slice(&buffer[..end]);
+                stdin.consume(end + 1);
+                if cancelled {
+                    return Ok(None);
+                }
+                break;
+            }
+            None => {
+                let len = buffer.len();
+                bytes.extend_from_slice(buffer);
+                stdin.consume(len);
+            }
+        }
+    }
+    let line = String::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not valid utf-8"))?;
+    Ok(Some(line.trim_end_matches('\r').to_string()))
+}
+
+#[derive(Debug, Default)]
+pub struct Editor {
+    // what ctrl-k, ctrl-u and ctrl-w cut last, ctrl-y pastes it
+    cut: String,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor::default()
+    }
+
+    // the line typed, None once the input has ended (ctrl-d on an empty line).
+    // the line is not added to the history, that is up to the caller.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        history: &History,
+        completer: Option<Completer>,
+    ) -> io::Result<Option<String>> {
+        let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+        if !is_tty(libc::STDIN_FILENO) || dumb {
+            print!("{prompt}");
+            io::stdout().flush()?;
+            return crate::try_read_line();
+        }
+        let _raw = RawMode::enable()?;
+        self.edit(
+            prompt,
+            history.entries(),
+            completer,
+            &mut io::stdin().lock(),
+            &mut io::stdout().lock(),
+        )
+    }
+
+    // the editing itself, apart from the terminal so it works on any bytes
+    fn edit(
+        &mut self,
+        prompt: &str,
+        history: &[String],
+        completer: Option<Completer>,
+        input: &mut dyn Read,
+        out: &mut dyn Write,
+    ) -> io::Result<Option<String>> {
+        let mut state = State::new(history);
+        state.render(prompt, out)?;
+        loop {
+            let Some(key) = read_key(input)? else {
+                out.write_all(b"\r\n")?;
+                return Ok(None);
+            };
+            match self.apply(&mut state, key, completer) {
+                Step::Continue => state.render(prompt, out)?,
+                Step::Accept => {
+                    state.render(prompt, out)?;
+                    out.write_all(b"\r\n")?;
+                    return Ok(Some(state.line.text().to_string()));
+                }
+                // like a shell, the line is dropped and a new one starts
+                Step::Cancel => {
+                    out.write_all(b"^C\r\n")?;
+                    return Ok(Some(String::new()));
+                }
+                Step::Eof => {
+                    out.write_all(b"\r\n")?;
+                    return Ok(None);
+                }
+                Step::Clear => {
+                    out.write_all(b"\x1b[H\x1b[2J")?;
+                    state.render(prompt, out)?;
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, state: &mut State, key: Key, completer: Option<Completer>) -> Step {
+        if let Some(step) = state.search_key(key) {
+            return step;
+        }
+        let line = &mut state.line;
+        let cut = match key {
+            Key::Enter => return Step::Accept,
+            Key::Ctrl('c') => return Step::Cancel,
+            Key::Ctrl('d') if line.text().is_empty() => return Step::Eof,
+            Key::Ctrl('l') => return Step::Clear,
+            Key::Char(c) => {
+                line.insert(c.encode_utf8(&mut [0; 4]));
+                None
+            }
+            Key::Backspace => {
+                line.backspace();
+                None
+            }
+            Key::Delete | Key::Ctrl('d') => {
+                line.delete();
+                None
+            }
+            Key::Left | Key::Ctrl('b') => {
+                line.left();
+                None
+            }
+            Key::Right | Key::Ctrl('f') => {
+                line.right();
+                None
+            }
+            Key::Home | Key::Ctrl('a') => {
+                line.home();
+                None
+            }
+            Key::End | Key::Ctrl('e') => {
+                line.end();
+                None
+            }
+            Key::WordLeft | Key::Alt('b') => {
+                line.word_left();
+                None
+            }
+            Key::WordRight | Key::Alt('f') => {
+                line.word_right();
+                None
+            }
+            Key::Ctrl('k') => Some(line.kill_to_end()),
+            Key::Ctrl('u') => Some(line.kill_to_start()),
+            Key::Ctrl('w') | Key::AltBackspace => Some(line.kill_word_back()),
+            Key::Alt('d') => Some(line.kill_word_forward()),
+            Key::Ctrl('y') => {
+                line.insert(&self.cut);
+                None
+            }
+            Key::Ctrl('t') => {
+                line.transpose();
+                None
+            }
+            Key::Up | Key::Ctrl('p') => {
+                state.older();
+                None
+            }
+            Key::Down | Key::Ctrl('n') => {
+                state.newer();
+                None
+            }
+            Key::Ctrl('r') => {
+                state.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                    original: state.line.clone(),
+                });
+                None
+            }
+            Key::Tab => {
+                if let Some(completer) = completer {
+                    state.complete(completer);
+                }
+                None
+            }
+            _ => None,
+        };
+        // cutting nothing keeps what was cut before
+        if let Some(cut) = cut.filter(|cut| !cut.is_empty()) {
+            self.cut = cut;
+        }
+        Step::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_keys(
+        bytes: &[u8],
+        history: &[&str],
+        completer: Option<Completer>,
+    ) -> (Option<String>, String) {
+        let history: Vec<String> = history.iter().map(|s| s.to_string()).collect();
+        let mut out = Vec::new();
+        let line = Editor::new()
+            .edit("> ", &history, completer, &mut &bytes[..], &mut out)
+            .unwrap();
+        (line, String::from_utf8(out).unwrap())
+    }
+
+    fn typed(bytes: &[u8], history: &[&str]) -> Option<String> {
+        type_keys(bytes, history, None).0
+    }
+
+    #[test]
+    fn edits_with_emacs_keys() {
+        // three times left, o, ctrl-a, hello, ctrl-e, !
+        assert_eq!(
+            typed(b"wrld\x1b[D\x1b[D\x1b[Do\x01hello \x05!\r", &[]).as_deref(),
+            Some("hello world!")
+        );
+        // ctrl-w twice, ctrl-y pastes the last cut
+        assert_eq!(
+            typed(b"list alice\x17\x17find \x19\r", &[]).as_deref(),
+            Some("find list ")
+        );
+        // alt-b, ctrl-k, then ctrl-u which cuts nothing after ctrl-a
+        assert_eq!(
+            typed(b"role bob admin\x1bb\x0b\x01\x15\x19\r", &[]).as_deref(),
+            Some("adminrole bob ")
+        );
+        // ctrl-d on an empty line, ctrl-c, and the input ending
+        assert_eq!(typed(b"\x04", &[]), None);
+        assert_eq!(typed(b"abc\x03", &[]).as_deref(), Some(""));
+        assert_eq!(typed(b"abc", &[]), None);
+    }
+
+    #[test]
+    fn walks_the_history() {
+        let history = ["list", "find bob", "role bob admin"];
+        // up, up, down, down is back to what was typed
+        assert_eq!(
+            typed(b"x\x1b[A\x1b[A\x1b[B\x1b[B\r", &history).as_deref(),
+            Some("x")
+        );
+        assert_eq!(typed(b"\x10\x10\r", &history).as_deref(), Some("find bob"));
+        assert_eq!(
+            typed(b"\x10\x10\x10\x10\r", &history).as_deref(),
+            Some("list")
+        );
+    }
+
+    #[test]
+    fn searches_backwards() {
+        let history = ["list", "find bob", "role bob admin"];
+        // the newest match, then the one before it
+        assert_eq!(
+            typed(b"\x12bob\r", &history).as_deref(),
+            Some("role bob admin")
+        );
+        assert_eq!(
+            typed(b"\x12bob\x12\r", &history).as_deref(),
+            Some("find bob")
+        );
+        // another key takes the match and goes on editing
+        assert_eq!(
+            typed(b"\x12li\x05 -a\r", &history).as_deref(),
+            Some("list -a")
+        );
+        // ctrl-g puts back what was there
+        let (line, out) = type_keys(b"ab\x12zzz\x07\r", &history, None);
+        assert_eq!(line.as_deref(), Some("ab"));
+        assert!(out.contains("(failed reverse-i-search)'zzz': "), "{out:?}");
+    }
+
+    #[test]
+    fn completes_and_lists() {
+        let words = ["help", "history", "passwd"];
+        let complete = |line: &str| {
+            let start = line.rfind(' ').map_or(0, |i| i + 1);
+            let found = words
+                .iter()
+                .filter(|word| word.starts_with(&line[start..]))
+                .map(|word| word.to_string())
+                .collect();
+            (start, found)
+        };
+        assert_eq!(
+            type_keys(b"pa\tbob\r", &[], Some(&complete)).0.as_deref(),
+            Some("passwd bob")
+        );
+        assert_eq!(
+            type_keys(b"help hi\t\r", &[], Some(&complete)).0.as_deref(),
+            Some("help history ")
+        );
+        let (line, out) = type_keys(b"h\t\r", &[], Some(&complete));
+        assert_eq!(line.as_deref(), Some("h"));
+        assert!(out.contains("help  history\r\n"), "{out:?}");
+    }
+
+    #[test]
+    fn cursor_column_counts_wide_characters() {
+        // the prompt is 2 columns, 日 another 2
+        let (line, out) = type_keys("日本\x1b[D\r".as_bytes(), &[], None);
+        assert_eq!(line.as_deref(), Some("日本"));
+        assert!(out.ends_with("> 日本\x1b[K\r\x1b[4C\r\n"), "{out:?}");
+    }
+}
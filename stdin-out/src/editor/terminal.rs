@@ -0,0 +1,87 @@
+// Switching the terminal between cooked and raw mode with termios.
+//
+// cooked is the default: the terminal collects a line, handles backspace itself, echoes
+// what is typed and only hands the line over after enter. raw hands over every key as it
+// is pressed and shows nothing, so the editor can draw the line itself.
+// the settings are read with tcgetattr, changed, and put back when RawMode is dropped,
+// also when the editor returns early with an error. (a panic unwinds through drop too.)
+// NoEcho is cooked mode without the echo, for passwords: the terminal still collects the
+// line and handles backspace, it only does not show what is typed. ctrl-c does not kill
+// the program there, the echo would stay off after it. it ends the line instead (VEOL) and
+// arrives as its byte, read_secret takes that as a cancel.
+
+use std::{io, mem::MaybeUninit};
+
+pub const CTRL_C: u8 = 0x03;
+
+pub fn is_tty(fd: libc::c_int) -> bool {
+    // safe to call with any number, a closed or non terminal fd is just 0
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+fn get() -> io::Result<libc::termios> {
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+    // tcgetattr fills in the whole struct when it returns 0
+    unsafe {
+        if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(termios.assume_init())
+    }
+}
+
+fn set(termios: &libc::termios) -> io::Result<()> {
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<RawMode> {
+        let original = get()?;
+        let mut raw = original;
+        // no ctrl-s/ctrl-q flow control, \r is not turned into \n
+        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+        raw.c_cflag |= libc::CS8;
+        // no echo, no line collecting, ctrl-c and ctrl-v arrive as keys
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        // a read returns as soon as there is one byte
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        set(&raw)?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // nothing sensible to do if it fails, the terminal is gone
+        let _ = set(&self.original);
+    }
+}
+
+pub struct NoEcho {
+    original: libc::termios,
+}
+
+impl NoEcho {
+    pub fn enable() -> io::Result<NoEcho> {
+        let original = get()?;
+        let mut quiet = original;
+        quiet.c_lflag &= !(libc::ECHO | libc::ISIG);
+        quiet.c_cc[libc::VEOL] = CTRL_C;
+        set(&quiet)?;
+        Ok(NoEcho { original })
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        let _ = set(&self.original);
+    }
+}
@@ -0,0 +1,105 @@
+// The lines typed so far, oldest first, kept in a file so they are there after a restart.
+//
+// one line per entry, every new line is appended to the file as soon as it is typed.
+// a line the same as the one before is not kept twice, and neither is an empty one.
+// only the newest max lines are kept, the file is cut down to them when it is loaded.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<String>,
+    max: usize,
+}
+
+impl History {
+    pub const DEFAULT_MAX: usize = 1000;
+
+    // not saved anywhere
+    pub fn in_memory() -> History {
+        History {
+            path: None,
+            entries: Vec::new(),
+            max: History::DEFAULT_MAX,
+        }
+    }
+
+    // a missing file is an empty history, it is created with the first line
+    pub fn load(path: impl AsRef<Path>, max: usize) -> io::Result<History> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries: Vec<String> = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        if entries.len() > max {
+            entries.drain(..entries.len() - max);
+            let mut contents = entries.join("\n");
+            contents.push('\n');
+            fs::write(&path, contents)?;
+        }
+        Ok(History {
+            path: Some(path),
+            entries,
+            max,
+        })
+    }
+
+    pub fn push(&mut self, line: &str) -> io::Result<()> {
+        if line.trim().is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return Ok(());
+        }
+        // a line break inside would come back as two entries
+        let line = line.replace(['\n', '\r'], " ");
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{line}")?;
+        }
+        self.entries.push(line);
+        if self.entries.len() > self.max {
+            self.entries.remove(0);
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::load(&path, 3).unwrap();
+        for line in ["list", "list", "", "find a", "role bob admin", "help"] {
+            history.push(line).unwrap();
+        }
+        // duplicates and empty lines skipped, only the newest 3 kept
+        assert_eq!(history.entries(), ["find a", "role bob admin", "help"]);
+
+        let history = History::load(&path, 3).unwrap();
+        assert_eq!(history.entries(), ["find a", "role bob admin", "help"]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "find a\nrole bob admin\nhelp\n"
+        );
+    }
+}
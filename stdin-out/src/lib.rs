@@ -0,0 +1,38 @@
+// read_line is the lesson, the rest is built on top of it:
+// history keeps the lines typed in a file so they survive a restart,
+// editor reads a line from a terminal with arrow keys, history and search,
+// shell is a small command shell for managing the users file.
+
+use std::io;
+
+pub mod editor;
+pub mod history;
+pub mod shell;
+
+pub fn read_line() -> String {
+    // mutable as its changed by standard input
+    let mut line = String::new();
+    // since stdin will modify it we need to pass mutable reference.
+    // this might error if we run the prog with has no input stream
+    // but since we know it wont error out in our case we will ignore error handling using unwrap
+    std::io::stdin().read_line(&mut line).unwrap();
+    // readline will also include \n at the end or carriage return
+    // we remove it using trim()
+    // trim() however return &str which is a constant string in memory
+    line.trim().to_string()
+
+    // there are other streams like stdout, stderr etc.
+}
+
+// read_line for a loop: an empty line and the end of input (ctrl-d, a closed pipe) both
+// look like "" there, here the end is None. only the line ending is removed, spaces and
+// tabs are left for the caller.
+pub fn try_read_line() -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
@@ -1,19 +1,61 @@
-fn read_line() -> String {
-    // mutable as its changed by standard input
-    let mut line = String::new();
-    // since stdin will modify it we need to pass mutable reference.
-    // this might error if we run the prog with has no input stream
-    // but since we know it wont error out in our case we will ignore error handling using unwrap
-    std::io::stdin().read_line(&mut line).unwrap();
-    // readline will also include \n at the end or carriage return
-    // we remove it using trim()
-    // trim() however return &str which is a constant string in memory
-    line.trim().to_string()
+// read_line lives in the library (src/lib.rs) so the shell can use it too.
+//   cargo run -p stdin-out                       reads one line and prints it back
+//   cargo run -p stdin-out -- shell [users.json]  a shell for managing the users file
 
-    // there are other streams like stdout, stderr etc.
+use std::{io, process::ExitCode};
+
+use stdin_out::{
+    editor::{self, Editor},
+    history::History,
+    read_line,
+    shell::{self, Shell},
+};
+use users::file;
+
+const HISTORY_PATH: &str = ".users_history";
+
+// the shell only asks for passwords, what is typed is not shown
+fn ask(question: &str) -> Option<String> {
+    editor::read_secret(&format!("{question}: ")).ok().flatten()
+}
+
+fn run_shell(users_path: &str) -> io::Result<()> {
+    // a missing file starts out with the default users
+    file::load_or_create(users_path).map_err(io::Error::other)?;
+    let history = History::load(HISTORY_PATH, History::DEFAULT_MAX)
+        .map_err(|e| io::Error::new(e.kind(), format!("{HISTORY_PATH}: {e}")))?;
+    let mut shell = Shell::new(users_path, ask)
+        .with_audit("audit.log")
+        .with_history(history);
+    println!("type help for the commands");
+    // the editor falls back to try_read_line by itself when stdin is not a terminal
+    let mut editor = Editor::new();
+    shell::run(
+        &mut shell,
+        |shell, prompt| {
+            editor.read_line(prompt, shell.history(), Some(&|line| shell.complete(line)))
+        },
+        &mut io::stdout(),
+    )
 }
 
-fn main() {
-    let line = read_line();
-    println!("{}", line);
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [command] if command == "shell" => run_shell("users.json"),
+        [command, path] if command == "shell" => run_shell(path),
+        _ => {
+            let line = read_line();
+            println!("{}", line);
+            Ok(())
+        }
+    };
+    // e.g. a .users_history that cannot be read, said plainly instead of a panic
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,722 @@
+// A command shell for the users file, read_line in a loop:
+//
+//   users> add alice alice@example.com admin
+//   password: ...
+//   users> find "example.com"
+//   users> role alice user
+//
+// a line is split into words like a unix shell does it: spaces separate words, 'single' and
+// "double" quotes keep spaces inside a word, a backslash takes the next character as it is.
+// every command reads and writes the file under its lock (file::modify_users), so other
+// programs using users.json at the same time are fine. changes go to the audit log too.
+// passwords are asked for on their own line, never typed as part of a command, so they do
+// not end up in the history.
+// a command that fails prints why and the shell carries on, only quit (or ctrl-d) ends it.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use users::{
+    audit::{Action, AuditLog, Event, Outcome},
+    builder::BuildError,
+    file,
+    password_policy::{PasswordPolicy, PolicyError},
+    Role, User, UsersError, ValidationError,
+};
+
+use crate::history::History;
+
+// name, arguments, what it does
+const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "add",
+        "<username> <email> [role]",
+        "add a user, asks for the password",
+    ),
+    ("remove", "<username>", "remove a user"),
+    ("list", "", "list every user"),
+    (
+        "find",
+        "<text>",
+        "users whose username or email contains the text",
+    ),
+    ("passwd", "<username>", "change a user's password"),
+    ("role", "<username> <admin|user>", "change a user's role"),
+    ("history", "", "the commands typed so far"),
+    (
+        "help",
+        "[command]",
+        "this list, or the usage of one command",
+    ),
+    ("quit", "", "leave the shell (ctrl-d works too)"),
+];
+
+// recorded as the actor in the audit log
+const ACTOR: &str = "shell";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnterminatedQuote(char),
+    TrailingBackslash,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote) => write!(f, "missing closing {quote}"),
+            ParseError::TrailingBackslash => write!(f, "nothing after the last \\"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// splits a line into words, see the top of the file
+pub fn split_args(line: &str) -> Result<Vec<String>, ParseError> {
+    let mut args = Vec::new();
+    // None between words, "" is an empty word ('' or "")
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(word.take()),
+            '\\' => {
+                let next = chars.next().ok_or(ParseError::TrailingBackslash)?;
+                word.get_or_insert_with(String::new).push(next);
+            }
+            '\'' | '"' => {
+                let quote = c;
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        None => return Err(ParseError::UnterminatedQuote(quote)),
+                        Some(c) if c == quote => break,
+                        // inside double quotes only \" and \\ are escapes
+                        Some('\\') if quote == '"' => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(ParseError::UnterminatedQuote(quote)),
+                        },
+                        Some(c) => word.push(c),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(word);
+    Ok(args)
+}
+
+#[derive(Debug)]
+pub enum ShellError {
+    Parse(ParseError),
+    UnknownCommand(String),
+    // the usage line of the command
+    Usage(String),
+    NoSuchUser(String),
+    UserExists(String),
+    InvalidUser(BuildError),
+    InvalidRole(ValidationError),
+    WeakPassword(PolicyError),
+    PasswordsDiffer,
+    // the input ended while asking for something
+    Cancelled,
+    // removing or demoting them would leave nobody to manage the users
+    LastAdmin(String),
+    Users(UsersError),
+    Io(io::Error),
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::Parse(e) => write!(f, "{e}"),
+            ShellError::UnknownCommand(name) => {
+                write!(f, "unknown command '{name}', type help for the list")
+            }
+            ShellError::Usage(usage) => write!(f, "usage: {usage}"),
+            ShellError::NoSuchUser(username) => write!(f, "there is no user '{username}'"),
+            ShellError::UserExists(username) => write!(f, "'{username}' already exists"),
+            ShellError::InvalidUser(e) => write!(f, "{e}"),
+            ShellError::InvalidRole(e) => write!(f, "{e}"),
+            ShellError::WeakPassword(e) => write!(f, "{e}"),
+            ShellError::PasswordsDiffer => write!(f, "the passwords do not match"),
+            ShellError::Cancelled => write!(f, "cancelled"),
+            ShellError::LastAdmin(username) => {
+                write!(f, "{username} is the last admin, there has to be one")
+            }
+            ShellError::Users(e) => write!(f, "{e}"),
+            ShellError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {}
+
+impl From<ParseError> for ShellError {
+    fn from(e: ParseError) -> Self {
+        ShellError::Parse(e)
+    }
+}
+
+impl From<UsersError> for ShellError {
+    fn from(e: UsersError) -> Self {
+        ShellError::Users(e)
+    }
+}
+
+impl From<io::Error> for ShellError {
+    fn from(e: io::Error) -> Self {
+        ShellError::Io(e)
+    }
+}
+
+fn usage(name: &str) -> ShellError {
+    let (name, args, _) = COMMANDS
+        .iter()
+        .find(|(command, _, _)| *command == name)
+        .expect("usage of a known command");
+    ShellError::Usage(format!("{name} {args}").trim_end().to_string())
+}
+
+fn is_last_admin(users: &HashMap<String, User>, username: &str) -> bool {
+    users[username].role == Role::Admin
+        && users
+            .values()
+            .filter(|user| user.role == Role::Admin)
+            .count()
+            == 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+// asks a question on a line of its own, None when the input has ended
+type Ask<'a> = Box<dyn FnMut(&str) -> Option<String> + 'a>;
+
+pub struct Shell<'a> {
+    users_path: PathBuf,
+    audit_path: Option<PathBuf>,
+    // opened on the first change and kept, opening reads the whole log to verify it
+    audit_log: Option<AuditLog>,
+    policy: PasswordPolicy,
+    history: History,
+    ask: Ask<'a>,
+}
+
+impl<'a> Shell<'a> {
+    pub fn new(
+        users_path: impl Into<PathBuf>,
+        ask: impl FnMut(&str) -> Option<String> + 'a,
+    ) -> Shell<'a> {
+        Shell {
+            users_path: users_path.into(),
+            audit_path: None,
+            audit_log: None,
+            policy: PasswordPolicy::default(),
+            history: History::in_memory(),
+            ask: Box::new(ask),
+        }
+    }
+
+    pub fn with_audit(mut self, path: impl Into<PathBuf>) -> Shell<'a> {
+        self.audit_path = Some(path.into());
+        self
+    }
+
+    pub fn with_history(mut self, history: History) -> Shell<'a> {
+        self.history = history;
+        self
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    // runs one line, the line goes into the history whether it worked or not.
+    // a history that cannot be written is only a warning, like the audit log
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> Result<Flow, ShellError> {
+        if let Err(e) = self.history.push(line) {
+            writeln!(out, "warning: not saved in the history: {e}")?;
+        }
+        let args = split_args(line)?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            [] => {}
+            ["quit" | "exit"] => return Ok(Flow::Quit),
+            ["help"] => {
+                for (name, args, what) in COMMANDS {
+                    writeln!(out, "{:<36} {what}", format!("{name} {args}"))?;
+                }
+            }
+            ["help", name] => match COMMANDS.iter().find(|(command, _, _)| command == name) {
+                Some((name, args, what)) => writeln!(out, "{name} {args}\n  {what}")?,
+                None => return Err(ShellError::UnknownCommand(name.to_string())),
+            },
+            ["history"] => {
+                for (i, line) in self.history.entries().iter().enumerate() {
+                    writeln!(out, "{:>4}  {line}", i + 1)?;
+                }
+            }
+            ["list"] => {
+                let users = file::load_users(&self.users_path)?;
+                let mut users: Vec<&User> = users.values().collect();
+                users.sort_by(|a, b| a.username.cmp(&b.username));
+                for user in &users {
+                    writeln!(
+                        out,
+                        "{:<20} {:<30} {}",
+                        user.username,
+                        user.email.as_str(),
+                        user.role
+                    )?;
+                }
+                writeln!(out, "{} users", users.len())?;
+            }
+            ["find", text] => {
+                let text = text.to_lowercase();
+                let users = file::load_users(&self.users_path)?;
+                let mut found: Vec<&User> = users
+                    .values()
+                    .filter(|user| {
+                        user.username.contains(&text) || user.email.as_str().contains(&text)
+                    })
+                    .collect();
+                found.sort_by(|a, b| a.username.cmp(&b.username));
+                for user in &found {
+                    writeln!(
+                        out,
+                        "{:<20} {:<30} {}",
+                        user.username,
+                        user.email.as_str(),
+                        user.role
+                    )?;
+                }
+                writeln!(out, "{} found", found.len())?;
+            }
+            ["add", username, email] => self.add(username, email, Role::User, out)?,
+            ["add", username, email, role] => {
+                let role = role.parse().map_err(ShellError::InvalidRole)?;
+                self.add(username, email, role, out)?
+            }
+            ["remove", username] => {
+                let username = username.to_lowercase();
+                file::modify_users(&self.users_path, |users| {
+                    if !users.contains_key(&username) {
+                        return Err(ShellError::NoSuchUser(username.clone()));
+                    }
+                    if is_last_admin(users, &username) {
+                        return Err(ShellError::LastAdmin(username.clone()));
+                    }
+                    users.remove(&username);
+                    Ok(())
+                })?;
+                writeln!(out, "removed {username}")?;
+                self.audit(
+                    Event::new(ACTOR, Action::UserDelete, &username, Outcome::Success),
+                    out,
+                )?;
+            }
+            ["passwd", username] => {
+                let username = username.to_lowercase();
+                // asked before taking the lock, nobody waits on someone typing
+                if !file::load_users(&self.users_path)?.contains_key(&username) {
+                    return Err(ShellError::NoSuchUser(username));
+                }
+                let password = self.new_password()?;
+                let policy = &self.policy;
+                let changed = file::modify_users(&self.users_path, |users| {
+                    let user = users
+                        .get_mut(&username)
+                        .ok_or_else(|| ShellError::NoSuchUser(username.clone()))?;
+                    user.change_password(&password, policy)
+                        .map_err(ShellError::WeakPassword)
+                });
+                let outcome = match changed {
+                    Ok(()) => Outcome::Success,
+                    Err(_) => Outcome::Failure,
+                };
+                self.audit(
+                    Event::new(ACTOR, Action::PasswordChange, &username, outcome),
+                    out,
+                )?;
+                changed?;
+                writeln!(out, "changed the password of {username}")?;
+            }
+            ["role", username, role] => {
+                let username = username.to_lowercase();
+                let role: Role = role.parse().map_err(ShellError::InvalidRole)?;
+                let old = file::modify_users(&self.users_path, |users| {
+                    if !users.contains_key(&username) {
+                        return Err(ShellError::NoSuchUser(username.clone()));
+                    }
+                    if role != Role::Admin && is_last_admin(users, &username) {
+                        return Err(ShellError::LastAdmin(username.clone()));
+                    }
+                    let user = users.get_mut(&username).expect("checked above");
+                    Ok(std::mem::replace(&mut user.role, role))
+                })?;
+                writeln!(out, "{username} is now {role} (was {old})")?;
+                self.audit(
+                    Event::new(ACTOR, Action::RoleChange, &username, Outcome::Success)
+                        .detail(format!("{old} -> {role}")),
+                    out,
+                )?;
+            }
+            [name, ..] => {
+                return Err(
+                    match COMMANDS.iter().find(|(command, _, _)| command == name) {
+                        Some(_) => usage(name),
+                        None => ShellError::UnknownCommand(name.to_string()),
+                    },
+                )
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn add(
+        &mut self,
+        username: &str,
+        email: &str,
+        role: Role,
+        out: &mut dyn Write,
+    ) -> Result<(), ShellError> {
+        let password = self.new_password()?;
+        let user = User::builder()
+            .username(username)
+            .email(email)
+            .password(password)
+            .role(role)
+            .policy(&self.policy)
+            .build()
+            .map_err(ShellError::InvalidUser)?;
+        let username = user.username.clone();
+        file::modify_users(&self.users_path, |users| {
+            if users.contains_key(&username) {
+                return Err(ShellError::UserExists(username.clone()));
+            }
+            users.insert(username.clone(), user);
+            Ok(())
+        })?;
+        writeln!(out, "added {username}")?;
+        self.audit(
+            Event::new(ACTOR, Action::UserCreate, &username, Outcome::Success),
+            out,
+        )
+    }
+
+    // asked twice, a typo would lock the user out
+    fn new_password(&mut self) -> Result<String, ShellError> {
+        let password = (self.ask)("password").ok_or(ShellError::Cancelled)?;
+        let again = (self.ask)("again").ok_or(ShellError::Cancelled)?;
+        if password != again {
+            return Err(ShellError::PasswordsDiffer);
+        }
+        Ok(password)
+    }
+
+    // the change is already saved, a log that cannot be written is only a warning
+    fn audit(&mut self, event: Event, out: &mut dyn Write) -> Result<(), ShellError> {
+        let Some(path) = &self.audit_path else {
+            return Ok(());
+        };
+        let log = match self.audit_log.take() {
+            Some(log) => Ok(log),
+            None => AuditLog::open(path),
+        };
+        match log.and_then(|mut log| log.record(event).map(|_| log)) {
+            Ok(log) => self.audit_log = Some(log),
+            // opened again next time, a log that got broken says so every time
+            Err(e) => writeln!(out, "warning: not recorded in {}: {e}", path.display())?,
+        }
+        Ok(())
+    }
+
+    // what the word being typed at the end of line could become: the byte offset where that
+    // word starts and every candidate for it. commands first, then usernames and roles
+    // where a command expects them.
+    pub fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| {
+            i + line[i..].chars().next().map_or(1, char::len_utf8)
+        });
+        let prefix = &line[start..];
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+        let command_names = || {
+            COMMANDS
+                .iter()
+                .map(|(name, _, _)| name.to_string())
+                .collect()
+        };
+        let words: Vec<String> = match before.as_slice() {
+            [] | ["help"] => command_names(),
+            ["remove" | "passwd" | "role" | "find"] => file::load_users(&self.users_path)
+                .map(|users| users.into_keys().collect())
+                .unwrap_or_default(),
+            ["role", _] | ["add", _, _] => vec!["admin".to_string(), "user".to_string()],
+            _ => Vec::new(),
+        };
+        let mut candidates: Vec<String> = words
+            .into_iter()
+            .filter(|word| word.starts_with(prefix))
+            .collect();
+        candidates.sort();
+        (start, candidates)
+    }
+}
+
+// the loop: prompt, read, run, print what went wrong, again.
+// read_line gets the shell for its history and completion, and returns None when the input
+// has ended.
+pub fn run(
+    shell: &mut Shell,
+    mut read_line: impl FnMut(&Shell, &str) -> io::Result<Option<String>>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    loop {
+        let Some(line) = read_line(shell, "users> ")? else {
+            writeln!(out)?;
+            return Ok(());
+        };
+        // the editor completes a tab as it is pressed. read without it (from a pipe, or a
+        // terminal in cooked mode) the tab arrives with the rest of the line after enter,
+        // what it would complete to is printed and the line itself is not run.
+        if let Some(tab) = line.find('\t') {
+            let (_, candidates) = shell.complete(&line[..tab]);
+            if candidates.is_empty() {
+                writeln!(out, "no completions")?;
+            } else {
+                writeln!(out, "{}", candidates.join("  "))?;
+            }
+            continue;
+        }
+        match shell.execute(&line, out) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Quit) => return Ok(()),
+            // the output itself is gone, nothing more can be shown
+            Err(ShellError::Io(e)) => return Err(e),
+            Err(e) => writeln!(out, "error: {e}")?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+    use users::audit::{self, Query};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn users_file(dir: &Path) -> PathBuf {
+        let path = dir.join("users.json");
+        file::save_users(&path, &users::get_default_users()).unwrap();
+        path
+    }
+
+    fn output(shell: &mut Shell, line: &str) -> Result<String, ShellError> {
+        let mut out = Vec::new();
+        shell.execute(line, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn splits_like_a_shell() {
+        assert_eq!(split_args("  add  bob  ").unwrap(), ["add", "bob"]);
+        assert_eq!(
+            split_args(r#"find "a b" 'c "d"' e\ f "" x"y"z"#).unwrap(),
+            ["find", "a b", "c \"d\"", "e f", "", "xyz"]
+        );
+        assert_eq!(
+            split_args(r#""say \"hi\" \n""#).unwrap(),
+            ["say \"hi\" \\n"]
+        );
+        assert_eq!(
+            split_args("find 'open"),
+            Err(ParseError::UnterminatedQuote('\''))
+        );
+        assert_eq!(split_args("find \\"), Err(ParseError::TrailingBackslash));
+        assert!(split_args("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn manages_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = users_file(dir.path());
+        let audit_path = dir.path().join("audit.log");
+        let mut shell = Shell::new(&path, |_| Some(PASSWORD.to_string())).with_audit(&audit_path);
+
+        assert!(output(&mut shell, "add Alice alice@example.com admin")
+            .unwrap()
+            .contains("added alice"));
+        assert!(matches!(
+            shell.execute("add alice alice@example.com", &mut io::sink()),
+            Err(ShellError::UserExists(_))
+        ));
+        let found = output(&mut shell, "find EXAMPLE").unwrap();
+        assert!(
+            found.contains("alice") && found.ends_with("1 found\n"),
+            "{found}"
+        );
+
+        output(&mut shell, "role alice user").unwrap();
+        output(&mut shell, "passwd alice").unwrap();
+        output(&mut shell, "remove user").unwrap();
+        let users = file::load_users(&path).unwrap();
+        assert_eq!(users["alice"].role, Role::User);
+        assert!(users["alice"].check_password(PASSWORD));
+        assert!(!users.contains_key("user"));
+        assert!(output(&mut shell, "list").unwrap().ends_with("2 users\n"));
+
+        // admin is the only admin left
+        assert!(matches!(
+            shell.execute("role admin user", &mut io::sink()),
+            Err(ShellError::LastAdmin(_))
+        ));
+        assert!(matches!(
+            shell.execute("remove admin", &mut io::sink()),
+            Err(ShellError::LastAdmin(_))
+        ));
+
+        let actions: Vec<Action> = audit::query(&audit_path, &Query::new().user("alice"))
+            .unwrap()
+            .iter()
+            .map(|entry| entry.action)
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                Action::UserCreate,
+                Action::RoleChange,
+                Action::PasswordChange
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_are_explained() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = users_file(dir.path());
+        let mut answers = vec!["short", "short", PASSWORD, "different"].into_iter();
+        let mut shell = Shell::new(&path, move |_| answers.next().map(str::to_string));
+
+        let error = |shell: &mut Shell, line| shell.execute(line, &mut io::sink()).unwrap_err();
+        assert!(matches!(
+            error(&mut shell, "add bob bob@example.com"),
+            ShellError::InvalidUser(_)
+        ));
+        assert!(matches!(
+            error(&mut shell, "passwd admin"),
+            ShellError::PasswordsDiffer
+        ));
+        // the answers have run out
+        assert!(matches!(
+            error(&mut shell, "passwd admin"),
+            ShellError::Cancelled
+        ));
+        assert_eq!(
+            error(&mut shell, "role").to_string(),
+            "usage: role <username> <admin|user>"
+        );
+        assert_eq!(
+            error(&mut shell, "role admin root").to_string(),
+            "'root' is not a role"
+        );
+        assert!(matches!(
+            error(&mut shell, "remove nobody"),
+            ShellError::NoSuchUser(_)
+        ));
+        assert!(matches!(
+            error(&mut shell, "frobnicate"),
+            ShellError::UnknownCommand(_)
+        ));
+        assert!(matches!(error(&mut shell, "find 'x"), ShellError::Parse(_)));
+        // every line is in the history (passwd admin once), the passwords are not
+        assert_eq!(shell.history().len(), 7);
+        assert!(!shell
+            .history()
+            .entries()
+            .iter()
+            .any(|line| line.contains("short")));
+        assert!(file::load_users(&path).unwrap().len() == 2);
+    }
+
+    #[test]
+    fn completes_commands_and_usernames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = users_file(dir.path());
+        let shell = Shell::new(&path, |_| None);
+        assert_eq!(shell.complete("pa"), (0, vec!["passwd".to_string()]));
+        assert_eq!(shell.complete("h").1, ["help", "history"]);
+        assert_eq!(
+            shell.complete("passwd "),
+            (7, vec!["admin".into(), "user".into()])
+        );
+        assert_eq!(shell.complete("role  ad"), (6, vec!["admin".to_string()]));
+        assert_eq!(shell.complete("role admin u").1, ["user"]);
+        assert!(shell.complete("list x").1.is_empty());
+    }
+
+    #[test]
+    fn the_session_survives_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = users_file(dir.path());
+        let history_path = dir.path().join("history");
+        let history = History::load(&history_path, History::DEFAULT_MAX).unwrap();
+        let mut shell = Shell::new(&path, |_| None).with_history(history);
+        let mut lines = vec!["nope", "remove ghost", "pass\t", "list", "quit", "list"].into_iter();
+        let mut out = Vec::new();
+        run(
+            &mut shell,
+            |_, _| Ok(lines.next().map(str::to_string)),
+            &mut out,
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("error: unknown command 'nope'"), "{out}");
+        assert!(out.contains("error: there is no user 'ghost'"), "{out}");
+        assert!(out.contains("passwd\n"), "{out}");
+        assert!(out.ends_with("2 users\n"), "{out}");
+        // the lines after quit are never read
+        assert_eq!(lines.next(), Some("list"));
+        assert_eq!(
+            fs::read_to_string(history_path).unwrap(),
+            "nope\nremove ghost\nlist\nquit\n"
+        );
+    }
+
+    #[test]
+    fn a_history_that_cannot_be_written_is_a_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = users_file(dir.path());
+        let history_path = dir.path().join("history");
+        let history = History::load(&history_path, History::DEFAULT_MAX).unwrap();
+        // a directory where the file goes, appending to it fails
+        fs::create_dir(&history_path).unwrap();
+        let mut shell = Shell::new(&path, |_| None).with_history(history);
+        let mut out = Vec::new();
+        assert!(matches!(
+            shell.execute("list", &mut out),
+            Ok(Flow::Continue)
+        ));
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("warning: not saved in the history"),
+            "{out}"
+        );
+        assert!(out.ends_with("2 users\n"), "{out}");
+    }
+}
@@ -1,51 +1,41 @@
 // enums indicate a choice they arent used to combining data logically
 // that is where strcutures come into play
 
-#[derive(Debug)]
-pub enum Role {
-    Admin,
-    User,
-}
-
-#[derive(Debug)]
-pub struct User {
-    // field accessible by objects is denoted by pub
-    pub username: String,
-    pub email: String,
-    password: String,
-    pub role: Role, // structures can contain enums
-}
+// User and Role used to be declared here, they now live in the shared users crate
+// so every lesson works on the same type. see users/src/lib.rs for how they are built:
+// - a struct with pub fields and a private password
+// - methods in an impl block, new is a constructor by convention
+// - get_users returns dummy data
+use users::{
+    audit::{Action, AuditLog, Event, Outcome},
+    get_users,
+    password_policy::PasswordPolicy,
+    session::{SessionConfig, SessionManager},
+    throttle::{LoginGuard, ThrottleConfig},
+    Role,
+};
 
-// methods are defined using the impl block
-impl User {
-    // a constructor new (just a convention nothing related to rust)
-    // pub makes sure the function is callable outside this module
-    pub fn new(username: &str, email: &str, password: &str, role: Role) -> User {
-        // Self refers to the type currently implementing for
-        // can also use User
-        Self {
-            username: username.to_lowercase(),
-            email: email.to_lowercase(),
-            password: password.to_string(),
-            role,
+fn main() {
+    let users = get_users();
+    // remembers who logged in, see users/src/session.rs
+    let sessions = SessionManager::new(SessionConfig::default());
+    // slows down password guessing, see users/src/throttle.rs
+    let guard = LoginGuard::new(ThrottleConfig::default());
+    // logins and logouts are written down, see users/src/audit.rs
+    let mut audit = AuditLog::open("audit.log")
+        .map_err(|e| println!("not auditing: {e}"))
+        .ok();
+    let mut record = |event: Event| {
+        if let Some(Err(e)) = audit.as_mut().map(|log| log.record(event)) {
+            println!("not recorded: {e}");
         }
-    }
+    };
 
-    fn check_password(&self, password: &str) -> bool {
-        self.password == password.to_string()
+    // User::new takes any password, one chosen by a person is checked against a policy first.
+    // every broken rule is listed, see users/src/password_policy.rs
+    if let Err(e) = PasswordPolicy::default().check("password", "admin", "admin@localhost") {
+        println!("{e}");
     }
-}
-
-// returns a static array of dummy data
-pub fn get_users() -> [User; 2] {
-    [
-        User::new("admin", "admin@localhost", "password", Role::Admin),
-        User::new("user", "user@localhost", "password", Role::User),
-    ]
-}
-
-fn main() {
-    let users = get_users();
 
     // iterators are used to access list, vectors etc.
     for user in users.iter() {
@@ -60,12 +50,49 @@ fn main() {
     if let Some(user) = users.iter().find(|user| user.username == "admin") {
         // user here is owned by the users array we only have a reference.
         println!("{:?}", user);
-        if user.check_password("password") {
+        // a wrong guess makes the next try wait
+        if let Err(e) = guard.attempt(&user.username, "terminal", || user.check_password("guess")) {
+            println!("{e}");
+        }
+        if let Err(e) = guard.attempt(&user.username, "terminal", || {
+            user.check_password("password")
+        }) {
+            println!("{e}");
+        }
+        // an admin can clear the failures instead of waiting
+        guard.unlock(&user.username);
+        if guard
+            .attempt(&user.username, "terminal", || {
+                user.check_password("password")
+            })
+            .is_ok()
+        {
             println!("Logged in");
+            record(Event::new(
+                &user.username,
+                Action::Login,
+                &user.username,
+                Outcome::Success,
+            ));
+            // the id is what a client would keep (a cookie) and send back every time
+            let session = sessions.create(&user.username);
+            println!("session {} expires at {:?}", session.id, session.expires_at);
+            if let Ok(session) = sessions.touch(&session.id) {
+                println!("{} is still logged in", session.username);
+            }
             match user.role {
                 Role::Admin => println!("Admin Role"),
                 Role::User => println!("User Role"),
             }
+            // logging out revokes the session, the id is worth nothing afterwards
+            if sessions.revoke(&session.id) {
+                record(Event::new(
+                    &user.username,
+                    Action::Logout,
+                    &user.username,
+                    Outcome::Success,
+                ));
+            }
         }
     }
 }
@@ -57,7 +57,7 @@ fn main() {
     let mut thread_handles = Vec::new();
     for i in 0..5 {
         let thread_handle = std::thread::spawn(move || {
-            return do_math(i);
+            do_math(i) // no semicolon, the value is returned from the closure
         });
         thread_handles.push(thread_handle);
     }
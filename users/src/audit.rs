@@ -0,0 +1,694 @@
+// An audit log: who did what to whom, when, and whether it worked.
+//
+// one json object per line, appended and never rewritten:
+//   {"seq":3,"at":1700000000000,"actor":"admin","action":"login","target":"admin",
+//    "outcome":"failure","detail":null,"prev":"9f2c…","hash":"41d0…"}
+// hash is the sha256 of the entry without it, and prev is the hash of the entry before
+// (64 zeros for the first). change a byte of any entry and its hash no longer matches,
+// fix the hash and the next entry's prev no longer matches, and so on to the end.
+// cutting entries off the end leaves a valid chain, so the last seq and hash are also kept
+// in <log>.head, written after every entry. someone who can rewrite both files can still
+// forge a whole new history, printing the head now and then (verify does) and keeping it
+// elsewhere catches that.
+//
+// a crash can leave two things behind, both are repaired when the log is next opened:
+// - a torn last line, the write of an entry stopped halfway. an entry is written in one go
+//   with its newline, so a last line without one never made it and is cut off.
+// - a head one entry behind, the entry was fsynced but the head not yet written. adding an
+//   entry at the end is possible without a crash too (the chain has no key), the head is
+//   there to catch entries cut off, so one more entry than the head says is accepted.
+// anything else (a broken line before the last, a head that is further off) is tampering.
+//
+// AuditLog stays open for as long as the program records, but takes the exclusive lock
+// only while it writes, so other programs can read and record in between. the chain is
+// read in full when it is opened and again only when the file has changed under it.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    file::{self, FileLock},
+    UsersError,
+};
+
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // a password check, failed ones have outcome failure
+    Login,
+    Logout,
+    UserCreate,
+    UserDelete,
+    PasswordChange,
+    RoleChange,
+    // the whole users file was written (import, migration, recovery)
+    FileRewrite,
+    Elevation,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the same text as in the file
+        let text = serde_json::to_value(self).expect("a unit variant serializes");
+        write!(f, "{}", text.as_str().unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub actor: String,
+    pub action: Action,
+    // the user (or file) acted on
+    pub target: String,
+    pub outcome: Outcome,
+    pub detail: Option<String>,
+}
+
+impl Event {
+    pub fn new(actor: &str, action: Action, target: &str, outcome: Outcome) -> Event {
+        Event {
+            actor: actor.to_string(),
+            action,
+            target: target.to_string(),
+            outcome,
+            detail: None,
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Event {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    // unix time in milliseconds
+    pub at: u64,
+    pub actor: String,
+    pub action: Action,
+    pub target: String,
+    pub outcome: Outcome,
+    pub detail: Option<String>,
+    pub prev: String,
+}
+
+impl Entry {
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.at)
+    }
+
+    fn digest(&self) -> String {
+        let json = serde_json::to_vec(self).expect("an entry serializes");
+        Sha256::digest(&json)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+// a line of the file, the entry and its hash
+#[derive(Serialize, Deserialize)]
+struct Line {
+    #[serde(flatten)]
+    entry: Entry,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Io { path: PathBuf, source: io::Error },
+    File(UsersError),
+    // line is 1 based, like an editor shows it
+    Malformed { line: usize, message: String },
+    Tampered { seq: u64, reason: &'static str },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            AuditError::File(e) => write!(f, "{e}"),
+            AuditError::Malformed { line, message } => {
+                write!(f, "audit log line {line} is not an entry: {message}")
+            }
+            AuditError::Tampered { seq, reason } => {
+                write!(f, "audit log was tampered with at entry {seq}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl From<UsersError> for AuditError {
+    fn from(e: UsersError) -> Self {
+        AuditError::File(e)
+    }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+// what verify found, head is what to write down elsewhere
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    pub entries: u64,
+    pub head: String,
+}
+
+// what read_chain found, besides the entries
+struct Chain {
+    entries: Vec<Entry>,
+    verified: Verified,
+    // the bytes up to the end of the last whole line, less than len when it is torn
+    valid_len: u64,
+    len: u64,
+    // the head is one entry behind, the crash came before it was written
+    head_behind: bool,
+}
+
+// reads every entry and checks the whole chain and the head
+fn read_chain(path: &Path) -> Result<Chain, AuditError> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(source) => {
+            return Err(AuditError::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+    let mut entries = Vec::new();
+    let mut prev = GENESIS.to_string();
+    // the hash before prev, what a head one entry behind holds
+    let mut before = GENESIS.to_string();
+    let mut valid_len = 0;
+    // a last line without its newline is left out, it is torn
+    while let Some(end) = contents[valid_len..].iter().position(|&byte| byte == b'\n') {
+        let index = entries.len();
+        let line = &contents[valid_len..valid_len + end];
+        valid_len += end + 1;
+        let Line { entry, hash } =
+            serde_json::from_slice(line).map_err(|e| AuditError::Malformed {
+                line: index + 1,
+                message: e.to_string(),
+            })?;
+        let seq = entry.seq;
+        if seq != index as u64 + 1 {
+            return Err(AuditError::Tampered {
+                seq,
+                reason: "entries are missing or out of order",
+            });
+        }
+        if entry.prev != prev {
+            return Err(AuditError::Tampered {
+                seq,
+                reason: "does not follow the entry before it",
+            });
+        }
+        if entry.digest() != hash {
+            return Err(AuditError::Tampered {
+                seq,
+                reason: "its contents do not match its hash",
+            });
+        }
+        before = std::mem::replace(&mut prev, hash);
+        entries.push(entry);
+    }
+
+    let last = entries.len() as u64;
+    let expected_head = format!("{last} {prev}");
+    let behind_head = format!("{} {before}", last.saturating_sub(1));
+    let mut head_behind = false;
+    let head_path = head_path(path);
+    match fs::read_to_string(&head_path) {
+        Ok(head) if head.trim() == expected_head => {}
+        Ok(head) if last > 0 && head.trim() == behind_head => head_behind = true,
+        Ok(_) => {
+            return Err(AuditError::Tampered {
+                seq: last,
+                reason: "the log does not end where its head says, entries were cut off",
+            })
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound && last <= 1 => head_behind = last == 1,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(AuditError::Tampered {
+                seq: last,
+                reason: "the head file is missing",
+            })
+        }
+        Err(source) => {
+            return Err(AuditError::Io {
+                path: head_path,
+                source,
+            })
+        }
+    }
+    Ok(Chain {
+        entries,
+        verified: Verified {
+            entries: last,
+            head: prev,
+        },
+        valid_len: valid_len as u64,
+        len: contents.len() as u64,
+        head_behind,
+    })
+}
+
+// the verifier: Ok with the number of entries and the last hash, or where it broke.
+// what a crash leaves behind is not an error, the next AuditLog::open repairs it
+pub fn verify(path: impl AsRef<Path>) -> Result<Verified, AuditError> {
+    let path = path.as_ref();
+    let _lock = file::lock_shared(path)?;
+    read_chain(path).map(|chain| chain.verified)
+}
+
+// all filters are optional, an empty query matches everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    user: Option<String>,
+    action: Option<Action>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    // entries where the user is the actor or the target
+    pub fn user(mut self, username: &str) -> Query {
+        self.user = Some(username.to_string());
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Query {
+        self.action = Some(action);
+        self
+    }
+
+    // since is inclusive, until is not
+    pub fn between(mut self, since: SystemTime, until: SystemTime) -> Query {
+        self.since = Some(since);
+        self.until = Some(until);
+        self
+    }
+
+    pub fn since(mut self, since: SystemTime) -> Query {
+        self.since = Some(since);
+        self
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let time = entry.time();
+        self.user
+            .as_ref()
+            .is_none_or(|user| entry.actor == *user || entry.target == *user)
+            && self.action.is_none_or(|action| entry.action == action)
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time < until)
+    }
+}
+
+// only answers from a log that verifies, a tampered log is an error not a result
+pub fn query(path: impl AsRef<Path>, query: &Query) -> Result<Vec<Entry>, AuditError> {
+    let path = path.as_ref();
+    let _lock = file::lock_shared(path)?;
+    let chain = read_chain(path)?;
+    Ok(chain
+        .entries
+        .into_iter()
+        .filter(|entry| query.matches(entry))
+        .collect())
+}
+
+#[derive(Debug)]
+pub struct AuditLog<C: Clock = SystemClock> {
+    path: PathBuf,
+    file: File,
+    clock: C,
+    seq: u64,
+    prev: String,
+    // how long the file was after our last write, anything else means someone else wrote
+    len: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<AuditLog, AuditError> {
+        AuditLog::with_clock(path, SystemClock)
+    }
+}
+
+impl<C: Clock> AuditLog<C> {
+    // refuses to add to a log that does not verify, the new entries would hide the damage
+    pub fn with_clock(path: impl AsRef<Path>, clock: C) -> Result<AuditLog<C>, AuditError> {
+        let path = path.as_ref().to_path_buf();
+        let lock = file::lock_exclusive(&path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|source| AuditError::Io {
+                path: path.clone(),
+                source,
+            })?;
+        let mut log = AuditLog {
+            path,
+            file,
+            clock,
+            seq: 0,
+            prev: GENESIS.to_string(),
+            len: 0,
+        };
+        log.reload(&lock)?;
+        Ok(log)
+    }
+
+    fn io_error(&self, source: io::Error) -> AuditError {
+        AuditError::Io {
+            path: self.path.clone(),
+            source,
+        }
+    }
+
+    // reads and checks the whole chain and repairs what a crash left, under the lock
+    fn reload(&mut self, _lock: &FileLock) -> Result<(), AuditError> {
+        let chain = read_chain(&self.path)?;
+        if chain.valid_len < chain.len {
+            self.file
+                .set_len(chain.valid_len)
+                .and_then(|()| self.file.sync_data())
+                .map_err(|e| self.io_error(e))?;
+        }
+        let Verified { entries, head } = chain.verified;
+        if chain.head_behind {
+            file::write_atomic(
+                &head_path(&self.path),
+                format!("{entries} {head}\n").as_bytes(),
+            )?;
+        }
+        self.seq = entries;
+        self.prev = head;
+        self.len = chain.valid_len;
+        Ok(())
+    }
+
+    // the entry is on disk (fsynced) before this returns
+    pub fn record(&mut self, event: Event) -> Result<Entry, AuditError> {
+        let lock = file::lock_exclusive(&self.path)?;
+        let len = self.file.metadata().map_err(|e| self.io_error(e))?.len();
+        if len != self.len {
+            self.reload(&lock)?;
+        }
+        let at = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let entry = Entry {
+            seq: self.seq + 1,
+            at,
+            actor: event.actor,
+            action: event.action,
+            target: event.target,
+            outcome: event.outcome,
+            detail: event.detail,
+            prev: self.prev.clone(),
+        };
+        let hash = entry.digest();
+        let line = Line {
+            entry: entry.clone(),
+            hash: hash.clone(),
+        };
+        let mut bytes = serde_json::to_vec(&line).expect("an entry serializes");
+        bytes.push(b'\n');
+        self.file
+            .write_all(&bytes)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| self.io_error(e))?;
+        self.len += bytes.len() as u64;
+        self.seq = entry.seq;
+        self.prev = hash.clone();
+        file::write_atomic(
+            &head_path(&self.path),
+            format!("{} {hash}\n", entry.seq).as_bytes(),
+        )?;
+        drop(lock);
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    fn write_log(path: &Path) -> Arc<ManualClock> {
+        let clock = Arc::new(ManualClock::at_unix(1_700_000_000));
+        let mut log = AuditLog::with_clock(path, clock.clone()).unwrap();
+        log.record(Event::new(
+            "admin",
+            Action::Login,
+            "admin",
+            Outcome::Success,
+        ))
+        .unwrap();
+        clock.advance(Duration::from_secs(60));
+        log.record(Event::new("bob", Action::Login, "bob", Outcome::Failure))
+            .unwrap();
+        clock.advance(Duration::from_secs(60));
+        log.record(
+            Event::new("admin", Action::RoleChange, "bob", Outcome::Success)
+                .detail("user -> admin"),
+        )
+        .unwrap();
+        clock
+    }
+
+    #[test]
+    fn entries_are_chained_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path);
+        let verified = verify(&path).unwrap();
+        assert_eq!(verified.entries, 3);
+
+        // reopening continues the chain
+        let mut log = AuditLog::open(&path).unwrap();
+        let entry = log
+            .record(Event::new(
+                "admin",
+                Action::Logout,
+                "admin",
+                Outcome::Success,
+            ))
+            .unwrap();
+        assert_eq!(entry.seq, 4);
+        assert_eq!(entry.prev, verified.head);
+        drop(log);
+        assert_eq!(verify(&path).unwrap().entries, 4);
+        // an empty log is fine too
+        assert_eq!(verify(dir.path().join("none.log")).unwrap().entries, 0);
+    }
+
+    #[test]
+    fn edits_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path);
+        let contents = fs::read_to_string(&path).unwrap();
+
+        // bob's failed login becomes a success
+        fs::write(&path, contents.replacen("\"failure\"", "\"success\"", 1)).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(AuditError::Tampered { seq: 2, .. })
+        ));
+
+        // the middle entry removed
+        let lines: Vec<&str> = contents.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(AuditError::Tampered { seq: 3, .. })
+        ));
+
+        // the last entry cut off, the chain is fine but the head is not
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(AuditError::Tampered { seq: 2, .. })
+        ));
+        // and nothing more can be added to it
+        assert!(AuditLog::open(&path).is_err());
+
+        fs::write(&path, "not json\n").unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(AuditError::Malformed { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn a_head_one_entry_behind_is_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path);
+        let head = fs::read_to_string(head_path(&path)).unwrap();
+        let mut log = AuditLog::open(&path).unwrap();
+        log.record(Event::new(
+            "admin",
+            Action::Logout,
+            "admin",
+            Outcome::Success,
+        ))
+        .unwrap();
+        drop(log);
+        // the crash came after the entry was fsynced and before the head was written
+        fs::write(head_path(&path), &head).unwrap();
+        assert_eq!(verify(&path).unwrap().entries, 4);
+
+        let mut log = AuditLog::open(&path).unwrap();
+        let verified = verify(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(head_path(&path)).unwrap(),
+            format!("4 {}\n", verified.head)
+        );
+        assert_eq!(
+            log.record(Event::new("bob", Action::Login, "bob", Outcome::Success))
+                .unwrap()
+                .seq,
+            5
+        );
+
+        // the very first entry, there was no head yet
+        let first = dir.path().join("first.log");
+        AuditLog::open(&first)
+            .unwrap()
+            .record(Event::new(
+                "admin",
+                Action::Login,
+                "admin",
+                Outcome::Success,
+            ))
+            .unwrap();
+        fs::remove_file(head_path(&first)).unwrap();
+        assert_eq!(AuditLog::open(&first).unwrap().seq, 1);
+        assert_eq!(verify(&first).unwrap().entries, 1);
+
+        // two behind is entries cut off
+        fs::write(head_path(&path), &head).unwrap();
+        assert!(matches!(
+            AuditLog::open(&path),
+            Err(AuditError::Tampered { seq: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn a_torn_last_line_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path);
+        let contents = fs::read(&path).unwrap();
+        let mut torn = contents.clone();
+        torn.extend_from_slice(br#"{"seq":4,"at":17000"#);
+        fs::write(&path, &torn).unwrap();
+        assert_eq!(verify(&path).unwrap().entries, 3);
+
+        let mut log = AuditLog::open(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        log.record(Event::new(
+            "admin",
+            Action::Logout,
+            "admin",
+            Outcome::Success,
+        ))
+        .unwrap();
+        assert_eq!(verify(&path).unwrap().entries, 4);
+
+        // a whole line that is broken is not a torn one
+        let mut broken = contents.clone();
+        broken.extend_from_slice(b"{\"seq\":4\n");
+        fs::write(&path, &broken).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(AuditError::Malformed { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn an_open_log_sees_what_others_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut first = AuditLog::open(&path).unwrap();
+        let mut second = AuditLog::open(&path).unwrap();
+        let login = || Event::new("admin", Action::Login, "admin", Outcome::Success);
+        first.record(login()).unwrap();
+        second.record(login()).unwrap();
+        assert_eq!(first.record(login()).unwrap().seq, 3);
+        // first reread the file before its second entry instead of forking the chain
+        assert_eq!(verify(&path).unwrap().entries, 3);
+    }
+
+    #[test]
+    fn query_filters_by_user_action_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path);
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let seqs = |q: Query| -> Vec<u64> {
+            query(&path, &q)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.seq)
+                .collect()
+        };
+        assert_eq!(seqs(Query::new()), [1, 2, 3]);
+        // as actor or as target
+        assert_eq!(seqs(Query::new().user("bob")), [2, 3]);
+        assert_eq!(seqs(Query::new().action(Action::Login)), [1, 2]);
+        assert_eq!(
+            seqs(Query::new().between(start, start + Duration::from_secs(60))),
+            [1]
+        );
+        assert_eq!(
+            seqs(
+                Query::new()
+                    .user("admin")
+                    .since(start + Duration::from_secs(60))
+            ),
+            [3]
+        );
+        let entries = query(&path, &Query::new().action(Action::RoleChange)).unwrap();
+        assert_eq!(entries[0].detail.as_deref(), Some("user -> admin"));
+        assert_eq!(entries[0].time(), start + Duration::from_secs(120));
+    }
+}
@@ -0,0 +1,323 @@
+// Building a User out of pieces that came from outside.
+//
+// User::new("admin", "password", "admin@localhost", ...) compiles fine and is wrong, four
+// strings in a row are easy to swap. the builder names every field and checks all of them
+// in build(), which returns every problem at once instead of stopping at the first:
+//
+//   User::builder()
+//       .username("alice")
+//       .email("alice@example.com")
+//       .password("correct horse battery staple")
+//       .role_name("user")
+//       .build()
+//
+// prompts feed it what was typed, deserialization feeds it what was read (with
+// password_hash, the file never holds the plain password), so neither can make a bad user.
+
+use std::fmt;
+
+use crate::{
+    email::EmailAddress, password, password_policy::PasswordPolicy, secret::Secret, Role, User,
+    ValidationError,
+};
+
+pub const MAX_USERNAME_LEN: usize = 32;
+
+// shared with User::validate
+pub(crate) fn check_username(username: &str) -> Result<(), ValidationError> {
+    if username.is_empty() {
+        return Err(ValidationError::EmptyUsername);
+    }
+    let len = username.chars().count();
+    if len > MAX_USERNAME_LEN {
+        return Err(ValidationError::UsernameTooLong {
+            max: MAX_USERNAME_LEN,
+            actual: len,
+        });
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(ValidationError::InvalidUsername(username.to_string()));
+    }
+    Ok(())
+}
+
+// every field that was wrong, never empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildError {
+    pub errors: Vec<ValidationError>,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Debug)]
+enum Password {
+    // typed by someone, checked against the policy and hashed
+    Plain(Secret<String>),
+    // read from storage, already hashed (or an old plaintext one, see upgrade_password)
+    Stored(Secret<String>),
+}
+
+#[derive(Debug, Default)]
+pub struct UserBuilder<'a> {
+    username: Option<String>,
+    email: Option<String>,
+    password: Option<Password>,
+    role: Option<String>,
+    policy: Option<&'a PasswordPolicy>,
+}
+
+impl User {
+    pub fn builder<'a>() -> UserBuilder<'a> {
+        UserBuilder::default()
+    }
+}
+
+impl<'a> UserBuilder<'a> {
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn email(mut self, email: impl Into<String>) -> Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(Password::Plain(Secret::new(password.into())));
+        self
+    }
+
+    // for users loaded from storage, the policy cannot look at a hash
+    pub fn password_hash(mut self, hash: impl Into<String>) -> Self {
+        self.password = Some(Password::Stored(Secret::new(hash.into())));
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = Some(role.to_string());
+        self
+    }
+
+    // the role as typed, "admin" or "user"
+    pub fn role_name(mut self, role: impl Into<String>) -> Self {
+        self.role = Some(role.into());
+        self
+    }
+
+    // PasswordPolicy::default() is used when none is given
+    pub fn policy(mut self, policy: &'a PasswordPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn build(self) -> Result<User, BuildError> {
+        let mut errors = Vec::new();
+
+        let username = match &self.username {
+            Some(username) => {
+                let username = username.trim().to_lowercase();
+                match check_username(&username) {
+                    Ok(()) => Some(username),
+                    Err(e) => {
+                        errors.push(e);
+                        None
+                    }
+                }
+            }
+            None => {
+                errors.push(ValidationError::Missing("username"));
+                None
+            }
+        };
+
+        let email = match &self.email {
+            Some(email) => match EmailAddress::parse(email) {
+                Ok(email) => Some(email),
+                Err(reason) => {
+                    errors.push(ValidationError::InvalidEmail {
+                        email: email.clone(),
+                        reason,
+                    });
+                    None
+                }
+            },
+            None => {
+                errors.push(ValidationError::Missing("email"));
+                None
+            }
+        };
+
+        // a user unless said otherwise
+        let role = match self.role.as_deref().map(str::parse::<Role>) {
+            Some(Ok(role)) => Some(role),
+            Some(Err(e)) => {
+                errors.push(e);
+                None
+            }
+            None => Some(Role::User),
+        };
+
+        let default_policy;
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => {
+                default_policy = PasswordPolicy::default();
+                &default_policy
+            }
+        };
+        match &self.password {
+            Some(Password::Plain(password) | Password::Stored(password))
+                if password.expose().is_empty() =>
+            {
+                errors.push(ValidationError::EmptyPassword)
+            }
+            Some(Password::Plain(password)) => {
+                // the raw text, the username or email may be the very thing that is wrong
+                let username = self.username.as_deref().unwrap_or_default();
+                let email = self.email.as_deref().unwrap_or_default();
+                if let Err(e) = policy.check(password.expose(), username, email) {
+                    errors.push(ValidationError::WeakPassword(e));
+                }
+            }
+            Some(Password::Stored(_)) => {}
+            None => errors.push(ValidationError::Missing("password")),
+        }
+
+        match (username, email, self.password, role) {
+            (Some(username), Some(email), Some(password), Some(role)) if errors.is_empty() => {
+                let password = match password {
+                    // hashing is slow, it is only done once everything else is fine
+                    Password::Plain(password) => password::hash_password(password.expose()).into(),
+                    Password::Stored(hash) => hash,
+                };
+                Ok(User {
+                    username,
+                    email,
+                    password,
+                    role,
+                })
+            }
+            _ => Err(BuildError { errors }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_a_user_from_named_fields() {
+        let user = User::builder()
+            .username("Alice")
+            .email("Alice@Example.com")
+            .password("correct horse battery staple")
+            .role_name("admin")
+            .build()
+            .unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.email, "alice@example.com");
+        assert_eq!(user.role, Role::Admin);
+        assert!(user.check_password("correct horse battery staple"));
+        assert!(!user.needs_password_upgrade());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let error = User::builder()
+            .username("no spaces allowed")
+            .email("alice")
+            .password("alice")
+            .role_name("root")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.errors.len(), 4, "{error}");
+        assert_eq!(
+            error.errors[0],
+            ValidationError::InvalidUsername("no spaces allowed".to_string())
+        );
+        assert!(matches!(
+            error.errors[1],
+            ValidationError::InvalidEmail { .. }
+        ));
+        assert_eq!(
+            error.errors[2],
+            ValidationError::InvalidRole("root".to_string())
+        );
+        assert!(matches!(
+            &error.errors[3],
+            ValidationError::WeakPassword(e) if e.violations.len() == 2
+        ));
+
+        let error = User::builder().build().unwrap_err();
+        assert_eq!(
+            error.errors,
+            [
+                ValidationError::Missing("username"),
+                ValidationError::Missing("email"),
+                ValidationError::Missing("password"),
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "username is missing; email is missing; password is missing"
+        );
+    }
+
+    #[test]
+    fn usernames_have_a_maximum_length() {
+        let error = User::builder()
+            .username("a".repeat(33))
+            .email("a@localhost")
+            .password_hash("$pbkdf2-sha256$...")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            error.errors,
+            [ValidationError::UsernameTooLong {
+                max: 32,
+                actual: 33
+            }]
+        );
+    }
+
+    #[test]
+    fn stored_passwords_skip_the_policy() {
+        let policy = PasswordPolicy {
+            min_length: 100,
+            ..PasswordPolicy::default()
+        };
+        let user = User::builder()
+            .username("admin")
+            .email("admin@localhost")
+            .password_hash("admin")
+            .role(Role::Admin)
+            .policy(&policy)
+            .build()
+            .unwrap();
+        assert!(user.needs_password_upgrade());
+        assert!(User::builder()
+            .username("admin")
+            .email("admin@localhost")
+            .password("not a hundred characters")
+            .policy(&policy)
+            .build()
+            .is_err());
+    }
+}
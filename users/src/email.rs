@@ -0,0 +1,653 @@
+// Email addresses.
+//
+// an email used to be any String with an @ in it (and "user" without one slipped through).
+// EmailAddress can only be made by parsing, so a User always holds a real address.
+// the parser covers the addr-spec of RFC 5322, the part of an address between < and >:
+//   local-part@domain
+//   local-part: dot-atom (first.last+tag) or a quoted string ("john doe")
+//   domain: dot separated labels (example.com), or an ip in brackets ([127.0.0.1])
+// comments, folding white space and display names are not accepted, nobody types those.
+//
+// domains with non ascii letters (münchen.de) are stored in their ascii form, IDNA punycode
+// (xn--mnchen-3ya.de), which is what DNS and mail servers understand. domain_unicode turns
+// it back for display.
+//
+// canonicalization: many providers deliver name+anything@ to name@, and gmail ignores dots,
+// so first.last+shop@gmail.com and firstlast@googlemail.com are the same inbox. the
+// canonical form is only used to find such duplicates, the address is kept as it was typed.
+
+use std::{collections::BTreeMap, fmt, net::Ipv4Addr, net::Ipv6Addr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::User;
+
+const MAX_LOCAL_LEN: usize = 64;
+// what fits in the path of an SMTP command (RFC 5321 4.5.3.1.3)
+const MAX_ADDRESS_LEN: usize = 254;
+const MAX_DOMAIN_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmailError {
+    Empty,
+    MissingAt,
+    EmptyLocalPart,
+    EmptyDomain,
+    LocalPartTooLong(usize),
+    TooLong(usize),
+    InvalidLocalPart(&'static str),
+    InvalidDomain { label: String, reason: &'static str },
+    InvalidDomainLiteral(String),
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Empty => write!(f, "the email address is empty"),
+            EmailError::MissingAt => write!(f, "an email address needs an @"),
+            EmailError::EmptyLocalPart => write!(f, "nothing before the @"),
+            EmailError::EmptyDomain => write!(f, "nothing after the @"),
+            EmailError::LocalPartTooLong(len) => write!(
+                f,
+                "the part before the @ is {len} characters, at most {MAX_LOCAL_LEN} are allowed"
+            ),
+            EmailError::TooLong(len) => write!(
+                f,
+                "the address is {len} characters, at most {MAX_ADDRESS_LEN} are allowed"
+            ),
+            EmailError::InvalidLocalPart(reason) => {
+                write!(f, "the part before the @ {reason}")
+            }
+            EmailError::InvalidDomain { label, reason } => {
+                write!(f, "'{label}' in the domain {reason}")
+            }
+            EmailError::InvalidDomainLiteral(literal) => {
+                write!(f, "[{literal}] is not an ip address")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmailError {}
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EmailAddress {
+    // local@domain with the domain in its ascii form
+    address: String,
+    // where the @ between the two is, the local part can contain @ when quoted
+    at: usize,
+}
+
+impl EmailAddress {
+    // like usernames, addresses are treated as case insensitive and stored lowercased.
+    // RFC 5321 lets a server tell Bob@ from bob@ but none of the big ones do.
+    pub fn parse(text: &str) -> Result<EmailAddress, EmailError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(EmailError::Empty);
+        }
+        // before anything else, encoding a long domain to punycode takes quadratic time.
+        // the ascii form is never shorter than the characters typed, so nothing valid is lost
+        let len = text.chars().count();
+        if len > MAX_ADDRESS_LEN {
+            return Err(EmailError::TooLong(len));
+        }
+        let (local, domain) = text.rsplit_once('@').ok_or(EmailError::MissingAt)?;
+        if local.is_empty() {
+            return Err(EmailError::EmptyLocalPart);
+        }
+        if domain.is_empty() {
+            return Err(EmailError::EmptyDomain);
+        }
+        let local = parse_local_part(local)?;
+        let domain = parse_domain(domain)?;
+        let address = format!("{local}@{domain}");
+        if address.len() > MAX_ADDRESS_LEN {
+            return Err(EmailError::TooLong(address.len()));
+        }
+        Ok(EmailAddress {
+            at: local.len(),
+            address,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.address
+    }
+
+    pub fn local_part(&self) -> &str {
+        &self.address[..self.at]
+    }
+
+    // ascii, punycode for international domains
+    pub fn domain(&self) -> &str {
+        &self.address[self.at + 1..]
+    }
+
+    // the domain as people read it, xn--mnchen-3ya.de is münchen.de
+    pub fn domain_unicode(&self) -> String {
+        self.domain()
+            .split('.')
+            .map(|label| match label.strip_prefix("xn--") {
+                // the label was checked when parsing
+                Some(encoded) => punycode::decode(encoded).unwrap_or_else(|| label.to_string()),
+                None => label.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    // the form two addresses of the same inbox have in common
+    pub fn canonical(&self, rules: Canonicalization) -> String {
+        let mut local = self.local_part().to_string();
+        let mut domain = self.domain().to_string();
+        // quoted local parts are left alone, "a+b" is just a name
+        if !local.starts_with('"') {
+            if rules.strip_plus_tags {
+                if let Some(index) = local.find('+') {
+                    local.truncate(index);
+                }
+            }
+            if rules.provider_rules && matches!(domain.as_str(), "gmail.com" | "googlemail.com") {
+                local.retain(|c| c != '.');
+                domain = "gmail.com".to_string();
+            }
+        }
+        format!("{local}@{domain}")
+    }
+}
+
+impl fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)
+    }
+}
+
+// prints like the String it replaced
+impl fmt::Debug for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.address)
+    }
+}
+
+impl FromStr for EmailAddress {
+    type Err = EmailError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EmailAddress::parse(s)
+    }
+}
+
+// serde goes through these, a file with a broken address fails to load
+impl TryFrom<String> for EmailAddress {
+    type Error = EmailError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        EmailAddress::parse(&value)
+    }
+}
+
+impl From<EmailAddress> for String {
+    fn from(email: EmailAddress) -> Self {
+        email.address
+    }
+}
+
+impl PartialEq<str> for EmailAddress {
+    fn eq(&self, other: &str) -> bool {
+        self.address == other
+    }
+}
+
+impl PartialEq<&str> for EmailAddress {
+    fn eq(&self, other: &&str) -> bool {
+        self.address == *other
+    }
+}
+
+// atext of RFC 5322 3.2.3
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+fn parse_local_part(local: &str) -> Result<String, EmailError> {
+    if local.len() > MAX_LOCAL_LEN {
+        return Err(EmailError::LocalPartTooLong(local.len()));
+    }
+    if let Some(quoted) = local.strip_prefix('"') {
+        let inner = quoted
+            .strip_suffix('"')
+            .ok_or(EmailError::InvalidLocalPart("has an unterminated quote"))?;
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                // quoted-pair, \" and \\ are how a quote and a backslash get in
+                '\\' => match chars.next() {
+                    Some(' '..='~') => {}
+                    _ => return Err(EmailError::InvalidLocalPart("ends in a lone backslash")),
+                },
+                '"' => return Err(EmailError::InvalidLocalPart("has an unescaped quote")),
+                ' '..='~' => {}
+                _ => {
+                    return Err(EmailError::InvalidLocalPart(
+                        "may only contain printable ascii",
+                    ))
+                }
+            }
+        }
+        return Ok(local.to_ascii_lowercase());
+    }
+    for atom in local.split('.') {
+        if atom.is_empty() {
+            return Err(EmailError::InvalidLocalPart(
+                "cannot start or end with a dot or have two in a row",
+            ));
+        }
+        if !atom.chars().all(is_atext) {
+            return Err(EmailError::InvalidLocalPart(
+                "may only contain letters, digits and !#$%&'*+-/=?^_`{|}~ (or be quoted)",
+            ));
+        }
+    }
+    Ok(local.to_ascii_lowercase())
+}
+
+fn parse_domain(domain: &str) -> Result<String, EmailError> {
+    if let Some(literal) = domain.strip_prefix('[') {
+        let literal = literal
+            .strip_suffix(']')
+            .ok_or_else(|| EmailError::InvalidDomainLiteral(literal.to_string()))?;
+        let valid = match literal.strip_prefix("IPv6:") {
+            Some(ip) => ip.parse::<Ipv6Addr>().is_ok(),
+            None => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+        return if valid {
+            Ok(format!("[{literal}]"))
+        } else {
+            Err(EmailError::InvalidDomainLiteral(literal.to_string()))
+        };
+    }
+    let labels = domain
+        .split('.')
+        .map(domain_label)
+        .collect::<Result<Vec<_>, _>>()?;
+    let domain = labels.join(".");
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err(EmailError::InvalidDomain {
+            label: domain,
+            reason: "is longer than 253 characters",
+        });
+    }
+    Ok(domain)
+}
+
+// one label in its ascii form
+fn domain_label(label: &str) -> Result<String, EmailError> {
+    let invalid = |reason| EmailError::InvalidDomain {
+        label: label.to_string(),
+        reason,
+    };
+    if label.is_empty() {
+        return Err(invalid(
+            "is empty, the domain has two dots in a row or one at an end",
+        ));
+    }
+    let lowered = label.to_lowercase();
+    let ascii = if lowered.is_ascii() {
+        // an already encoded label has to be valid punycode
+        if let Some(encoded) = lowered.strip_prefix("xn--") {
+            punycode::decode(encoded).ok_or_else(|| invalid("is not valid punycode"))?;
+        }
+        lowered
+    } else {
+        if !lowered.chars().all(|c| c.is_alphanumeric() || c == '-') {
+            return Err(invalid("may only contain letters, digits and '-'"));
+        }
+        let encoded = punycode::encode(&lowered).ok_or_else(|| invalid("is too long"))?;
+        format!("xn--{encoded}")
+    };
+    if ascii.len() > MAX_LABEL_LEN {
+        return Err(invalid("is longer than 63 characters"));
+    }
+    if !ascii.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(invalid("may only contain letters, digits and '-'"));
+    }
+    if ascii.starts_with('-') || ascii.ends_with('-') {
+        return Err(invalid("cannot start or end with '-'"));
+    }
+    Ok(ascii)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canonicalization {
+    // name+tag@ is name@
+    pub strip_plus_tags: bool,
+    // what specific providers do, gmail ignores dots and googlemail.com is gmail.com
+    pub provider_rules: bool,
+}
+
+impl Default for Canonicalization {
+    fn default() -> Self {
+        Canonicalization {
+            strip_plus_tags: true,
+            provider_rules: true,
+        }
+    }
+}
+
+// users whose addresses lead to the same inbox, grouped by the canonical address
+pub fn duplicates<'a>(
+    users: impl IntoIterator<Item = &'a User>,
+    rules: Canonicalization,
+) -> BTreeMap<String, Vec<&'a User>> {
+    let mut groups: BTreeMap<String, Vec<&User>> = BTreeMap::new();
+    for user in users {
+        groups
+            .entry(user.email.canonical(rules))
+            .or_default()
+            .push(user);
+    }
+    groups.retain(|_, users| users.len() > 1);
+    groups
+}
+
+// Punycode (RFC 3492), unicode written with the letters, digits and '-' DNS allows.
+// the ascii characters are copied as they are, then every other character is encoded as
+// a number saying which character it is and where it goes.
+mod punycode {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+    const SKEW: u32 = 38;
+    const DAMP: u32 = 700;
+    const INITIAL_BIAS: u32 = 72;
+    const INITIAL_N: u32 = 128;
+
+    fn adapt(mut delta: u32, points: u32, first: bool) -> u32 {
+        delta /= if first { DAMP } else { 2 };
+        delta += delta / points;
+        let mut k = 0;
+        while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+            delta /= BASE - T_MIN;
+            k += BASE;
+        }
+        k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+    }
+
+    fn threshold(k: u32, bias: u32) -> u32 {
+        if k <= bias {
+            T_MIN
+        } else if k >= bias + T_MAX {
+            T_MAX
+        } else {
+            k - bias
+        }
+    }
+
+    fn digit(d: u32) -> char {
+        match d {
+            0..=25 => (b'a' + d as u8) as char,
+            _ => (b'0' + (d - 26) as u8) as char,
+        }
+    }
+
+    fn value(c: char) -> Option<u32> {
+        match c {
+            'a'..='z' => Some(c as u32 - 'a' as u32),
+            'A'..='Z' => Some(c as u32 - 'A' as u32),
+            '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+            _ => None,
+        }
+    }
+
+    // None when the numbers overflow, only for absurdly long input
+    pub fn encode(input: &str) -> Option<String> {
+        let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+        let mut output: String = input.chars().filter(char::is_ascii).collect();
+        let basic = output.len() as u32;
+        let mut handled = basic;
+        if basic > 0 {
+            output.push('-');
+        }
+        let (mut n, mut delta, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+        while (handled as usize) < chars.len() {
+            // the smallest character not encoded yet
+            let m = *chars.iter().filter(|&&c| c >= n).min()?;
+            delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+            n = m;
+            for &c in &chars {
+                if c < n {
+                    delta = delta.checked_add(1)?;
+                }
+                if c == n {
+                    let mut q = delta;
+                    let mut k = BASE;
+                    loop {
+                        let t = threshold(k, bias);
+                        if q < t {
+                            break;
+                        }
+                        output.push(digit(t + (q - t) % (BASE - t)));
+                        q = (q - t) / (BASE - t);
+                        k += BASE;
+                    }
+                    output.push(digit(q));
+                    bias = adapt(delta, handled + 1, handled == basic);
+                    delta = 0;
+                    handled += 1;
+                }
+            }
+            delta += 1;
+            n += 1;
+        }
+        Some(output)
+    }
+
+    pub fn decode(input: &str) -> Option<String> {
+        let (basic, encoded) = match input.rfind('-') {
+            Some(index) => (&input[..index], &input[index + 1..]),
+            None => ("", input),
+        };
+        if !basic.is_ascii() {
+            return None;
+        }
+        let mut output: Vec<char> = basic.chars().collect();
+        let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+        let mut digits = encoded.chars().peekable();
+        while digits.peek().is_some() {
+            let old_i = i;
+            let mut weight = 1u32;
+            let mut k = BASE;
+            loop {
+                let d = value(digits.next()?)?;
+                i = i.checked_add(d.checked_mul(weight)?)?;
+                let t = threshold(k, bias);
+                if d < t {
+                    break;
+                }
+                weight = weight.checked_mul(BASE - t)?;
+                k += BASE;
+            }
+            let len = output.len() as u32 + 1;
+            bias = adapt(i - old_i, len, old_i == 0);
+            n = n.checked_add(i / len)?;
+            i %= len;
+            output.insert(i as usize, char::from_u32(n)?);
+            i += 1;
+        }
+        Some(output.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Role;
+
+    #[test]
+    fn parses_the_addr_spec() {
+        let email = EmailAddress::parse(" First.Last+Shop@Example.COM ").unwrap();
+        assert_eq!(email, "first.last+shop@example.com");
+        assert_eq!(email.local_part(), "first.last+shop");
+        assert_eq!(email.domain(), "example.com");
+
+        for valid in [
+            "admin@localhost",
+            "o'brien@example.ie",
+            "!#$%&'*+-/=?^_`{|}~@example.com",
+            r#""john doe"@example.com"#,
+            r#""a@b\"c"@example.com"#,
+            "root@[127.0.0.1]",
+            "root@[IPv6:::1]",
+        ] {
+            assert!(EmailAddress::parse(valid).is_ok(), "{valid}");
+        }
+        // the quoted @ is part of the local part
+        let quoted = EmailAddress::parse(r#""a@b"@example.com"#).unwrap();
+        assert_eq!(quoted.local_part(), r#""a@b""#);
+    }
+
+    #[test]
+    fn rejects_what_is_not_an_address() {
+        let cases = [
+            ("user", EmailError::MissingAt),
+            ("", EmailError::Empty),
+            ("@localhost", EmailError::EmptyLocalPart),
+            ("user@", EmailError::EmptyDomain),
+            (
+                ".user@localhost",
+                EmailError::InvalidLocalPart("cannot start or end with a dot or have two in a row"),
+            ),
+            (
+                "john doe@example.com",
+                EmailError::InvalidLocalPart(
+                    "may only contain letters, digits and !#$%&'*+-/=?^_`{|}~ (or be quoted)",
+                ),
+            ),
+            (
+                r#""unterminated@example.com"#,
+                EmailError::InvalidLocalPart("has an unterminated quote"),
+            ),
+            (
+                "root@[999.0.0.1]",
+                EmailError::InvalidDomainLiteral("999.0.0.1".to_string()),
+            ),
+        ];
+        for (text, error) in cases {
+            assert_eq!(EmailAddress::parse(text), Err(error), "{text}");
+        }
+        for text in [
+            "user@example..com",
+            "user@-example.com",
+            "user@exam_ple.com",
+            "user@xn--zz.com",
+        ] {
+            assert!(
+                matches!(
+                    EmailAddress::parse(text),
+                    Err(EmailError::InvalidDomain { .. })
+                ),
+                "{text}"
+            );
+        }
+        let long = format!("{}@example.com", "a".repeat(65));
+        assert_eq!(
+            EmailAddress::parse(&long),
+            Err(EmailError::LocalPartTooLong(65))
+        );
+        // turned away before the domain is encoded, punycode of this would take a while
+        let huge = format!("user@{}.com", "ü".repeat(100_000));
+        assert_eq!(
+            EmailAddress::parse(&huge),
+            Err(EmailError::TooLong(100_009))
+        );
+    }
+
+    // samples from RFC 3492 7.1 and the usual IDNA examples
+    #[test]
+    fn international_domains_become_punycode() {
+        assert_eq!(punycode::encode("münchen").unwrap(), "mnchen-3ya");
+        assert_eq!(punycode::encode("bücher").unwrap(), "bcher-kva");
+        assert_eq!(
+            punycode::encode("他们为什么不说中文").unwrap(),
+            "ihqwcrb4cv8a8dqg056pqjye"
+        );
+        assert_eq!(
+            punycode::decode("egbpdaj6bu4bxfgehfvwxn").unwrap(),
+            "ليهمابتكلموشعربي؟"
+        );
+
+        let email = EmailAddress::parse("hans@München.de").unwrap();
+        assert_eq!(email, "hans@xn--mnchen-3ya.de");
+        assert_eq!(email.domain_unicode(), "münchen.de");
+        let email = EmailAddress::parse("info@例え.テスト").unwrap();
+        assert_eq!(email.domain(), "xn--r8jz45g.xn--zckzah");
+        // the ascii form parses to the same address
+        assert_eq!(
+            EmailAddress::parse("hans@xn--mnchen-3ya.de").unwrap(),
+            EmailAddress::parse("hans@münchen.de").unwrap()
+        );
+    }
+
+    #[test]
+    fn canonical_form_finds_the_same_inbox() {
+        let rules = Canonicalization::default();
+        let canonical = |text: &str| EmailAddress::parse(text).unwrap().canonical(rules);
+        assert_eq!(
+            canonical("First.Last+shop@gmail.com"),
+            "firstlast@gmail.com"
+        );
+        assert_eq!(canonical("firstlast@googlemail.com"), "firstlast@gmail.com");
+        // dots only matter to gmail
+        assert_eq!(
+            canonical("first.last+x@example.com"),
+            "first.last@example.com"
+        );
+        assert_eq!(canonical(r#""a+b"@example.com"#), r#""a+b"@example.com"#);
+        let off = Canonicalization {
+            strip_plus_tags: false,
+            provider_rules: false,
+        };
+        assert_eq!(
+            EmailAddress::parse("a.b+c@gmail.com")
+                .unwrap()
+                .canonical(off),
+            "a.b+c@gmail.com"
+        );
+
+        let users = [
+            User::new("alice", "alice@gmail.com", "password", Role::User),
+            User::new("bob", "bob@example.com", "password", Role::User),
+            User::new(
+                "alice2",
+                "a.l.i.c.e+2@googlemail.com",
+                "password",
+                Role::User,
+            ),
+        ];
+        let found = duplicates(&users, rules);
+        assert_eq!(found.len(), 1);
+        let names: Vec<&str> = found["alice@gmail.com"]
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect();
+        assert_eq!(names, ["alice", "alice2"]);
+    }
+
+    #[test]
+    fn serializes_as_a_plain_string() {
+        let email = EmailAddress::parse("admin@localhost").unwrap();
+        assert_eq!(
+            serde_json::to_string(&email).unwrap(),
+            r#""admin@localhost""#
+        );
+        assert_eq!(
+            serde_json::from_str::<EmailAddress>(r#""Admin@LocalHost""#).unwrap(),
+            email
+        );
+        assert!(serde_json::from_str::<EmailAddress>(r#""admin""#).is_err());
+        assert_eq!(format!("{email:?}"), r#""admin@localhost""#);
+    }
+}
@@ -0,0 +1,660 @@
+// An encrypted container for the users file.
+//
+// hashed passwords still leave emails and roles readable to anyone who can read users.json.
+// this wraps the same json in ChaCha20-Poly1305, an AEAD cipher: it encrypts and also
+// authenticates, a single changed byte anywhere makes decryption fail instead of
+// producing garbage. the key either comes from a passphrase (stretched with PBKDF2 like
+// the passwords are) or is read from a key file.
+//
+// layout of the file, everything before the ciphertext is authenticated too:
+//   "USERSENC"  magic, 8 bytes
+//   1           format version, 1 byte
+//   kdf         0 = key file, 1 = pbkdf2-sha256 followed by rounds (u32 le) and a 16 byte salt
+//   nonce       12 random bytes, new for every write
+//   ciphertext  the users json + 16 byte tag
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use password_hash::rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::{
+    file,
+    password::DEFAULT_ROUNDS,
+    secret::Secret,
+    store::{StoreError, UserStore},
+    User, UsersError,
+};
+
+const MAGIC: &[u8; 8] = b"USERSENC";
+const FORMAT_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// the rounds are read from the header before anything is authenticated, a file edited to
+// ask for u32::MAX rounds would keep every open busy for hours. only a range around what
+// this program writes is accepted.
+const MIN_ROUNDS: u32 = DEFAULT_ROUNDS / 2;
+const MAX_ROUNDS: u32 = DEFAULT_ROUNDS * 4;
+
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    Passphrase(Secret<String>),
+    // 32 bytes written as 64 hex characters, see generate_key_file
+    KeyFile(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kdf {
+    KeyFile,
+    Pbkdf2 { rounds: u32, salt: [u8; SALT_LEN] },
+}
+
+// the key that actually goes into the cipher, wiped from memory when dropped
+struct DerivedKey {
+    kdf: Kdf,
+    key: [u8; KEY_LEN],
+}
+
+impl Drop for DerivedKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    File(UsersError),
+    // the file does not start with the magic bytes, probably a plain users.json
+    NotEncrypted,
+    Malformed(&'static str),
+    UnsupportedVersion(u8),
+    // pbkdf2 rounds outside MIN_ROUNDS..=MAX_ROUNDS in the header
+    UnexpectedRounds(u32),
+    KeyFile { path: PathBuf, message: String },
+    // e.g. a passphrase was given for a file encrypted with a key file
+    WrongKeyKind,
+    // the cipher cannot tell these two apart, and an attacker should not learn which one it was
+    DecryptionFailed,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::File(e) => write!(f, "{e}"),
+            EncryptionError::NotEncrypted => write!(f, "the file is not encrypted"),
+            EncryptionError::Malformed(what) => write!(f, "malformed encrypted file: {what}"),
+            EncryptionError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encrypted file version {version}")
+            }
+            EncryptionError::UnexpectedRounds(rounds) => write!(
+                f,
+                "the header asks for {rounds} key derivation rounds, only \
+                 {MIN_ROUNDS}..={MAX_ROUNDS} are accepted: the file has been tampered with"
+            ),
+            EncryptionError::KeyFile { path, message } => {
+                write!(f, "key file {}: {message}", path.display())
+            }
+            EncryptionError::WrongKeyKind => write!(
+                f,
+                "the file was encrypted with a different kind of key (passphrase vs key file)"
+            ),
+            EncryptionError::DecryptionFailed => write!(
+                f,
+                "decryption failed: the key is wrong or the file has been tampered with"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl From<UsersError> for EncryptionError {
+    fn from(e: UsersError) -> Self {
+        EncryptionError::File(e)
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+// one hex digit, by hand: from_str_radix takes a sign too and slicing a str can land
+// inside a multi byte character
+fn nibble(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<[u8; KEY_LEN], EncryptionError> {
+    let key_file_error = |message: String| EncryptionError::KeyFile {
+        path: path.to_path_buf(),
+        message,
+    };
+    let mut text = fs::read_to_string(path).map_err(|e| key_file_error(e.to_string()))?;
+    let hex = text.trim().as_bytes();
+    if hex.len() != KEY_LEN * 2 {
+        text.zeroize();
+        return Err(key_file_error(format!(
+            "expected {} hex characters",
+            KEY_LEN * 2
+        )));
+    }
+    let mut key = [0; KEY_LEN];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks(2)) {
+        match (nibble(pair[0]), nibble(pair[1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => {
+                text.zeroize();
+                key.zeroize();
+                return Err(key_file_error("not a hex string".to_string()));
+            }
+        }
+    }
+    text.zeroize();
+    Ok(key)
+}
+
+// a new random key, written as hex so it survives being copied around.
+// only the owner may read it, the file is created that way (not chmodded afterwards, in
+// between anyone could read it). an existing key file is never overwritten, whatever was
+// encrypted with it could not be decrypted any more.
+pub fn generate_key_file(path: impl AsRef<Path>) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => EncryptionError::KeyFile {
+            path: path.to_path_buf(),
+            message: "already exists, a new key would lock out what the old one encrypted"
+                .to_string(),
+        },
+        _ => UsersError::io(path, e).into(),
+    })?;
+    let mut key: [u8; KEY_LEN] = random_bytes();
+    let mut hex: String = key.iter().map(|byte| format!("{byte:02x}")).collect();
+    key.zeroize();
+    hex.push('\n');
+    let result = file
+        .write_all(hex.as_bytes())
+        .and_then(|()| file.sync_all());
+    hex.zeroize();
+    result.map_err(|e| UsersError::io(path, e))?;
+    Ok(())
+}
+
+impl KeySource {
+    // existing is the kdf from the header of the file being read, the salt and rounds
+    // must be the same ones it was written with. None for a new file: a fresh salt.
+    fn derive(&self, existing: Option<Kdf>) -> Result<DerivedKey, EncryptionError> {
+        match (self, existing) {
+            (KeySource::KeyFile(path), None | Some(Kdf::KeyFile)) => Ok(DerivedKey {
+                kdf: Kdf::KeyFile,
+                key: read_key_file(path)?,
+            }),
+            (KeySource::Passphrase(passphrase), existing) => {
+                let kdf = match existing {
+                    Some(kdf @ Kdf::Pbkdf2 { .. }) => kdf,
+                    None => Kdf::Pbkdf2 {
+                        rounds: DEFAULT_ROUNDS,
+                        salt: random_bytes(),
+                    },
+                    Some(Kdf::KeyFile) => return Err(EncryptionError::WrongKeyKind),
+                };
+                let Kdf::Pbkdf2 { rounds, salt } = kdf else {
+                    unreachable!()
+                };
+                let mut key = [0; KEY_LEN];
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    passphrase.expose().as_bytes(),
+                    &salt,
+                    rounds,
+                    &mut key,
+                );
+                Ok(DerivedKey { kdf, key })
+            }
+            (KeySource::KeyFile(_), Some(Kdf::Pbkdf2 { .. })) => Err(EncryptionError::WrongKeyKind),
+        }
+    }
+}
+
+fn header(kdf: Kdf, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    match kdf {
+        Kdf::KeyFile => header.push(0),
+        Kdf::Pbkdf2 { rounds, salt } => {
+            header.push(1);
+            header.extend_from_slice(&rounds.to_le_bytes());
+            header.extend_from_slice(&salt);
+        }
+    }
+    header.extend_from_slice(nonce);
+    header
+}
+
+// splits a file into (kdf, nonce, header length)
+fn parse_header(bytes: &[u8]) -> Result<(Kdf, [u8; NONCE_LEN], usize), EncryptionError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(EncryptionError::NotEncrypted);
+    }
+    let truncated = EncryptionError::Malformed("the header is cut short");
+    let mut at = MAGIC.len();
+    let version = *bytes.get(at).ok_or(truncated)?;
+    if version != FORMAT_VERSION {
+        return Err(EncryptionError::UnsupportedVersion(version));
+    }
+    at += 1;
+    let kdf = match bytes.get(at) {
+        Some(0) => {
+            at += 1;
+            Kdf::KeyFile
+        }
+        Some(1) => {
+            let params = bytes
+                .get(at + 1..at + 1 + 4 + SALT_LEN)
+                .ok_or(EncryptionError::Malformed("the header is cut short"))?;
+            at += 1 + 4 + SALT_LEN;
+            let rounds = u32::from_le_bytes(params[..4].try_into().unwrap());
+            if !(MIN_ROUNDS..=MAX_ROUNDS).contains(&rounds) {
+                return Err(EncryptionError::UnexpectedRounds(rounds));
+            }
+            Kdf::Pbkdf2 {
+                rounds,
+                salt: params[4..].try_into().unwrap(),
+            }
+        }
+        Some(_) => return Err(EncryptionError::Malformed("unknown key derivation")),
+        None => return Err(EncryptionError::Malformed("the header is cut short")),
+    };
+    let nonce = bytes
+        .get(at..at + NONCE_LEN)
+        .ok_or(EncryptionError::Malformed("the header is cut short"))?;
+    Ok((kdf, nonce.try_into().unwrap(), at + NONCE_LEN))
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn seal(key: &DerivedKey, plaintext: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_LEN] = random_bytes();
+    let mut sealed = header(key.kdf, &nonce);
+    let cipher = ChaCha20Poly1305::new((&key.key).into());
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &sealed,
+            },
+        )
+        .expect("chacha20poly1305 encrypts any input that fits in memory");
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+fn open_sealed(key: &DerivedKey, bytes: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let (_, nonce, header_len) = parse_header(bytes)?;
+    let cipher = ChaCha20Poly1305::new((&key.key).into());
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &bytes[header_len..],
+                aad: &bytes[..header_len],
+            },
+        )
+        .map_err(|_| EncryptionError::DecryptionFailed)
+}
+
+// reads and decrypts, returns the key too so it can be reused for writing back
+fn read_locked(
+    path: &Path,
+    source: &KeySource,
+) -> Result<(HashMap<String, User>, DerivedKey), EncryptionError> {
+    let bytes = fs::read(path).map_err(|e| UsersError::io(path, e))?;
+    let (kdf, _, _) = parse_header(&bytes)?;
+    let key = source.derive(Some(kdf))?;
+    let mut plaintext = open_sealed(&key, &bytes)?;
+    let result = match std::str::from_utf8(&plaintext) {
+        Ok(text) => file::parse_users(path, text).map(|(users, _)| users),
+        Err(_) => Err(EncryptionError::Malformed(
+            "the decrypted users are not utf-8",
+        ))?,
+    };
+    plaintext.zeroize();
+    Ok((result?, key))
+}
+
+fn write_locked(
+    path: &Path,
+    users: &HashMap<String, User>,
+    key: &DerivedKey,
+) -> Result<(), EncryptionError> {
+    let mut contents = file::to_contents(path, users)?;
+    let sealed = seal(key, contents.as_bytes());
+    contents.zeroize();
+    Ok(file::write_atomic(path, &sealed)?)
+}
+
+pub fn load_encrypted(
+    path: impl AsRef<Path>,
+    source: &KeySource,
+) -> Result<HashMap<String, User>, EncryptionError> {
+    let path = path.as_ref();
+    let _lock = file::lock_shared(path)?;
+    read_locked(path, source).map(|(users, _)| users)
+}
+
+pub fn save_encrypted(
+    path: impl AsRef<Path>,
+    users: &HashMap<String, User>,
+    source: &KeySource,
+) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let _lock = file::lock_exclusive(path)?;
+    write_locked(path, users, &source.derive(None)?)
+}
+
+// turns a plain users.json into the encrypted container, in place
+pub fn encrypt_file(path: impl AsRef<Path>, source: &KeySource) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let _lock = file::lock_exclusive(path)?;
+    let users = file::read_locked(path)?;
+    write_locked(path, &users, &source.derive(None)?)
+}
+
+// and back to a plain users.json
+pub fn decrypt_file(path: impl AsRef<Path>, source: &KeySource) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let _lock = file::lock_exclusive(path)?;
+    let (users, _) = read_locked(path, source)?;
+    Ok(file::write_locked(path, &users)?)
+}
+
+// re-encrypts the file with a new key (new salt, new nonce).
+// the new file replaces the old one atomically, a crash leaves one or the other.
+pub fn rotate_key(
+    path: impl AsRef<Path>,
+    old: &KeySource,
+    new: &KeySource,
+) -> Result<(), EncryptionError> {
+    let path = path.as_ref();
+    let _lock = file::lock_exclusive(path)?;
+    let (users, _) = read_locked(path, old)?;
+    write_locked(path, &users, &new.derive(None)?)
+}
+
+// the encrypted file behind the UserStore trait.
+// the key is derived once on open, stretching a passphrase on every call would be far too slow.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    key: DerivedKey,
+}
+
+impl EncryptedFileStore {
+    // a missing file is an empty store, the file is created on the first change
+    pub fn open(
+        path: impl AsRef<Path>,
+        source: &KeySource,
+    ) -> Result<EncryptedFileStore, EncryptionError> {
+        let path = path.as_ref().to_path_buf();
+        let key = if path.exists() {
+            let _lock = file::lock_shared(&path)?;
+            read_locked(&path, source)?.1
+        } else {
+            source.derive(None)?
+        };
+        Ok(EncryptedFileStore { path, key })
+    }
+
+    fn read(&self) -> Result<HashMap<String, User>, EncryptionError> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let _lock = file::lock_shared(&self.path)?;
+        self.read_locked()
+    }
+
+    fn read_locked(&self) -> Result<HashMap<String, User>, EncryptionError> {
+        let bytes = fs::read(&self.path).map_err(|e| UsersError::io(&self.path, e))?;
+        let mut plaintext = open_sealed(&self.key, &bytes)?;
+        let users = match std::str::from_utf8(&plaintext) {
+            Ok(text) => file::parse_users(&self.path, text).map(|(users, _)| users),
+            Err(_) => Err(EncryptionError::Malformed(
+                "the decrypted users are not utf-8",
+            ))?,
+        };
+        plaintext.zeroize();
+        Ok(users?)
+    }
+
+    // same idea as file::modify_users, one exclusive lock around read-modify-write
+    fn modify<T>(
+        &mut self,
+        f: impl FnOnce(&mut HashMap<String, User>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let _lock = file::lock_exclusive(&self.path)?;
+        let mut users = if self.path.exists() {
+            self.read_locked()?
+        } else {
+            HashMap::new()
+        };
+        let value = f(&mut users)?;
+        write_locked(&self.path, &users, &self.key)?;
+        Ok(value)
+    }
+}
+
+impl UserStore for EncryptedFileStore {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.read()?.remove(username))
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        self.modify(|users| {
+            if users.contains_key(&user.username) {
+                return Err(StoreError::AlreadyExists(user.username));
+            }
+            users.insert(user.username.clone(), user);
+            Ok(())
+        })
+    }
+
+    fn update(&mut self, user: User) -> Result<(), StoreError> {
+        self.modify(|users| match users.get_mut(&user.username) {
+            Some(existing) => {
+                *existing = user;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound(user.username)),
+        })
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        self.modify(|users| {
+            users
+                .remove(username)
+                .ok_or_else(|| StoreError::NotFound(username.to_string()))
+        })
+    }
+
+    fn list(&self) -> Result<Vec<User>, StoreError> {
+        let mut users: Vec<User> = self.read()?.into_values().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_default_users;
+
+    fn passphrase(s: &str) -> KeySource {
+        KeySource::Passphrase(s.into())
+    }
+
+    #[test]
+    fn round_trips_with_a_passphrase_and_hides_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let users = get_default_users();
+        save_encrypted(&path, &users, &passphrase("correct horse")).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(is_encrypted(&bytes));
+        let needle = b"admin@localhost";
+        assert!(!bytes.windows(needle.len()).any(|window| window == needle));
+
+        assert_eq!(
+            load_encrypted(&path, &passphrase("correct horse")).unwrap(),
+            users
+        );
+        assert!(matches!(
+            load_encrypted(&path, &passphrase("wrong")),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn tampering_anywhere_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let key_path = dir.path().join("users.key");
+        generate_key_file(&key_path).unwrap();
+        let key = KeySource::KeyFile(key_path);
+        save_encrypted(&path, &get_default_users(), &key).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        // a byte of the nonce (header) and a byte of the ciphertext
+        let (_, _, header_len) = parse_header(&good).unwrap();
+        for index in [header_len - 1, header_len + 3, good.len() - 1] {
+            let mut bytes = good.clone();
+            bytes[index] ^= 0x01;
+            fs::write(&path, &bytes).unwrap();
+            assert!(matches!(
+                load_encrypted(&path, &key),
+                Err(EncryptionError::DecryptionFailed)
+            ));
+        }
+
+        fs::write(&path, &good[..5]).unwrap();
+        assert!(matches!(
+            load_encrypted(&path, &key),
+            Err(EncryptionError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn tampered_rounds_are_refused_before_deriving() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let key = passphrase("correct horse");
+        save_encrypted(&path, &get_default_users(), &key).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        // magic, version, kdf byte, then the rounds
+        let at = MAGIC.len() + 2;
+        for rounds in [u32::MAX, 1, MIN_ROUNDS - 1, MAX_ROUNDS + 1] {
+            let mut bytes = good.clone();
+            bytes[at..at + 4].copy_from_slice(&rounds.to_le_bytes());
+            fs::write(&path, &bytes).unwrap();
+            // returns at once, u32::MAX rounds would not
+            assert!(matches!(
+                load_encrypted(&path, &key),
+                Err(EncryptionError::UnexpectedRounds(found)) if found == rounds
+            ));
+        }
+    }
+
+    #[test]
+    fn key_rotation_re_encrypts_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        file::save_users(&path, &get_default_users()).unwrap();
+
+        let old = passphrase("old passphrase");
+        encrypt_file(&path, &old).unwrap();
+        let users = load_encrypted(&path, &old).unwrap();
+
+        let key_path = dir.path().join("users.key");
+        generate_key_file(&key_path).unwrap();
+        let new = KeySource::KeyFile(key_path);
+        rotate_key(&path, &old, &new).unwrap();
+
+        assert_eq!(load_encrypted(&path, &new).unwrap(), users);
+        assert!(matches!(
+            load_encrypted(&path, &old),
+            Err(EncryptionError::WrongKeyKind)
+        ));
+
+        decrypt_file(&path, &new).unwrap();
+        assert_eq!(file::load_users(&path).unwrap(), users);
+    }
+
+    #[test]
+    fn bad_key_file_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("users.key");
+        fs::write(&key_path, "too short").unwrap();
+        let result = save_encrypted(
+            dir.path().join("users.json"),
+            &HashMap::new(),
+            &KeySource::KeyFile(key_path.clone()),
+        );
+        assert!(matches!(result, Err(EncryptionError::KeyFile { .. })));
+
+        // the right length in bytes, but not hex: 32 é and a sign
+        for text in ["é".repeat(32), format!("+f{}", "0".repeat(62))] {
+            fs::write(&key_path, text).unwrap();
+            assert!(matches!(
+                read_key_file(&key_path),
+                Err(EncryptionError::KeyFile { message, .. }) if message == "not a hex string"
+            ));
+        }
+    }
+
+    #[test]
+    fn key_files_are_private_and_never_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("users.key");
+        generate_key_file(&key_path).unwrap();
+        let key = fs::read(&key_path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(matches!(
+            generate_key_file(&key_path),
+            Err(EncryptionError::KeyFile { .. })
+        ));
+        assert_eq!(fs::read(&key_path).unwrap(), key);
+    }
+}
@@ -0,0 +1,130 @@
+// Errors from loading and saving the users file.
+// each variant says what went wrong and with which file, so a broken users.json
+// ends up as a readable message instead of a panic from unwrap.
+
+use std::{fmt, io, path::PathBuf};
+
+use crate::schema::MigrationError;
+
+#[derive(Debug)]
+pub enum UsersError {
+    // any other io failure (disk full, file is a directory ...)
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // singled out as it almost always needs a human to fix
+    PermissionDenied {
+        path: PathBuf,
+    },
+    // not json at all, e.g. truncated or hand edited
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // valid json but not the shape of a users file (a missing field, an unknown role ...)
+    SchemaMismatch {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // an old file could not be upgraded, or the file is newer than this program
+    Migration {
+        path: PathBuf,
+        source: MigrationError,
+    },
+}
+
+impl UsersError {
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> UsersError {
+        let path = path.into();
+        match source.kind() {
+            io::ErrorKind::PermissionDenied => UsersError::PermissionDenied { path },
+            _ => UsersError::Io { path, source },
+        }
+    }
+
+    pub(crate) fn json(path: impl Into<PathBuf>, source: serde_json::Error) -> UsersError {
+        let path = path.into();
+        let (line, column) = (source.line(), source.column());
+        // serde_json appends " at line x column y", we keep those as fields instead
+        let message = source.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(index) => message[..index].to_string(),
+            None => message,
+        };
+        match source.classify() {
+            serde_json::error::Category::Data => UsersError::SchemaMismatch {
+                path,
+                line,
+                column,
+                message,
+            },
+            _ => UsersError::Parse {
+                path,
+                line,
+                column,
+                message,
+            },
+        }
+    }
+
+    // the file exists and can be read but its contents are broken.
+    // a file from a newer version is not broken, we just cannot read it.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            UsersError::Parse { .. }
+                | UsersError::SchemaMismatch { .. }
+                | UsersError::Migration {
+                    source: MigrationError::Failed { .. },
+                    ..
+                }
+        )
+    }
+}
+
+impl fmt::Display for UsersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsersError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            UsersError::PermissionDenied { path } => {
+                write!(f, "{}: permission denied", path.display())
+            }
+            UsersError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{}:{line}:{column}: invalid json: {message}",
+                path.display()
+            ),
+            UsersError::SchemaMismatch {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{}:{line}:{column}: not a users file: {message}",
+                path.display()
+            ),
+            UsersError::Migration { path, source } => write!(f, "{}: {source}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for UsersError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UsersError::Io { source, .. } => Some(source),
+            UsersError::Migration { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
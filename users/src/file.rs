@@ -0,0 +1,397 @@
+// Reading and writing the users.json file.
+// the file is a versioned envelope around a json object of username -> user (see schema.rs).
+// older files are upgraded in memory when they are loaded and written in the current
+// format on the next save, migrate_file upgrades one explicitly.
+//
+// Two things protect the file:
+// - writes never touch users.json directly. the new contents go to a temp file next to it,
+//   are fsynced to disk and then renamed over users.json. rename is atomic, after a crash
+//   the file is either the old one or the new one, never half written.
+// - every function takes an advisory lock (flock) on users.json.lock first. readers share
+//   the lock, writers hold it alone, so several processes can use the same file.
+//   the lock is on a separate file because rename replaces users.json with a new file
+//   and a lock on the old one would protect nothing.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+use crate::{
+    get_default_users,
+    schema::{self, Envelope, MigrationReport, CURRENT_VERSION},
+    User, UsersError,
+};
+
+// holds the lock until dropped, the os releases flock when the file is closed.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: File,
+}
+
+fn sibling(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(prefix);
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn open_lock_file(path: &Path) -> Result<File, UsersError> {
+    let lock_path = sibling(path, "", ".lock");
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| UsersError::io(lock_path, e))
+}
+
+// for reading, any number of processes can hold it at once. blocks while a writer holds it.
+pub fn lock_shared(path: impl AsRef<Path>) -> Result<FileLock, UsersError> {
+    let path = path.as_ref();
+    let file = open_lock_file(path)?;
+    file.lock_shared().map_err(|e| UsersError::io(path, e))?;
+    Ok(FileLock { _file: file })
+}
+
+// for read-modify-write, blocks until every other holder is gone.
+pub fn lock_exclusive(path: impl AsRef<Path>) -> Result<FileLock, UsersError> {
+    let path = path.as_ref();
+    let file = open_lock_file(path)?;
+    file.lock().map_err(|e| UsersError::io(path, e))?;
+    Ok(FileLock { _file: file })
+}
+
+// the *_locked functions expect the caller to hold the lock already.
+// flock locks taken through two different open files conflict even inside one process,
+// so locking again here would wait on ourselves forever.
+pub(crate) fn read_locked(path: &Path) -> Result<HashMap<String, User>, UsersError> {
+    let contents = fs::read_to_string(path).map_err(|e| UsersError::io(path, e))?;
+    parse_users(path, &contents).map(|(users, _)| users)
+}
+
+// parses and if needed migrates the contents of a users file
+pub(crate) fn parse_users(
+    path: &Path,
+    contents: &str,
+) -> Result<(HashMap<String, User>, MigrationReport), UsersError> {
+    let mut document: Value =
+        serde_json::from_str(contents).map_err(|e| UsersError::json(path, e))?;
+    let report = schema::migrate(&mut document).map_err(|source| UsersError::Migration {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let envelope: Envelope<HashMap<String, User>> = if report.is_up_to_date() {
+        // parse the text again rather than the Value, errors then point at a line and column
+        serde_json::from_str(contents)
+    } else {
+        serde_json::from_value(document)
+    }
+    .map_err(|e| UsersError::json(path, e))?;
+    Ok((envelope.users, report))
+}
+
+pub(crate) fn write_locked(path: &Path, users: &HashMap<String, User>) -> Result<(), UsersError> {
+    let contents = to_contents(path, users)?;
+    write_atomic(path, contents.as_bytes())
+}
+
+// the users in the current envelope, as they are written to the file
+pub(crate) fn to_contents(
+    path: &Path,
+    users: &HashMap<String, User>,
+) -> Result<String, UsersError> {
+    let envelope = Envelope {
+        schema_version: CURRENT_VERSION,
+        users,
+    };
+    // serializing a map of strings to users cannot fail, it is still an error and not a panic
+    serde_json::to_string(&envelope).map_err(|e| UsersError::json(path, e))
+}
+
+// temp file, fsync, rename, fsync the directory. see the top of this file.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), UsersError> {
+    let temp_path = sibling(path, ".", &format!(".tmp-{}", std::process::id()));
+    let result = (|| {
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(contents)?;
+        // make sure the bytes are on disk before the rename makes them visible
+        temp.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        // the rename itself lives in the directory, sync that too
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    })();
+    result.map_err(|e| {
+        let _ = fs::remove_file(&temp_path);
+        UsersError::io(path, e)
+    })
+}
+
+fn load_or_create_locked(path: &Path) -> Result<HashMap<String, User>, UsersError> {
+    if path.exists() {
+        read_locked(path)
+    } else {
+        let users = get_default_users();
+        write_locked(path, &users)?;
+        Ok(users)
+    }
+}
+
+pub fn load_users(path: impl AsRef<Path>) -> Result<HashMap<String, User>, UsersError> {
+    let path = path.as_ref();
+    let _lock = lock_shared(path)?;
+    read_locked(path)
+}
+
+pub fn save_users(path: impl AsRef<Path>, users: &HashMap<String, User>) -> Result<(), UsersError> {
+    let path = path.as_ref();
+    let _lock = lock_exclusive(path)?;
+    write_locked(path, users)
+}
+
+// loads the file, a missing file is created with the default users
+pub fn load_or_create(path: impl AsRef<Path>) -> Result<HashMap<String, User>, UsersError> {
+    let path = path.as_ref();
+    let _lock = lock_exclusive(path)?;
+    load_or_create_locked(path)
+}
+
+// read-modify-write under one exclusive lock, no other process can sneak a write in between.
+// a missing file starts out empty. the users are only written back when f returns Ok,
+// so f can bail out with its own error (anything a UsersError converts into).
+pub fn modify_users<T, E>(
+    path: impl AsRef<Path>,
+    f: impl FnOnce(&mut HashMap<String, User>) -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: From<UsersError>,
+{
+    let path = path.as_ref();
+    let _lock = lock_exclusive(path)?;
+    let mut users = if path.exists() {
+        read_locked(path)?
+    } else {
+        HashMap::new()
+    };
+    let value = f(&mut users)?;
+    write_locked(path, &users)?;
+    Ok(value)
+}
+
+// upgrades the file on disk to the current schema version and says what changed.
+// with dry_run the file is left untouched, the report says what would change.
+pub fn migrate_file(path: impl AsRef<Path>, dry_run: bool) -> Result<MigrationReport, UsersError> {
+    let path = path.as_ref();
+    let _lock = lock_exclusive(path)?;
+    let contents = fs::read_to_string(path).map_err(|e| UsersError::io(path, e))?;
+    // parsing all the way to users also proves the migrated file will load
+    let (users, report) = parse_users(path, &contents)?;
+    if !dry_run && !report.is_up_to_date() {
+        write_locked(path, &users)?;
+    }
+    Ok(report)
+}
+
+#[derive(Debug)]
+pub struct Recovered {
+    pub users: HashMap<String, User>,
+    // where the corrupt file was moved to, None when the file was fine
+    pub quarantined: Option<PathBuf>,
+}
+
+// recovery mode of load_or_create.
+// a corrupt file (bad json or wrong shape) is not deleted, it is renamed to
+// users.json.corrupt-<unix timestamp> so it can be inspected later, and the defaults are
+// written in its place. io errors such as permission denied are still returned,
+// moving a file we cannot read would not fix anything.
+pub fn load_or_recover(path: impl AsRef<Path>) -> Result<Recovered, UsersError> {
+    let path = path.as_ref();
+    let _lock = lock_exclusive(path)?;
+    match load_or_create_locked(path) {
+        Ok(users) => Ok(Recovered {
+            users,
+            quarantined: None,
+        }),
+        Err(e) if e.is_corrupt() => {
+            let quarantined = quarantine(path)?;
+            let users = get_default_users();
+            write_locked(path, &users)?;
+            Ok(Recovered {
+                users,
+                quarantined: Some(quarantined),
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn quarantine(path: &Path) -> Result<PathBuf, UsersError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let quarantined = sibling(path, "", &format!(".corrupt-{timestamp}"));
+    fs::rename(path, &quarantined).map_err(|e| UsersError::io(path, e))?;
+    Ok(quarantined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_users;
+
+    #[test]
+    fn missing_file_is_created_with_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let users = load_or_create(&path).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(load_users(&path).unwrap(), users);
+    }
+
+    #[test]
+    fn truncated_file_is_a_parse_error_with_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, "{\n  \"admin\": {\"username\": \"admin\",").unwrap();
+        match load_users(&path) {
+            Err(UsersError::Parse { line, column, .. }) => {
+                assert_eq!(line, 2);
+                assert!(column > 0);
+            }
+            other => panic!("expected a parse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn wrong_shape_is_a_schema_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(
+            &path,
+            r#"{"schema_version": 3, "users": {"admin": {"username": "admin"}}}"#,
+        )
+        .unwrap();
+        let error = load_users(&path).unwrap_err();
+        assert!(matches!(error, UsersError::SchemaMismatch { line: 1, .. }));
+        assert!(error.to_string().contains("missing field"));
+    }
+
+    #[test]
+    fn corrupt_file_is_quarantined_and_defaults_regenerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, "not json").unwrap();
+
+        let recovered = load_or_recover(&path).unwrap();
+        assert_eq!(recovered.users.len(), 2);
+        let quarantined = recovered.quarantined.unwrap();
+        let name = quarantined.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("users.json.corrupt-"));
+        assert_eq!(fs::read_to_string(&quarantined).unwrap(), "not json");
+        assert_eq!(load_users(&path).unwrap(), recovered.users);
+
+        // nothing left to recover the second time
+        assert!(load_or_recover(&path).unwrap().quarantined.is_none());
+    }
+
+    const VERSION_1: &str = r#"{"admin": {"username": "admin", "email": "admin@localhost", "password": "admin", "role": "Admin"}}"#;
+
+    #[test]
+    fn old_file_is_upgraded_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, VERSION_1).unwrap();
+        let users = load_users(&path).unwrap();
+        assert_eq!(users["admin"].role, crate::Role::Admin);
+        // loading does not write, the file stays as it was until saved
+        assert_eq!(fs::read_to_string(&path).unwrap(), VERSION_1);
+    }
+
+    #[test]
+    fn dry_run_reports_without_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, VERSION_1).unwrap();
+
+        let report = migrate_file(&path, true).unwrap();
+        assert_eq!(report.from_version, 1);
+        assert!(!report.is_up_to_date());
+        assert_eq!(fs::read_to_string(&path).unwrap(), VERSION_1);
+
+        assert_eq!(migrate_file(&path, false).unwrap(), report);
+        let document: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(document["schema_version"], CURRENT_VERSION);
+        assert!(migrate_file(&path, false).unwrap().is_up_to_date());
+    }
+
+    #[test]
+    fn newer_file_is_not_treated_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        fs::write(&path, r#"{"schema_version": 99, "users": {}}"#).unwrap();
+        let error = load_or_recover(&path).unwrap_err();
+        assert!(matches!(error, UsersError::Migration { .. }));
+        assert!(!error.is_corrupt());
+    }
+
+    #[test]
+    fn save_leaves_no_temp_file_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        save_users(&path, &HashMap::new()).unwrap();
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["users.json", "users.json.lock"]);
+    }
+
+    #[test]
+    fn concurrent_modifications_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let template = get_users().remove(0);
+
+        // every thread opens its own lock file, just like separate processes would
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let path = &path;
+                let template = &template;
+                s.spawn(move || {
+                    modify_users(path, |users| {
+                        let mut user = template.clone();
+                        user.username = format!("user{i}");
+                        users.insert(user.username.clone(), user);
+                        Ok::<_, UsersError>(())
+                    })
+                    .unwrap();
+                });
+            }
+        });
+        assert_eq!(load_users(&path).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn failed_modification_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let result = modify_users(&path, |users| {
+            users.clear();
+            Err::<(), _>(UsersError::PermissionDenied { path: path.clone() })
+        });
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+}
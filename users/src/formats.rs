@@ -0,0 +1,312 @@
+// Import and export of users in formats other than the users.json file.
+// - json, toml, yaml: text formats people edit by hand (seed data)
+// - csv: one row per user, for reports and spreadsheets
+// - messagepack, cbor: compact binary snapshots
+//
+// every format except csv holds the same versioned envelope as users.json (see schema.rs).
+// on import the document is first read into a serde_json::Value, whatever format it came in,
+// so the same migrations upgrade old documents in every format.
+
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+    schema::{self, Envelope, MigrationError, CURRENT_VERSION},
+    User,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Csv,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    pub const ALL: [Format; 6] = [
+        Format::Json,
+        Format::Toml,
+        Format::Yaml,
+        Format::Csv,
+        Format::MessagePack,
+        Format::Cbor,
+    ];
+
+    // the extension export_file expects and the first one from_extension accepts
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Csv => "csv",
+            Format::MessagePack => "msgpack",
+            Format::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_extension(path: impl AsRef<Path>) -> Option<Format> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "csv" => Some(Format::Csv),
+            "msgpack" | "mpk" => Some(Format::MessagePack),
+            "cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    // guesses the format from the first bytes of the contents.
+    // the binary formats are told apart by how they encode the top level map,
+    // the text formats by what their first meaningful line looks like.
+    pub fn from_magic(bytes: &[u8]) -> Option<Format> {
+        match bytes.first()? {
+            // messagepack: fixmap, map16, map32
+            0x80..=0x8f | 0xde | 0xdf => return Some(Format::MessagePack),
+            // cbor: map of any size, or the self describe tag d9 d9 f7
+            0xa0..=0xbf => return Some(Format::Cbor),
+            0xd9 if bytes.starts_with(&[0xd9, 0xd9, 0xf7]) => return Some(Format::Cbor),
+            _ => {}
+        }
+
+        let text = std::str::from_utf8(bytes).ok()?;
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))?;
+        // a bare key followed by = is toml, followed by : is yaml
+        let after_key = line
+            .trim_start_matches(|c: char| c.is_ascii_alphanumeric() || "_-.\"".contains(c))
+            .trim_start();
+        if line.starts_with('{') {
+            Some(Format::Json)
+        } else if line.starts_with("username,") {
+            Some(Format::Csv)
+        } else if line.starts_with('[') || after_key.starts_with('=') {
+            Some(Format::Toml)
+        } else if line == "---" || line.starts_with("- ") || after_key.starts_with(':') {
+            Some(Format::Yaml)
+        } else {
+            None
+        }
+    }
+
+    // the extension wins, the contents are only looked at when it says nothing
+    pub fn detect(path: impl AsRef<Path>, bytes: &[u8]) -> Option<Format> {
+        Self::from_extension(path).or_else(|| Self::from_magic(bytes))
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Csv => "csv",
+            Format::MessagePack => "messagepack",
+            Format::Cbor => "cbor",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    UnknownFormat(String),
+    Io(std::io::Error),
+    // the libraries all have their own error types, the message is all we keep
+    Encode { format: Format, message: String },
+    Decode { format: Format, message: String },
+    Migration(MigrationError),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::UnknownFormat(path) => write!(f, "cannot tell the format of {path}"),
+            FormatError::Io(e) => write!(f, "io error: {e}"),
+            FormatError::Encode { format, message } => {
+                write!(f, "cannot write {format}: {message}")
+            }
+            FormatError::Decode { format, message } => {
+                write!(f, "cannot read {format}: {message}")
+            }
+            FormatError::Migration(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl From<std::io::Error> for FormatError {
+    fn from(e: std::io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+fn encode_error(format: Format) -> impl Fn(&dyn fmt::Display) -> FormatError {
+    move |e| FormatError::Encode {
+        format,
+        message: e.to_string(),
+    }
+}
+
+fn decode_error(format: Format) -> impl Fn(&dyn fmt::Display) -> FormatError {
+    move |e| FormatError::Decode {
+        format,
+        message: e.to_string(),
+    }
+}
+
+pub fn export_users(users: &HashMap<String, User>, format: Format) -> Result<Vec<u8>, FormatError> {
+    let envelope = Envelope {
+        schema_version: CURRENT_VERSION,
+        users,
+    };
+    let error = encode_error(format);
+    match format {
+        Format::Json => serde_json::to_vec_pretty(&envelope).map_err(|e| error(&e)),
+        Format::Toml => toml::to_string(&envelope)
+            .map(String::into_bytes)
+            .map_err(|e| error(&e)),
+        Format::Yaml => serde_yaml::to_string(&envelope)
+            .map(String::into_bytes)
+            .map_err(|e| error(&e)),
+        Format::Csv => {
+            // rows sorted by username so exports can be diffed
+            let mut rows: Vec<&User> = users.values().collect();
+            rows.sort_by(|a, b| a.username.cmp(&b.username));
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for row in rows {
+                writer.serialize(row).map_err(|e| error(&e))?;
+            }
+            writer.into_inner().map_err(|e| error(&e))
+        }
+        // to_vec_named writes structs as maps with field names, the default writes arrays
+        Format::MessagePack => rmp_serde::to_vec_named(&envelope).map_err(|e| error(&e)),
+        Format::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&envelope, &mut bytes).map_err(|e| error(&e))?;
+            Ok(bytes)
+        }
+    }
+}
+
+pub fn import_users(bytes: &[u8], format: Format) -> Result<HashMap<String, User>, FormatError> {
+    let error = decode_error(format);
+    let mut document: Value = match format {
+        Format::Json => serde_json::from_slice(bytes).map_err(|e| error(&e))?,
+        Format::Toml => {
+            let text = std::str::from_utf8(bytes).map_err(|e| error(&e))?;
+            toml::from_str(text).map_err(|e| error(&e))?
+        }
+        Format::Yaml => serde_yaml::from_slice(bytes).map_err(|e| error(&e))?,
+        Format::Csv => {
+            let mut users = HashMap::new();
+            for row in csv::Reader::from_reader(bytes).deserialize() {
+                let user: User = row.map_err(|e| error(&e))?;
+                users.insert(user.username.clone(), user);
+            }
+            // csv has no envelope and nothing to migrate
+            return Ok(users);
+        }
+        Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| error(&e))?,
+        Format::Cbor => ciborium::from_reader(bytes).map_err(|e| error(&e))?,
+    };
+    schema::migrate(&mut document).map_err(FormatError::Migration)?;
+    let envelope: Envelope<HashMap<String, User>> =
+        serde_json::from_value(document).map_err(|e| error(&e))?;
+    Ok(envelope.users)
+}
+
+// the format comes from the file extension, then from the contents
+pub fn import_file(path: impl AsRef<Path>) -> Result<HashMap<String, User>, FormatError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let format = Format::detect(path, &bytes)
+        .ok_or_else(|| FormatError::UnknownFormat(path.display().to_string()))?;
+    import_users(&bytes, format)
+}
+
+// the format comes from the file extension
+pub fn export_file(
+    path: impl AsRef<Path>,
+    users: &HashMap<String, User>,
+) -> Result<(), FormatError> {
+    let path = path.as_ref();
+    let format = Format::from_extension(path)
+        .ok_or_else(|| FormatError::UnknownFormat(path.display().to_string()))?;
+    fs::write(path, export_users(users, format)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_default_users;
+
+    #[test]
+    fn every_format_round_trips() {
+        let users = get_default_users();
+        for format in Format::ALL {
+            let bytes = export_users(&users, format).unwrap();
+            assert_eq!(import_users(&bytes, format).unwrap(), users, "{format}");
+        }
+    }
+
+    #[test]
+    fn every_format_is_detected_from_its_contents() {
+        let users = get_default_users();
+        for format in Format::ALL {
+            let bytes = export_users(&users, format).unwrap();
+            assert_eq!(Format::from_magic(&bytes), Some(format), "{format}");
+        }
+    }
+
+    #[test]
+    fn extension_wins_over_contents() {
+        assert_eq!(Format::detect("seed.yml", b"{}"), Some(Format::Yaml));
+        assert_eq!(Format::detect("seed", b"{}"), Some(Format::Json));
+        assert_eq!(Format::detect("seed.txt", b"\x00"), None);
+        assert_eq!(
+            Format::from_magic(b"# seed\nemail = \"a: b\""),
+            Some(Format::Toml)
+        );
+        assert_eq!(Format::from_magic(b"email: a=b"), Some(Format::Yaml));
+    }
+
+    #[test]
+    fn old_toml_seed_is_migrated() {
+        // version 1 (no envelope) and a capitalized role, as someone would type it
+        let seed = r#"
+[admin]
+username = "admin"
+email = "admin@localhost"
+password = "admin"
+role = "Admin"
+"#;
+        let users = import_users(seed.as_bytes(), Format::Toml).unwrap();
+        assert_eq!(users["admin"].role, crate::Role::Admin);
+    }
+
+    #[test]
+    fn files_round_trip_through_their_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = get_default_users();
+        for format in Format::ALL {
+            let path = dir.path().join(format!("users.{}", format.extension()));
+            export_file(&path, &users).unwrap();
+            assert_eq!(import_file(&path).unwrap(), users);
+        }
+        assert!(matches!(
+            export_file(dir.path().join("users.txt"), &users),
+            Err(FormatError::UnknownFormat(_))
+        ));
+    }
+}
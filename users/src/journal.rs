@@ -0,0 +1,496 @@
+// An append-only journal (write-ahead log) of changes to the users.
+//
+// rewriting all of users.json for every change gets slow as the file grows.
+// instead every insert/update/delete is appended to users.journal as one record,
+// and the users are rebuilt by replaying the records on top of the last snapshot (users.json).
+// compact folds the journal into a new snapshot and starts an empty journal.
+//
+// a record on disk:  [payload length: u32 le][crc32 of payload: u32 le][payload: json Mutation]
+// a crash while appending leaves a torn record at the end, it is too short or its checksum
+// does not match. that record was never acknowledged to anyone so it is dropped on open.
+// a bad record followed by more bytes is real corruption and an error, the file is left
+// as it is for someone to look at. that includes a broken length that claims to run past
+// the end: what follows it is more records, not the start of one json payload.
+// a tail of nothing but zero bytes is torn too, some file systems grow the file before the
+// data lands in it. no real record starts with a zero: its payload is never empty.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::{self, FileLock},
+    store::{StoreError, UserStore},
+    User, UsersError,
+};
+
+const HEADER_LEN: usize = 8;
+
+// compact after this many records unless told otherwise
+pub const DEFAULT_COMPACT_AFTER: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Mutation {
+    Insert { user: User },
+    Update { user: User },
+    Delete { username: String },
+}
+
+impl Mutation {
+    // every mutation sets a user to its final state (or removes it), so replaying records
+    // that are already in the snapshot changes nothing. that makes a crash between
+    // writing the snapshot and emptying the journal harmless.
+    fn apply(self, users: &mut HashMap<String, User>) {
+        match self {
+            Mutation::Insert { user } | Mutation::Update { user } => {
+                users.insert(user.username.clone(), user);
+            }
+            Mutation::Delete { username } => {
+                users.remove(&username);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Snapshot(UsersError),
+    // a record in the middle of the journal is broken, offset is where it starts
+    Corrupt { offset: u64 },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "journal io error: {e}"),
+            JournalError::Snapshot(e) => write!(f, "snapshot: {e}"),
+            JournalError::Corrupt { offset } => {
+                write!(f, "journal record at byte {offset} is corrupt")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<UsersError> for JournalError {
+    fn from(e: UsersError) -> Self {
+        JournalError::Snapshot(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    snapshot_path: PathBuf,
+    log: File,
+    users: HashMap<String, User>,
+    records: usize,
+    compact_after: usize,
+    discarded: u64,
+    // why the last compaction failed, it is tried again on the next append
+    compact_error: Option<JournalError>,
+    // one writer at a time, held for as long as the journal is open
+    _lock: FileLock,
+}
+
+impl Journal {
+    // users.json -> users.journal next to it
+    pub fn open(snapshot_path: impl AsRef<Path>) -> Result<Journal, JournalError> {
+        let snapshot_path = snapshot_path.as_ref().to_path_buf();
+        let log_path = snapshot_path.with_extension("journal");
+        let lock = file::lock_exclusive(&snapshot_path)?;
+
+        let mut users = if snapshot_path.exists() {
+            file::read_locked(&snapshot_path)?
+        } else {
+            HashMap::new()
+        };
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&log_path)?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let (mutations, valid_len) = read_records(&bytes)?;
+        let records = mutations.len();
+        for mutation in mutations {
+            mutation.apply(&mut users);
+        }
+
+        // drop the torn tail so the next record is appended after the last good one
+        let discarded = bytes.len() as u64 - valid_len;
+        if discarded > 0 {
+            log.set_len(valid_len)?;
+            log.sync_all()?;
+        }
+        log.seek(SeekFrom::Start(valid_len))?;
+
+        Ok(Journal {
+            snapshot_path,
+            log,
+            users,
+            records,
+            compact_after: DEFAULT_COMPACT_AFTER,
+            discarded,
+            compact_error: None,
+            _lock: lock,
+        })
+    }
+
+    pub fn compact_after(mut self, records: usize) -> Journal {
+        self.compact_after = records;
+        self
+    }
+
+    pub fn users(&self) -> &HashMap<String, User> {
+        &self.users
+    }
+
+    // records in the journal since the last snapshot
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    // bytes of a torn record dropped by open, 0 when the journal ended cleanly
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+    }
+
+    // set when compacting after an append failed, cleared once it works
+    pub fn compact_error(&self) -> Option<&JournalError> {
+        self.compact_error.as_ref()
+    }
+
+    // the record is on disk (fsynced) before the users in memory change
+    pub fn append(&mut self, mutation: Mutation) -> Result<(), JournalError> {
+        let payload = serde_json::to_vec(&mutation).map_err(io::Error::other)?;
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        let valid_len = self.log.stream_position()?;
+        if let Err(e) = self
+            .log
+            .write_all(&record)
+            .and_then(|()| self.log.sync_data())
+        {
+            // half a record must not stay in front of the next one, cut back to the last
+            // good record. if even that fails open will find the torn tail.
+            let _ = self
+                .log
+                .set_len(valid_len)
+                .and_then(|()| self.log.seek(SeekFrom::Start(valid_len)));
+            return Err(e.into());
+        }
+
+        mutation.apply(&mut self.users);
+        self.records += 1;
+        // the record is durable, so the append has happened whatever compaction does.
+        // a failed one leaves records at the limit and the next append tries again.
+        if self.records >= self.compact_after {
+            self.compact_error = self.compact().err();
+        }
+        Ok(())
+    }
+
+    // writes the users as a new snapshot (atomically, see file.rs) and empties the journal
+    pub fn compact(&mut self) -> Result<(), JournalError> {
+        file::write_locked(&self.snapshot_path, &self.users)?;
+        self.log.set_len(0)?;
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.sync_all()?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+// returns the mutations of every good record and how many bytes they take
+fn read_records(bytes: &[u8]) -> Result<(Vec<Mutation>, u64), JournalError> {
+    let mut mutations = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        // not even a full header, or only zeros up to the end: torn
+        if rest.len() < HEADER_LEN || rest.iter().all(|&b| b == 0) {
+            break;
+        }
+        let len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let end = HEADER_LEN + len;
+        // the payload runs past the end of the file. torn if what is there is the start of
+        // a payload, a whole payload followed by more means the length is wrong
+        if rest.len() < end {
+            let present = &rest[HEADER_LEN..];
+            let torn = present.is_empty()
+                || serde_json::from_slice::<serde_json::Value>(present).is_err_and(|e| e.is_eof());
+            if torn {
+                break;
+            }
+            return Err(JournalError::Corrupt {
+                offset: offset as u64,
+            });
+        }
+        let payload = &rest[HEADER_LEN..end];
+        let is_last = rest.len() == end;
+        let mutation = (crc32fast::hash(payload) == crc)
+            .then(|| serde_json::from_slice::<Mutation>(payload).ok())
+            .flatten();
+        match mutation {
+            Some(mutation) => mutations.push(mutation),
+            // the last record was being written when we crashed
+            None if is_last => break,
+            None => {
+                return Err(JournalError::Corrupt {
+                    offset: offset as u64,
+                })
+            }
+        }
+        offset += end;
+    }
+    Ok((mutations, offset as u64))
+}
+
+// the journal behind the same trait as the other stores
+impl UserStore for Journal {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.users.get(username).cloned())
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        if self.users.contains_key(&user.username) {
+            return Err(StoreError::AlreadyExists(user.username));
+        }
+        Ok(self.append(Mutation::Insert { user })?)
+    }
+
+    fn update(&mut self, user: User) -> Result<(), StoreError> {
+        if !self.users.contains_key(&user.username) {
+            return Err(StoreError::NotFound(user.username));
+        }
+        Ok(self.append(Mutation::Update { user })?)
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        let user = self
+            .users
+            .get(username)
+            .cloned()
+            .ok_or_else(|| StoreError::NotFound(username.to_string()))?;
+        self.append(Mutation::Delete {
+            username: username.to_string(),
+        })?;
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StoreError> {
+        let mut users: Vec<User> = self.users.values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_users, Role};
+
+    fn journal_path(dir: &Path) -> PathBuf {
+        dir.join("users.journal")
+    }
+
+    #[test]
+    fn replay_rebuilds_the_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for user in get_users() {
+                journal.insert(user).unwrap();
+            }
+            let mut admin = journal.get("admin").unwrap().unwrap();
+            admin.email = "root@localhost".parse().unwrap();
+            journal.update(admin).unwrap();
+            journal.delete("user").unwrap();
+        }
+        // nothing was snapshotted, it all comes from the journal
+        assert!(!path.exists());
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.records(), 4);
+        assert_eq!(journal.users().len(), 1);
+        assert_eq!(journal.users()["admin"].email, "root@localhost");
+    }
+
+    #[test]
+    fn torn_last_record_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for user in get_users() {
+                journal.insert(user).unwrap();
+            }
+        }
+        // cut the last record short, as a crash in the middle of write_all would
+        let log = OpenOptions::new()
+            .write(true)
+            .open(journal_path(dir.path()))
+            .unwrap();
+        let len = log.metadata().unwrap().len();
+        log.set_len(len - 5).unwrap();
+
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.records(), 1);
+        assert!(journal.discarded_bytes() > 0);
+        assert!(journal.users().contains_key("admin"));
+        assert!(!journal.users().contains_key("user"));
+
+        // new records go after the last good one
+        journal.insert(get_users().remove(1)).unwrap();
+        drop(journal);
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.records(), 2);
+        assert_eq!(journal.discarded_bytes(), 0);
+    }
+
+    #[test]
+    fn zeros_after_the_last_record_are_torn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for user in get_users() {
+                journal.insert(user).unwrap();
+            }
+        }
+        // the file grew but the data never made it, as after a crash on some file systems
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(journal_path(dir.path()))
+            .unwrap();
+        log.write_all(&[0; 4096]).unwrap();
+        drop(log);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.records(), 2);
+        assert_eq!(journal.discarded_bytes(), 4096);
+    }
+
+    #[test]
+    fn bad_checksum_on_the_last_record_is_torn_in_the_middle_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for user in get_users() {
+                journal.insert(user).unwrap();
+            }
+        }
+        let log_path = journal_path(dir.path());
+        let good = std::fs::read(&log_path).unwrap();
+
+        // flip a byte in the last payload
+        let mut bytes = good.clone();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&log_path, &bytes).unwrap();
+        assert_eq!(Journal::open(&path).unwrap().records(), 1);
+
+        // flip a byte in the first payload
+        let mut bytes = good;
+        bytes[HEADER_LEN] ^= 0xff;
+        std::fs::write(&log_path, &bytes).unwrap();
+        assert!(matches!(
+            Journal::open(&path),
+            Err(JournalError::Corrupt { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn broken_length_in_the_middle_is_corrupt_not_torn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        {
+            let mut journal = Journal::open(&path).unwrap();
+            for user in get_users() {
+                journal.insert(user).unwrap();
+            }
+        }
+        let log_path = journal_path(dir.path());
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        // the first record now claims to run past the end of the file
+        bytes[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&log_path, &bytes).unwrap();
+        assert!(matches!(
+            Journal::open(&path),
+            Err(JournalError::Corrupt { offset: 0 })
+        ));
+        // and the records after it are still there
+        assert_eq!(std::fs::read(&log_path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn compaction_folds_the_journal_into_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let mut journal = Journal::open(&path).unwrap().compact_after(3);
+        for user in get_users() {
+            journal.insert(user).unwrap();
+        }
+        assert_eq!(journal.records(), 2);
+        let mut user = journal.get("user").unwrap().unwrap();
+        user.role = Role::Admin;
+        // the third record triggers compaction
+        journal.update(user).unwrap();
+        assert_eq!(journal.records(), 0);
+        assert_eq!(
+            std::fs::metadata(journal_path(dir.path())).unwrap().len(),
+            0
+        );
+        drop(journal);
+
+        let snapshot = file::load_users(&path).unwrap();
+        assert_eq!(snapshot["user"].role, Role::Admin);
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.users(), &snapshot);
+    }
+
+    #[test]
+    fn failed_compaction_does_not_fail_the_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let mut journal = Journal::open(&path).unwrap().compact_after(1);
+        // a directory in the way, the snapshot cannot be renamed over it
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("in-the-way"), "").unwrap();
+
+        let mut users = get_users();
+        journal.insert(users.remove(0)).unwrap();
+        assert!(journal.compact_error().is_some());
+        assert_eq!(journal.records(), 1);
+
+        // tried again on the next append
+        std::fs::remove_dir_all(&path).unwrap();
+        journal.insert(users.remove(0)).unwrap();
+        assert!(journal.compact_error().is_none());
+        assert_eq!(journal.records(), 0);
+        drop(journal);
+        assert_eq!(file::load_users(&path).unwrap().len(), 2);
+    }
+}
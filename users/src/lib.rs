@@ -0,0 +1,322 @@
+// The users domain crate.
+// Every lesson that needs a user (structures, vectors, hashmaps, serialize-deserialize,
+// enumerations) used to declare its own copy of User. They drifted apart, so a fix to one
+// never reached the others and the json written by one binary could not be read by another.
+// This library owns the one and only User/Role and the dummy data every lesson starts with.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use email::{EmailAddress, EmailError};
+use password_policy::PolicyError;
+use secret::Secret;
+
+pub mod audit;
+pub mod builder;
+pub mod clock;
+pub mod email;
+pub mod encrypted;
+mod error;
+pub mod file;
+pub mod formats;
+pub mod journal;
+pub mod password;
+pub mod password_policy;
+pub mod schema;
+pub mod secret;
+pub mod session;
+pub mod store;
+pub mod throttle;
+pub mod token;
+pub mod totp;
+
+pub use error::UsersError;
+
+// rename_all keeps the serialized form as "admin"/"user"
+// which is what the old users.json files already contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::User => write!(f, "user"),
+        }
+    }
+}
+
+// the inverse of Display, "admin".parse::<Role>()
+impl FromStr for Role {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "user" => Ok(Role::User),
+            _ => Err(ValidationError::InvalidRole(s.to_string())),
+        }
+    }
+}
+
+// deserializing goes through UserBuilder (see UserRecord), a file cannot hold a bad user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UserRecord")]
+pub struct User {
+    pub username: String,
+    // always a valid address, see email.rs
+    pub email: EmailAddress,
+    // private, the only way to use it is through check_password.
+    // holds a PHC hash (see password.rs), files written before hashing existed hold plaintext
+    // which is replaced by a hash on the first successful login (see upgrade_password).
+    // a Secret so {:?} does not print it, written to files as it is since it is a hash.
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    password: Secret<String>,
+    pub role: Role,
+}
+
+// a User as it is stored, before it has been checked
+#[derive(Deserialize)]
+struct UserRecord {
+    username: String,
+    email: String,
+    password: String,
+    role: Role,
+}
+
+impl TryFrom<UserRecord> for User {
+    type Error = builder::BuildError;
+
+    fn try_from(record: UserRecord) -> Result<Self, Self::Error> {
+        User::builder()
+            .username(record.username)
+            .email(record.email)
+            .password_hash(record.password)
+            .role(record.role)
+            .build()
+    }
+}
+
+// everything that can be wrong with a user when validating it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    // a field UserBuilder was never given
+    Missing(&'static str),
+    EmptyUsername,
+    UsernameTooLong { max: usize, actual: usize },
+    InvalidUsername(String),
+    InvalidEmail { email: String, reason: EmailError },
+    EmptyPassword,
+    WeakPassword(PolicyError),
+    InvalidRole(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Missing(field) => write!(f, "{field} is missing"),
+            ValidationError::EmptyUsername => write!(f, "username cannot be empty"),
+            ValidationError::UsernameTooLong { max, actual } => write!(
+                f,
+                "username is {actual} characters long, at most {max} are allowed"
+            ),
+            ValidationError::InvalidUsername(name) => write!(
+                f,
+                "username '{name}' may only contain letters, digits, '_', '-' and '.'"
+            ),
+            ValidationError::InvalidEmail { email, reason } => {
+                write!(f, "'{email}' is not an email: {reason}")
+            }
+            ValidationError::EmptyPassword => write!(f, "password cannot be empty"),
+            ValidationError::WeakPassword(e) => write!(f, "{e}"),
+            ValidationError::InvalidRole(role) => write!(f, "'{role}' is not a role"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl User {
+    // usernames and emails are case insensitive so they are stored lowercased.
+    // only for the fixtures and tests: their emails are written by hand, an invalid one is a
+    // bug and panics. anything typed or read goes through User::builder(), which returns
+    // every problem as a ValidationError instead.
+    pub fn new(username: &str, email: &str, password: &str, role: Role) -> User {
+        Self {
+            username: username.to_lowercase(),
+            email: EmailAddress::parse(email)
+                .unwrap_or_else(|e| panic!("invalid fixture email '{email}' for {username}: {e}")),
+            password: password::hash_password(password).into(),
+            role,
+        }
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        password::verify_password(password, self.password.expose())
+    }
+
+    // true while the stored password is still an old plaintext one
+    pub fn needs_password_upgrade(&self) -> bool {
+        !password::is_hashed(self.password.expose())
+    }
+
+    // checks the password and if it is correct but still stored as plaintext, hashes it.
+    // returns true only when something changed so the caller knows it has to save.
+    pub fn upgrade_password(&mut self, password: &str) -> bool {
+        if self.needs_password_upgrade() && self.check_password(password) {
+            self.password = password::hash_password(password).into();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password = password::hash_password(password).into();
+    }
+
+    // set_password for a password someone chose, it has to pass the policy first
+    pub fn change_password(
+        &mut self,
+        password: &str,
+        policy: &password_policy::PasswordPolicy,
+    ) -> Result<(), password_policy::PolicyError> {
+        policy.check(password, &self.username, self.email.as_str())?;
+        self.set_password(password);
+        Ok(())
+    }
+
+    // new skips the checks so lessons can build dummy data easily,
+    // anything coming from outside (files, prompts) goes through User::builder instead.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        builder::check_username(&self.username)?;
+        // the fields are pub, validate checks every one of them and the email too
+        if let Err(reason) = EmailAddress::parse(self.email.as_str()) {
+            return Err(ValidationError::InvalidEmail {
+                email: self.email.to_string(),
+                reason,
+            });
+        }
+        if self.password.expose().is_empty() {
+            return Err(ValidationError::EmptyPassword);
+        }
+        Ok(())
+    }
+}
+
+// dummy data shared by all the lessons
+pub fn get_users() -> Vec<User> {
+    vec![
+        User::new("admin", "admin@localhost", "password", Role::Admin),
+        User::new("user", "user@localhost", "password", Role::User),
+    ]
+}
+
+// same dummy data keyed by username
+pub fn get_default_users() -> HashMap<String, User> {
+    get_users()
+        .into_iter()
+        .map(|user| (user.username.clone(), user))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_lowercases_username_and_email() {
+        let user = User::new("Admin", "Admin@LocalHost", "password", Role::Admin);
+        assert_eq!(user.username, "admin");
+        assert_eq!(user.email, "admin@localhost");
+        assert!(user.check_password("password"));
+        assert!(!user.check_password("Password"));
+    }
+
+    #[test]
+    fn debug_does_not_print_the_password() {
+        let user = User::new("admin", "admin@localhost", "password", Role::Admin);
+        let printed = format!("{user:?}");
+        assert!(printed.contains("password: [REDACTED]"), "{printed}");
+        assert!(!printed.contains("pbkdf2"));
+        // the hash is still what gets saved
+        let json = serde_json::to_string(&user).unwrap();
+        assert!(json.contains("\"password\":\"$pbkdf2-sha256$"));
+    }
+
+    #[test]
+    fn fixtures_are_valid() {
+        let users = get_default_users();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["admin"].role, Role::Admin);
+        assert!(users.values().all(|user| user.validate().is_ok()));
+    }
+
+    #[test]
+    fn validate_rejects_bad_fields() {
+        let user = User::new("", "a@b", "x", Role::User);
+        assert_eq!(user.validate(), Err(ValidationError::EmptyUsername));
+        let user = User::new("a b", "a@b", "x", Role::User);
+        assert!(matches!(
+            user.validate(),
+            Err(ValidationError::InvalidUsername(_))
+        ));
+        let user = User {
+            password: String::new().into(),
+            ..User::new("user", "a@b", "x", Role::User)
+        };
+        assert_eq!(user.validate(), Err(ValidationError::EmptyPassword));
+        assert_eq!(User::new("user", "a@b", "x", Role::User).validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_users_cannot_be_loaded() {
+        // every problem is in the message, not just the first
+        let json = r#"{"username":"a b","email":"user","password":"","role":"user"}"#;
+        let error = serde_json::from_str::<User>(json).unwrap_err().to_string();
+        assert!(error.contains("username 'a b'"), "{error}");
+        assert!(error.contains("'user' is not an email"), "{error}");
+        assert!(error.contains("password cannot be empty"), "{error}");
+    }
+
+    #[test]
+    fn reads_the_old_users_json_layout() {
+        let json =
+            r#"{"username":"admin","email":"admin@localhost","password":"admin","role":"admin"}"#;
+        let user: User = serde_json::from_str(json).unwrap();
+        assert_eq!(user.role, Role::Admin);
+        assert!(user.check_password("admin"));
+    }
+
+    #[test]
+    fn plaintext_password_is_upgraded_on_login() {
+        let json =
+            r#"{"username":"admin","email":"admin@localhost","password":"admin","role":"admin"}"#;
+        let mut user: User = serde_json::from_str(json).unwrap();
+        assert!(user.needs_password_upgrade());
+        assert!(!user.upgrade_password("wrong"));
+        assert!(user.needs_password_upgrade());
+        assert!(user.upgrade_password("admin"));
+        assert!(!user.needs_password_upgrade());
+        assert!(user.check_password("admin"));
+        // already hashed, nothing left to do
+        assert!(!user.upgrade_password("admin"));
+    }
+
+    #[test]
+    fn change_password_goes_through_the_policy() {
+        let mut user = User::new("admin", "admin@localhost", "password", Role::Admin);
+        let policy = password_policy::PasswordPolicy::default();
+        let error = user.change_password("admin123", &policy).unwrap_err();
+        assert_eq!(error.violations.len(), 3);
+        assert!(user.check_password("password"));
+        user.change_password("four random words here", &policy)
+            .unwrap();
+        assert!(user.check_password("four random words here"));
+    }
+}
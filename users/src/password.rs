@@ -0,0 +1,85 @@
+// Password hashing.
+// Passwords are never stored as they are typed, only a salted and slow hash of them is.
+// - salted: every hash gets its own random salt so two users with the same password
+//   end up with different hashes and precomputed tables are useless.
+// - slow: PBKDF2 runs HMAC-SHA256 hundreds of thousands of times, guessing gets expensive.
+// The hash is stored as a PHC string, it carries the algorithm and its cost with it:
+// $pbkdf2-sha256$i=600000,l=32$<salt>$<hash>
+// so the cost can be raised later without breaking hashes that already exist.
+
+use password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use pbkdf2::{Algorithm, Params, Pbkdf2};
+use subtle::ConstantTimeEq;
+
+// OWASP recommendation for PBKDF2-HMAC-SHA256
+pub const DEFAULT_ROUNDS: u32 = 600_000;
+
+// every PHC string starts with $<algorithm id>
+const PHC_PREFIX: &str = "$pbkdf2-sha256$";
+
+pub fn hash_password(password: &str) -> String {
+    hash_password_with_rounds(password, DEFAULT_ROUNDS)
+}
+
+pub fn hash_password_with_rounds(password: &str, rounds: u32) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    let params = Params {
+        rounds,
+        ..Params::default()
+    };
+    // only fails on invalid params which we control
+    Pbkdf2
+        .hash_password_customized(
+            password.as_bytes(),
+            Some(Algorithm::Pbkdf2Sha256.ident()),
+            None,
+            params,
+            &salt,
+        )
+        .expect("valid pbkdf2 params")
+        .to_string()
+}
+
+// true if what is stored is a hash and not an old plaintext password
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with(PHC_PREFIX)
+}
+
+// verifies a password against what is stored.
+// stored is either a PHC hash or (for files written before hashing existed) the plaintext.
+// both comparisons are constant time so the time taken does not leak how much matched.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    if is_hashed(stored) {
+        match PasswordHash::new(stored) {
+            // verify_password compares the hashes in constant time
+            Ok(hash) => Pbkdf2.verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        password.as_bytes().ct_eq(stored.as_bytes()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_salted_phc_string() {
+        let first = hash_password_with_rounds("password", 1_000);
+        let second = hash_password_with_rounds("password", 1_000);
+        assert!(first.starts_with("$pbkdf2-sha256$i=1000,l=32$"));
+        assert!(is_hashed(&first));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn verifies_hashes_and_plaintext() {
+        let hash = hash_password_with_rounds("password", 1_000);
+        assert!(verify_password("password", &hash));
+        assert!(!verify_password("Password", &hash));
+        assert!(verify_password("admin", "admin"));
+        assert!(!verify_password("admin", "admin2"));
+        assert!(!verify_password("admin", "$pbkdf2-sha256$garbage"));
+    }
+}
@@ -0,0 +1,422 @@
+// What a password has to look like before it is accepted.
+//
+// User::new takes any password so the lessons can build dummy data, every password a person
+// chooses should go through a PasswordPolicy first (User::change_password does).
+// the policy checks every rule and returns all the broken ones at once, so the user can fix
+// everything in one go instead of finding out rule by rule.
+//
+// known breached passwords are checked offline against a file of SHA-1 hashes, the format
+// haveibeenpwned publishes them in. every line is 40 upper case hex digits and a newline,
+// sorted, so line i starts at byte 41 * i and the file can be binary searched with seek,
+// without reading millions of lines into memory. BreachedList::create writes such a file.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::UsersError;
+
+// 40 hex digits and '\n'
+const RECORD_LEN: u64 = 41;
+
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+pub struct BreachedList {
+    path: PathBuf,
+    // seek moves the file's cursor, so lookups take turns
+    file: Mutex<File>,
+    records: u64,
+}
+
+impl fmt::Debug for BreachedList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreachedList")
+            .field("path", &self.path)
+            .field("records", &self.records)
+            .finish()
+    }
+}
+
+impl BreachedList {
+    pub fn open(path: impl AsRef<Path>) -> Result<BreachedList, UsersError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| UsersError::io(path, e))?;
+        let len = file.metadata().map_err(|e| UsersError::io(path, e))?.len();
+        if len % RECORD_LEN != 0 {
+            return Err(UsersError::io(
+                path,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a breached password list, every line must be 40 hex digits",
+                ),
+            ));
+        }
+        Ok(BreachedList {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            records: len / RECORD_LEN,
+        })
+    }
+
+    // writes the hashes of passwords as a sorted list that open can read
+    pub fn create<'a>(
+        path: impl AsRef<Path>,
+        passwords: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), UsersError> {
+        let path = path.as_ref();
+        let mut hashes: Vec<String> = passwords.into_iter().map(sha1_hex).collect();
+        hashes.sort();
+        hashes.dedup();
+        let contents: String = hashes.iter().map(|hash| format!("{hash}\n")).collect();
+        File::create(path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| UsersError::io(path, e))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    fn record(file: &mut File, index: u64) -> io::Result<[u8; 40]> {
+        let mut record = [0u8; 40];
+        file.seek(SeekFrom::Start(index * RECORD_LEN))?;
+        file.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    pub fn contains(&self, password: &str) -> Result<bool, UsersError> {
+        let wanted = sha1_hex(password);
+        let mut file = self.file.lock().unwrap();
+        let (mut low, mut high) = (0, self.records);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let record = BreachedList::record(&mut file, middle)
+                .map_err(|e| UsersError::io(&self.path, e))?;
+            match record.as_slice().cmp(wanted.as_bytes()) {
+                std::cmp::Ordering::Equal => return Ok(true),
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    TooShort { min: usize, actual: usize },
+    NoLowercase,
+    NoUppercase,
+    NoDigit,
+    NoSymbol,
+    ContainsUsername,
+    ContainsEmail,
+    // the same character more than max times in a row
+    TooManyRepeats { max: usize, character: char },
+    Breached,
+    // the list could not be read, better to refuse than to let a breached password through
+    BreachCheckFailed(String),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::TooShort { min, actual } => {
+                write!(f, "must be at least {min} characters long, it is {actual}")
+            }
+            PolicyViolation::NoLowercase => write!(f, "must contain a lower case letter"),
+            PolicyViolation::NoUppercase => write!(f, "must contain an upper case letter"),
+            PolicyViolation::NoDigit => write!(f, "must contain a digit"),
+            PolicyViolation::NoSymbol => write!(f, "must contain a symbol"),
+            PolicyViolation::ContainsUsername => write!(f, "must not contain the username"),
+            PolicyViolation::ContainsEmail => write!(f, "must not contain the email address"),
+            PolicyViolation::TooManyRepeats { max, character } => write!(
+                f,
+                "must not repeat '{character}' more than {max} times in a row"
+            ),
+            PolicyViolation::Breached => {
+                write!(f, "has appeared in a data breach, choose another one")
+            }
+            PolicyViolation::BreachCheckFailed(message) => {
+                write!(
+                    f,
+                    "could not be checked against breached passwords: {message}"
+                )
+            }
+        }
+    }
+}
+
+// every rule the password broke, never empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyError {
+    pub violations: Vec<PolicyViolation>,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the password")?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            let separator = if i == 0 { " " } else { "; " };
+            write!(f, "{separator}{violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[derive(Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub forbid_user_details: bool,
+    pub max_repeat: Option<usize>,
+    pub breached: Option<BreachedList>,
+}
+
+// NIST 800-63B leans on length and breached lists rather than character classes,
+// the classes are there to be switched on
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 12,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            forbid_user_details: true,
+            max_repeat: Some(3),
+            breached: None,
+        }
+    }
+}
+
+// parts shorter than this ("a@b.io") would forbid too many passwords
+const MIN_DETAIL_LEN: usize = 3;
+
+impl PasswordPolicy {
+    pub fn with_breached_list(mut self, list: BreachedList) -> PasswordPolicy {
+        self.breached = Some(list);
+        self
+    }
+
+    pub fn check(&self, password: &str, username: &str, email: &str) -> Result<(), PolicyError> {
+        let mut violations = Vec::new();
+
+        // characters, not bytes
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(PolicyViolation::TooShort {
+                min: self.min_length,
+                actual: length,
+            });
+        }
+
+        let classes = [
+            (
+                self.require_lowercase,
+                PolicyViolation::NoLowercase,
+                char::is_lowercase as fn(char) -> bool,
+            ),
+            (
+                self.require_uppercase,
+                PolicyViolation::NoUppercase,
+                char::is_uppercase,
+            ),
+            (self.require_digit, PolicyViolation::NoDigit, |c: char| {
+                c.is_ascii_digit()
+            }),
+            (self.require_symbol, PolicyViolation::NoSymbol, |c: char| {
+                !c.is_alphanumeric() && !c.is_whitespace()
+            }),
+        ];
+        for (required, violation, matches) in classes {
+            if required && !password.chars().any(matches) {
+                violations.push(violation);
+            }
+        }
+
+        if self.forbid_user_details {
+            let lowered = password.to_lowercase();
+            let contains = |detail: &str| {
+                let detail = detail.to_lowercase();
+                detail.chars().count() >= MIN_DETAIL_LEN && lowered.contains(&detail)
+            };
+            if contains(username) {
+                violations.push(PolicyViolation::ContainsUsername);
+            }
+            let local = email.split_once('@').map_or(email, |(local, _)| local);
+            if contains(email) || contains(local) {
+                violations.push(PolicyViolation::ContainsEmail);
+            }
+        }
+
+        if let Some(max) = self.max_repeat {
+            if let Some(character) = longest_run_over(password, max) {
+                violations.push(PolicyViolation::TooManyRepeats { max, character });
+            }
+        }
+
+        if let Some(list) = &self.breached {
+            match list.contains(password) {
+                Ok(true) => violations.push(PolicyViolation::Breached),
+                Ok(false) => {}
+                Err(e) => violations.push(PolicyViolation::BreachCheckFailed(e.to_string())),
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyError { violations })
+        }
+    }
+}
+
+// the first character repeated more than max times in a row
+fn longest_run_over(password: &str, max: usize) -> Option<char> {
+    let mut previous = None;
+    let mut run = 0;
+    for c in password.chars() {
+        if Some(c) == previous {
+            run += 1;
+        } else {
+            previous = Some(c);
+            run = 1;
+        }
+        if run > max {
+            return Some(c);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 10,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..PasswordPolicy::default()
+        }
+    }
+
+    #[test]
+    fn every_broken_rule_is_reported() {
+        let error = strict()
+            .check("adminaaaa", "admin", "admin@localhost")
+            .unwrap_err();
+        assert_eq!(
+            error.violations,
+            [
+                PolicyViolation::TooShort { min: 10, actual: 9 },
+                PolicyViolation::NoUppercase,
+                PolicyViolation::NoDigit,
+                PolicyViolation::NoSymbol,
+                PolicyViolation::ContainsUsername,
+                PolicyViolation::ContainsEmail,
+                PolicyViolation::TooManyRepeats {
+                    max: 3,
+                    character: 'a'
+                },
+            ]
+        );
+        assert!(error
+            .to_string()
+            .starts_with("the password must be at least 10 characters long, it is 9; "));
+
+        strict()
+            .check("Correct-Horse-42", "admin", "admin@localhost")
+            .unwrap();
+    }
+
+    #[test]
+    fn user_details_are_matched_case_insensitively() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy
+                .check("my name is ALICE.smith!", "bob", "alice.smith@example.com")
+                .unwrap_err()
+                .violations,
+            [PolicyViolation::ContainsEmail]
+        );
+        // too short to mean anything
+        policy
+            .check("a perfectly long phrase", "a", "a@b.io")
+            .unwrap();
+        // length counts characters, not bytes
+        assert_eq!(
+            policy.check("ééééé", "", "").unwrap_err().violations[0],
+            PolicyViolation::TooShort { min: 12, actual: 5 }
+        );
+    }
+
+    #[test]
+    fn breached_passwords_are_found_by_binary_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("breached.txt");
+        let common = [
+            "password",
+            "123456",
+            "qwertyuiop123",
+            "iloveyou",
+            "correct horse battery staple",
+            "password",
+        ];
+        BreachedList::create(&path, common).unwrap();
+        let list = BreachedList::open(&path).unwrap();
+        assert_eq!(list.len(), 5);
+        for password in common {
+            assert!(list.contains(password).unwrap(), "{password}");
+        }
+        // the well known sha1 of "password"
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8\n"));
+        assert!(!list.contains("Password").unwrap());
+        assert!(!list.contains("").unwrap());
+
+        let policy = PasswordPolicy::default().with_breached_list(list);
+        assert_eq!(
+            policy
+                .check("correct horse battery staple", "admin", "admin@localhost")
+                .unwrap_err()
+                .violations,
+            [PolicyViolation::Breached]
+        );
+        policy
+            .check("correct horse battery stapler", "admin", "admin@localhost")
+            .unwrap();
+    }
+
+    #[test]
+    fn a_file_that_is_not_a_list_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("breached.txt");
+        std::fs::write(&path, "password\n").unwrap();
+        assert!(BreachedList::open(&path).is_err());
+    }
+}
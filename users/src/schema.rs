@@ -0,0 +1,250 @@
+// Versions of the users file and how to upgrade between them.
+//
+// version 1 is what serialize-deserialize started with, a bare json object of username -> user.
+// from version 2 on the users sit inside an envelope that says which version the file is:
+// { "schema_version": 3, "users": { "admin": { ... } } }
+//
+// files are upgraded by running every migration from the file's version up to CURRENT_VERSION
+// in order. the migrations work on plain serde_json::Value and not on User, an old file
+// might not even deserialize into today's User (that is why it needs migrating).
+// to change the format: bump CURRENT_VERSION and push a migration to MIGRATIONS.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub const CURRENT_VERSION: u32 = 3;
+
+// the on disk layout from version 2 on. generic so it can be written from a borrowed map
+// and read into an owned one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<U> {
+    pub schema_version: u32,
+    pub users: U,
+}
+
+pub struct Migration {
+    // upgrades a document from this version to from + 1
+    pub from: u32,
+    pub description: &'static str,
+    // changes the document in place and returns a line for every change it made
+    pub apply: fn(&mut Value) -> Result<Vec<String>, String>,
+}
+
+// ordered by from, one migration per version
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "wrap the users in a versioned envelope",
+        apply: wrap_in_envelope,
+    },
+    Migration {
+        from: 2,
+        description: "store roles as lowercase names",
+        apply: lowercase_roles,
+    },
+];
+
+fn wrap_in_envelope(document: &mut Value) -> Result<Vec<String>, String> {
+    let users = document.take();
+    if !users.is_object() {
+        return Err("expected a json object of users".to_string());
+    }
+    let count = users.as_object().map(Map::len).unwrap_or_default();
+    *document = serde_json::json!({ "schema_version": 2, "users": users });
+    Ok(vec![format!(
+        "wrapped {count} users in a schema_version envelope"
+    )])
+}
+
+// hand edited files tend to say "Admin", the Role enum only accepts "admin"
+fn lowercase_roles(document: &mut Value) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+    let users = users_mut(document)?;
+    for (username, user) in users.iter_mut() {
+        if let Some(Value::String(role)) = user.get_mut("role") {
+            let lower = role.to_lowercase();
+            if *role != lower {
+                changes.push(format!("{username}: role '{role}' -> '{lower}'"));
+                *role = lower;
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn users_mut(document: &mut Value) -> Result<&mut Map<String, Value>, String> {
+    document
+        .get_mut("users")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| "expected a users object in the envelope".to_string())
+}
+
+// version 1 files have no envelope, anything else says its version.
+// versions start at 1, an envelope saying 0 (or a number too big for u32) is broken and
+// must not be mistaken for version 1, it would be wrapped a second time.
+pub fn version_of(document: &Value) -> Result<u32, MigrationError> {
+    let (Some(version), Some(_)) = (document.get("schema_version"), document.get("users")) else {
+        return Ok(1);
+    };
+    match version.as_u64().map(u32::try_from) {
+        Some(Ok(version)) if version > 0 => Ok(version),
+        _ => Err(MigrationError::InvalidVersion(version.to_string())),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+    // written by a newer version of the program, we do not know how to read it
+    UnsupportedVersion { found: u32 },
+    // schema_version is not a version at all: 0, negative, not a number or too big
+    InvalidVersion(String),
+    Failed { from: u32, message: String },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::UnsupportedVersion { found } => write!(
+                f,
+                "schema version {found} is newer than the supported version {CURRENT_VERSION}"
+            ),
+            MigrationError::InvalidVersion(version) => {
+                write!(f, "{version} is not a valid schema version")
+            }
+            MigrationError::Failed { from, message } => {
+                write!(f, "migration from version {from} failed: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+// what migrate did (or with a dry run, would do)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    // (migration description, changes it made)
+    pub steps: Vec<(&'static str, Vec<String>)>,
+}
+
+impl MigrationReport {
+    pub fn is_up_to_date(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_up_to_date() {
+            return write!(f, "already at schema version {}", self.to_version);
+        }
+        writeln!(
+            f,
+            "schema version {} -> {}",
+            self.from_version, self.to_version
+        )?;
+        for (description, changes) in &self.steps {
+            writeln!(f, "- {description}")?;
+            for change in changes {
+                writeln!(f, "    {change}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// upgrades the document in place to CURRENT_VERSION.
+// a dry run works on a copy so the caller can look at the report and throw the result away.
+pub fn migrate(document: &mut Value) -> Result<MigrationReport, MigrationError> {
+    let from_version = version_of(document)?;
+    if from_version > CURRENT_VERSION {
+        return Err(MigrationError::UnsupportedVersion {
+            found: from_version,
+        });
+    }
+
+    let mut steps = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= from_version) {
+        let changes = (migration.apply)(document).map_err(|message| MigrationError::Failed {
+            from: migration.from,
+            message,
+        })?;
+        document["schema_version"] = Value::from(migration.from + 1);
+        steps.push((migration.description, changes));
+    }
+
+    Ok(MigrationReport {
+        from_version,
+        to_version: CURRENT_VERSION,
+        steps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrations_cover_every_version_in_order() {
+        let froms: Vec<u32> = MIGRATIONS.iter().map(|m| m.from).collect();
+        let expected: Vec<u32> = (1..CURRENT_VERSION).collect();
+        assert_eq!(froms, expected);
+    }
+
+    #[test]
+    fn bare_map_is_upgraded_to_current() {
+        let mut document = json!({
+            "admin": {"username": "admin", "email": "admin@localhost", "password": "admin", "role": "Admin"},
+            "user": {"username": "user", "email": "user@localhost", "password": "user", "role": "user"}
+        });
+        let report = migrate(&mut document).unwrap();
+        assert_eq!(report.from_version, 1);
+        assert_eq!(report.steps.len(), 2);
+        assert_eq!(report.steps[1].1, ["admin: role 'Admin' -> 'admin'"]);
+        assert_eq!(version_of(&document), Ok(CURRENT_VERSION));
+        assert_eq!(document["users"]["admin"]["role"], "admin");
+    }
+
+    #[test]
+    fn current_document_is_left_alone() {
+        let mut document = json!({"schema_version": CURRENT_VERSION, "users": {}});
+        let before = document.clone();
+        let report = migrate(&mut document).unwrap();
+        assert!(report.is_up_to_date());
+        assert_eq!(document, before);
+    }
+
+    #[test]
+    fn newer_document_is_refused() {
+        let mut document = json!({"schema_version": CURRENT_VERSION + 1, "users": {}});
+        assert_eq!(
+            migrate(&mut document),
+            Err(MigrationError::UnsupportedVersion {
+                found: CURRENT_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_versions_are_refused() {
+        for version in [
+            json!(0),
+            json!(u64::from(u32::MAX) + 1),
+            json!(-1),
+            json!("3"),
+        ] {
+            let mut document = json!({"schema_version": version, "users": {}});
+            let before = document.clone();
+            assert_eq!(
+                migrate(&mut document),
+                Err(MigrationError::InvalidVersion(version.to_string()))
+            );
+            assert_eq!(document, before);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// A value that must not leak: passwords, password hashes, passphrases.
+//
+// User derives Debug, so println!("{:?}", user) used to print the password hash for anyone
+// reading the terminal or the logs. Secret<T> prints [REDACTED] for {:?} and {} alike,
+// the value is only reachable through expose(), which is easy to spot in a review.
+// when a Secret is dropped its memory is overwritten with zeros (zeroize), so the value
+// does not linger in freed memory. (copies a String made while growing are out of reach.)
+//
+// serde: a Secret deserializes like the value inside it. it does not implement Serialize,
+// writing a secret somewhere has to be asked for on the field:
+//   #[serde(serialize_with = "secret::serialize_exposed")]
+// only for what is fine to store as it is, like the PHC hash of a password. a password
+// itself is never written, not even as a plain sha256: that is one lookup in a table of
+// common passwords away from the password.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+// compared in constant time, how long it takes says nothing about how much matched
+impl<T: Zeroize + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().ct_eq(other.0.as_ref()).into()
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Eq for Secret<T> {}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl<T: Zeroize> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl From<String> for Secret<String> {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+impl<'de, T: Zeroize + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Secret)
+    }
+}
+
+// for a secret that is fine to store as it is, a password hash is made to be stored
+pub fn serialize_exposed<T, S>(secret: &Secret<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Zeroize + Serialize,
+    S: Serializer,
+{
+    secret.expose().serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Login {
+        username: String,
+        #[serde(serialize_with = "serialize_exposed")]
+        hash: Secret<String>,
+    }
+
+    #[test]
+    fn never_printed() {
+        let secret = Secret::from("hunter2");
+        assert_eq!(format!("{secret}"), "[REDACTED]");
+        assert_eq!(format!("{secret:?}"), "[REDACTED]");
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(secret.clone(), secret);
+        assert_ne!(Secret::from("hunter3"), secret);
+    }
+
+    #[test]
+    fn serialized_only_when_asked() {
+        let json = r#"{"username":"admin","hash":"$pbkdf2-sha256$x"}"#;
+        let login: Login = serde_json::from_str(json).unwrap();
+        assert_eq!(login.hash.expose(), "$pbkdf2-sha256$x");
+        assert!(!format!("{login:?}").contains("pbkdf2"));
+        assert_eq!(serde_json::to_string(&login).unwrap(), json);
+    }
+
+    // records being zeroized, the memory of a dropped value cannot be looked at afterwards
+    struct Tracked(Arc<AtomicBool>);
+
+    impl Zeroize for Tracked {
+        fn zeroize(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn zeroized_on_drop() {
+        let zeroized = Arc::new(AtomicBool::new(false));
+        let secret = Secret::new(Tracked(Arc::clone(&zeroized)));
+        assert!(!zeroized.load(Ordering::SeqCst));
+        drop(secret);
+        assert!(zeroized.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn zeroize_clears_the_whole_buffer() {
+        let mut secret = Secret::from("hunter2");
+        // what drop does, run by hand so the memory can still be looked at
+        secret.0.zeroize();
+        let (ptr, capacity) = (secret.0.as_ptr(), secret.0.capacity());
+        // the string still owns its buffer, reading the whole of it is fine
+        let memory = unsafe { std::slice::from_raw_parts(ptr, capacity) };
+        assert!(capacity >= 7);
+        assert!(memory.iter().all(|&byte| byte == 0));
+    }
+}
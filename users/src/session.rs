@@ -0,0 +1,328 @@
+// Remembers who is logged in.
+//
+// a successful login gets a session: a random id the client sends back with every request.
+// the id is opaque, it is 32 random bytes and says nothing about the user, only the server
+// knows what it belongs to. a session ends when
+// - it is not used for idle_timeout (every use pushes that back, sliding renewal)
+// - absolute_timeout has passed since it was created, however busy it is
+// - it is revoked, e.g. on logout or when the password changes (revoke_all)
+//
+// SessionManager can be shared between threads (Arc<SessionManager>), the sessions sit
+// behind a RwLock: looking at them takes the read lock, creating, renewing and revoking
+// take the write lock.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use password_hash::rand_core::{OsRng, RngCore};
+
+use crate::clock::{Clock, SystemClock};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SessionId(String);
+
+impl SessionId {
+    fn generate() -> SessionId {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        SessionId(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    // the id as the client sent it back
+    pub fn from_string(id: impl Into<String>) -> SessionId {
+        SessionId(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// whoever has the id is logged in, it should not end up in logs through {:?}
+impl fmt::Debug for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // by chars, from_string takes whatever the client sent and that need not be ascii
+        let start: String = self.0.chars().take(8).collect();
+        write!(f, "SessionId({start}…)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    pub idle_timeout: Duration,
+    pub absolute_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout: Duration::from_secs(30 * 60),
+            absolute_timeout: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: SessionId,
+    pub username: String,
+    pub created_at: SystemTime,
+    pub last_seen: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    // never issued, revoked, or already cleaned up after expiring
+    NotFound,
+    IdleTimeout,
+    AbsoluteTimeout,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NotFound => write!(f, "no such session"),
+            SessionError::IdleTimeout => write!(f, "the session expired after being idle"),
+            SessionError::AbsoluteTimeout => write!(f, "the session reached its maximum age"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+pub struct SessionManager<C: Clock = SystemClock> {
+    config: SessionConfig,
+    clock: C,
+    sessions: RwLock<HashMap<SessionId, Session>>,
+}
+
+impl SessionManager {
+    pub fn new(config: SessionConfig) -> SessionManager {
+        SessionManager::with_clock(config, SystemClock)
+    }
+}
+
+impl<C: Clock> SessionManager<C> {
+    pub fn with_clock(config: SessionConfig, clock: C) -> SessionManager<C> {
+        SessionManager {
+            config,
+            clock,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // idle expiry, but never later than the absolute one
+    fn expiry(&self, created_at: SystemTime, last_seen: SystemTime) -> SystemTime {
+        (last_seen + self.config.idle_timeout).min(created_at + self.config.absolute_timeout)
+    }
+
+    fn check(&self, session: &Session, now: SystemTime) -> Result<(), SessionError> {
+        if now >= session.created_at + self.config.absolute_timeout {
+            Err(SessionError::AbsoluteTimeout)
+        } else if now >= session.last_seen + self.config.idle_timeout {
+            Err(SessionError::IdleTimeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn create(&self, username: &str) -> Session {
+        let now = self.clock.now();
+        let session = Session {
+            id: SessionId::generate(),
+            username: username.to_string(),
+            created_at: now,
+            last_seen: now,
+            expires_at: self.expiry(now, now),
+        };
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id.clone(), session.clone());
+        session
+    }
+
+    // checks the session and renews it, this is what every request should call.
+    // an expired session is removed on the way.
+    pub fn touch(&self, id: &SessionId) -> Result<Session, SessionError> {
+        let now = self.clock.now();
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.get_mut(id).ok_or(SessionError::NotFound)?;
+        if let Err(e) = self.check(session, now) {
+            sessions.remove(id);
+            return Err(e);
+        }
+        session.last_seen = now;
+        session.expires_at = self.expiry(session.created_at, now);
+        Ok(session.clone())
+    }
+
+    // checks the session without renewing it, only takes the read lock
+    pub fn get(&self, id: &SessionId) -> Result<Session, SessionError> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(id).ok_or(SessionError::NotFound)?;
+        self.check(session, self.clock.now())?;
+        Ok(session.clone())
+    }
+
+    // the live sessions of one user, oldest first
+    pub fn list(&self, username: &str) -> Vec<Session> {
+        let now = self.clock.now();
+        let mut sessions: Vec<Session> = self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|session| session.username == username && self.check(session, now).is_ok())
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    // true if there was such a session
+    pub fn revoke(&self, id: &SessionId) -> bool {
+        self.sessions.write().unwrap().remove(id).is_some()
+    }
+
+    // logs the user out everywhere, returns how many sessions were ended
+    pub fn revoke_all(&self, username: &str) -> usize {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.username != username);
+        before - sessions.len()
+    }
+
+    // expired sessions are only removed when touched, this clears out the rest
+    pub fn purge_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| self.check(session, now).is_ok());
+        before - sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn manager() -> (Arc<ManualClock>, SessionManager<Arc<ManualClock>>) {
+        let clock = Arc::new(ManualClock::at_unix(1_700_000_000));
+        let config = SessionConfig {
+            idle_timeout: 10 * MINUTE,
+            absolute_timeout: 60 * MINUTE,
+        };
+        (clock.clone(), SessionManager::with_clock(config, clock))
+    }
+
+    #[test]
+    fn ids_are_random_and_opaque() {
+        let (_, sessions) = manager();
+        let a = sessions.create("admin");
+        let b = sessions.create("admin");
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.id.as_str().len(), 64);
+        assert!(!a.id.as_str().contains("admin"));
+        assert!(!format!("{:?}", a.id).contains(a.id.as_str()));
+        assert_eq!(
+            sessions.get(&SessionId::from_string(a.id.to_string())),
+            Ok(a)
+        );
+        assert_eq!(
+            format!("{:?}", SessionId::from_string("ééééééééé")),
+            "SessionId(éééééééé…)"
+        );
+    }
+
+    #[test]
+    fn use_slides_the_idle_expiry_up_to_the_absolute_one() {
+        let (clock, sessions) = manager();
+        let session = sessions.create("admin");
+
+        // used every 9 minutes it stays alive past the idle timeout...
+        for _ in 0..6 {
+            clock.advance(9 * MINUTE);
+            sessions.touch(&session.id).unwrap();
+        }
+        // ...but not past the absolute one (54 + 9 > 60)
+        let renewed = sessions.get(&session.id).unwrap();
+        assert_eq!(renewed.expires_at, session.created_at + 60 * MINUTE);
+        clock.advance(9 * MINUTE);
+        assert_eq!(
+            sessions.touch(&session.id),
+            Err(SessionError::AbsoluteTimeout)
+        );
+        // and it is gone
+        assert_eq!(sessions.get(&session.id), Err(SessionError::NotFound));
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let (clock, sessions) = manager();
+        let session = sessions.create("admin");
+        clock.advance(9 * MINUTE);
+        // get does not renew
+        sessions.get(&session.id).unwrap();
+        clock.advance(MINUTE);
+        assert_eq!(sessions.get(&session.id), Err(SessionError::IdleTimeout));
+        assert!(sessions.list("admin").is_empty());
+        assert_eq!(sessions.purge_expired(), 1);
+    }
+
+    #[test]
+    fn sessions_are_listed_and_revoked_per_user() {
+        let (clock, sessions) = manager();
+        let first = sessions.create("admin");
+        clock.advance(MINUTE);
+        let second = sessions.create("admin");
+        let other = sessions.create("user");
+
+        let listed: Vec<SessionId> = sessions.list("admin").into_iter().map(|s| s.id).collect();
+        assert_eq!(listed, [first.id.clone(), second.id.clone()]);
+
+        assert!(sessions.revoke(&first.id));
+        assert!(!sessions.revoke(&first.id));
+        assert_eq!(sessions.touch(&first.id), Err(SessionError::NotFound));
+
+        assert_eq!(sessions.revoke_all("admin"), 1);
+        assert!(sessions.list("admin").is_empty());
+        assert!(sessions.get(&other.id).is_ok());
+    }
+
+    #[test]
+    fn can_be_shared_between_threads() {
+        let sessions = Arc::new(SessionManager::new(SessionConfig::default()));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let sessions = Arc::clone(&sessions);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        let session = sessions.create(&format!("user{}", i % 2));
+                        sessions.touch(&session.id).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(sessions.list("user0").len(), 200);
+        assert_eq!(sessions.revoke_all("user1"), 200);
+    }
+}
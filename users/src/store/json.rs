@@ -0,0 +1,84 @@
+// The users.json file from the serialize-deserialize lesson behind the UserStore trait.
+// the file is a json object of username -> user.
+// every call reads the file and every change is a locked read-modify-write (file::modify_users),
+// so several stores (or processes) pointing at the same file see each others changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use super::{StoreError, UserStore};
+use crate::{file, User};
+
+#[derive(Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+}
+
+impl JsonFileStore {
+    // a missing file is an empty store, the file is created on the first change
+    pub fn open(path: impl AsRef<Path>) -> Result<JsonFileStore, StoreError> {
+        Ok(JsonFileStore {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    // what serialize-deserialize always did: a missing file is created with the dummy users
+    pub fn open_with_defaults(path: impl AsRef<Path>) -> Result<JsonFileStore, StoreError> {
+        let store = Self::open(path)?;
+        file::load_or_create(&store.path)?;
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<HashMap<String, User>, StoreError> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        Ok(file::load_users(&self.path)?)
+    }
+}
+
+impl UserStore for JsonFileStore {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.read()?.remove(username))
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        file::modify_users(&self.path, |users| {
+            if users.contains_key(&user.username) {
+                return Err(StoreError::AlreadyExists(user.username));
+            }
+            users.insert(user.username.clone(), user);
+            Ok(())
+        })
+    }
+
+    fn update(&mut self, user: User) -> Result<(), StoreError> {
+        file::modify_users(&self.path, |users| match users.get_mut(&user.username) {
+            Some(existing) => {
+                *existing = user;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound(user.username)),
+        })
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        file::modify_users(&self.path, |users| {
+            users
+                .remove(username)
+                .ok_or_else(|| StoreError::NotFound(username.to_string()))
+        })
+    }
+
+    fn list(&self) -> Result<Vec<User>, StoreError> {
+        let mut users: Vec<User> = self.read()?.into_values().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use super::{StoreError, UserStore};
+use crate::User;
+
+// the hashmaps lesson, behind the UserStore trait. nothing survives the process.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    users: HashMap<String, User>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Self::default()
+    }
+}
+
+// lets a store be built straight from get_default_users()
+impl From<HashMap<String, User>> for MemoryStore {
+    fn from(users: HashMap<String, User>) -> Self {
+        MemoryStore { users }
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.users.get(username).cloned())
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        if self.users.contains_key(&user.username) {
+            return Err(StoreError::AlreadyExists(user.username));
+        }
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    fn update(&mut self, user: User) -> Result<(), StoreError> {
+        match self.users.get_mut(&user.username) {
+            Some(existing) => {
+                *existing = user;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound(user.username)),
+        }
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        self.users
+            .remove(username)
+            .ok_or_else(|| StoreError::NotFound(username.to_string()))
+    }
+
+    fn list(&self) -> Result<Vec<User>, StoreError> {
+        let mut users: Vec<User> = self.users.values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+}
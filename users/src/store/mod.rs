@@ -0,0 +1,192 @@
+// Where users are kept.
+// UserStore is a trait, code that needs users only talks to the trait
+// so the storage (memory, a json file, an encrypted file, sqlite, the journal) can be swapped without touching that code.
+// e.g. let store: Box<dyn UserStore> = Box::new(MemoryStore::new());
+
+use std::fmt;
+
+use crate::{encrypted::EncryptionError, journal::JournalError, User, UsersError};
+
+mod json;
+mod memory;
+mod sqlite;
+
+pub use json::JsonFileStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Debug)]
+pub enum StoreError {
+    AlreadyExists(String),
+    NotFound(String),
+    File(UsersError),
+    Sqlite(rusqlite::Error),
+    Journal(JournalError),
+    Encryption(EncryptionError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::AlreadyExists(username) => write!(f, "user '{username}' already exists"),
+            StoreError::NotFound(username) => write!(f, "user '{username}' not found"),
+            StoreError::File(e) => write!(f, "{e}"),
+            StoreError::Sqlite(e) => write!(f, "sqlite error: {e}"),
+            StoreError::Journal(e) => write!(f, "{e}"),
+            StoreError::Encryption(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::File(e) => Some(e),
+            StoreError::Sqlite(e) => Some(e),
+            StoreError::Journal(e) => Some(e),
+            StoreError::Encryption(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// From lets the ? operator convert the library errors into ours
+impl From<UsersError> for StoreError {
+    fn from(e: UsersError) -> Self {
+        StoreError::File(e)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+impl From<JournalError> for StoreError {
+    fn from(e: JournalError) -> Self {
+        StoreError::Journal(e)
+    }
+}
+
+impl From<EncryptionError> for StoreError {
+    fn from(e: EncryptionError) -> Self {
+        StoreError::Encryption(e)
+    }
+}
+
+// users are identified by their username everywhere.
+pub trait UserStore {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError>;
+
+    // fails with AlreadyExists if the username is taken
+    fn insert(&mut self, user: User) -> Result<(), StoreError>;
+
+    // replaces the user with the same username, fails with NotFound if there is none
+    fn update(&mut self, user: User) -> Result<(), StoreError>;
+
+    // returns the removed user, fails with NotFound if there is none
+    fn delete(&mut self, username: &str) -> Result<User, StoreError>;
+
+    // every user, sorted by username so all stores list in the same order
+    fn list(&self) -> Result<Vec<User>, StoreError>;
+}
+
+// every store has to pass the same tests, they are written once against the trait.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_users, Role};
+
+    fn conformance(store: &mut dyn UserStore) {
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.get("admin").unwrap().is_none());
+
+        for user in get_users() {
+            store.insert(user).unwrap();
+        }
+        let usernames: Vec<String> = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        assert_eq!(usernames, ["admin", "user"]);
+
+        let admin = store.get("admin").unwrap().unwrap();
+        assert_eq!(admin.role, Role::Admin);
+        assert!(admin.check_password("password"));
+
+        assert!(matches!(
+            store.insert(admin.clone()),
+            Err(StoreError::AlreadyExists(name)) if name == "admin"
+        ));
+
+        let mut user = store.get("user").unwrap().unwrap();
+        user.role = Role::Admin;
+        user.email = "someone@localhost".parse().unwrap();
+        store.update(user.clone()).unwrap();
+        assert_eq!(store.get("user").unwrap(), Some(user));
+
+        let ghost = User::new("ghost", "ghost@localhost", "password", Role::User);
+        assert!(matches!(
+            store.update(ghost),
+            Err(StoreError::NotFound(name)) if name == "ghost"
+        ));
+
+        assert_eq!(store.delete("admin").unwrap(), admin);
+        assert!(store.get("admin").unwrap().is_none());
+        assert!(matches!(
+            store.delete("admin"),
+            Err(StoreError::NotFound(_))
+        ));
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn memory_store_conforms() {
+        conformance(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn json_file_store_conforms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        conformance(&mut JsonFileStore::open(&path).unwrap());
+        // everything went to the file, a new store sees the same users
+        let reopened = JsonFileStore::open(&path).unwrap();
+        assert_eq!(reopened.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sqlite_store_conforms() {
+        conformance(&mut SqliteStore::open_in_memory().unwrap());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.db");
+        conformance(&mut SqliteStore::open(&path).unwrap());
+        let reopened = SqliteStore::open(&path).unwrap();
+        assert_eq!(reopened.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn journal_conforms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        conformance(&mut crate::journal::Journal::open(&path).unwrap());
+        let reopened = crate::journal::Journal::open(&path).unwrap();
+        assert_eq!(reopened.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn encrypted_file_store_conforms() {
+        use crate::encrypted::{EncryptedFileStore, KeySource};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let key = KeySource::Passphrase("passphrase".into());
+        conformance(&mut EncryptedFileStore::open(&path, &key).unwrap());
+        let reopened = EncryptedFileStore::open(&path, &key).unwrap();
+        assert_eq!(reopened.list().unwrap().len(), 1);
+    }
+}
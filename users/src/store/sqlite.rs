@@ -0,0 +1,117 @@
+// An embedded sqlite database, one row per user.
+// sqlite is compiled into the binary (bundled feature) so nothing has to be installed.
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{StoreError, UserStore};
+use crate::User;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    email TEXT NOT NULL,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL
+)";
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteStore, StoreError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore, StoreError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<SqliteStore, StoreError> {
+        conn.execute(SCHEMA, [])?;
+        Ok(SqliteStore { conn })
+    }
+}
+
+// the role column holds the same text the json file does.
+// the row goes through UserBuilder like a deserialized user, a hand edited row that is
+// not a valid user fails to load (reported against the first column, it is the row that is wrong)
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    User::builder()
+        .username(row.get::<_, String>(0)?)
+        .email(row.get::<_, String>(1)?)
+        .password_hash(row.get::<_, String>(2)?)
+        .role_name(row.get::<_, String>(3)?)
+        .build()
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })
+}
+
+impl UserStore for SqliteStore {
+    fn get(&self, username: &str) -> Result<Option<User>, StoreError> {
+        let user = self
+            .conn
+            .query_row(
+                "SELECT username, email, password, role FROM users WHERE username = ?1",
+                params![username],
+                user_from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), StoreError> {
+        // OR IGNORE turns the primary key clash into "0 rows changed"
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO users (username, email, password, role) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                user.username,
+                user.email.as_str(),
+                user.password.expose(),
+                user.role.to_string()
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(StoreError::AlreadyExists(user.username));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, user: User) -> Result<(), StoreError> {
+        let updated = self.conn.execute(
+            "UPDATE users SET email = ?2, password = ?3, role = ?4 WHERE username = ?1",
+            params![
+                user.username,
+                user.email.as_str(),
+                user.password.expose(),
+                user.role.to_string()
+            ],
+        )?;
+        if updated == 0 {
+            return Err(StoreError::NotFound(user.username));
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, username: &str) -> Result<User, StoreError> {
+        let user = self
+            .get(username)?
+            .ok_or_else(|| StoreError::NotFound(username.to_string()))?;
+        self.conn
+            .execute("DELETE FROM users WHERE username = ?1", params![username])?;
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, StoreError> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT username, email, password, role FROM users ORDER BY username")?;
+        let users = statement
+            .query_map([], user_from_row)?
+            .collect::<Result<Vec<User>, _>>()?;
+        Ok(users)
+    }
+}
@@ -0,0 +1,558 @@
+// Brute force protection for logins.
+//
+// check_password answers as often as it is asked, so without this a script can try a whole
+// dictionary against one account. two things slow it down:
+// - per username: every failure doubles the wait before the next try (exponential backoff)
+//   and after max_failures failures in a row the account is locked. the lock goes away by
+//   itself after lockout, or only when an admin unlocks it if lockout is None.
+// - per source (an ip address, a terminal...): a token bucket. every attempt takes a token,
+//   tokens come back one per refill_every, so one source cannot spray many usernames either.
+// a successful login clears the username's failures, so does forget_after without a failure.
+// every username and source tried is remembered, also made up ones, so the maps are pruned:
+// an account whose failures are forgotten or whose lock has run out and a source whose
+// bucket is full again are the same as no entry at all and are dropped. pruning walks the
+// whole map, it only happens when the map has doubled since the last time.
+//
+// LoginGuard is shared between threads like SessionManager. the state of the accounts and
+// sources sits behind a Mutex (see atomics_and_locks), the totals are atomics because they
+// are plain numbers only ever added to.
+// an attempt is counted as a failure before the password is checked and taken back when it
+// was right, so 10 threads guessing at once cannot squeeze in more guesses than one thread.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::clock::{Clock, SystemClock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    // failures in a row before the account is locked
+    pub max_failures: u32,
+    // how long a lock lasts, None means until an admin unlocks it
+    pub lockout: Option<Duration>,
+    // the wait after the first failure, doubled with every further one up to max_delay
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // failures (not locks) are forgotten after this long without another one
+    pub forget_after: Duration,
+    // attempts a source can make in a burst, and how fast they come back
+    pub source_capacity: u32,
+    pub source_refill_every: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_failures: 5,
+            lockout: Some(Duration::from_secs(15 * 60)),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            forget_after: Duration::from_secs(24 * 60 * 60),
+            source_capacity: 20,
+            source_refill_every: Duration::from_secs(6),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    // until None means only an admin can unlock it
+    Locked { until: Option<SystemTime> },
+    TooSoon { retry_after: Duration },
+    SourceLimited { retry_after: Duration },
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Throttled::Locked { until: Some(_) } => {
+                write!(f, "the account is locked after too many failed logins")
+            }
+            Throttled::Locked { until: None } => write!(
+                f,
+                "the account is locked after too many failed logins, ask an admin to unlock it"
+            ),
+            Throttled::TooSoon { retry_after } => {
+                write!(
+                    f,
+                    "too many failed logins, try again in {}s",
+                    retry_after.as_secs().max(1)
+                )
+            }
+            Throttled::SourceLimited { retry_after } => write!(
+                f,
+                "too many login attempts from here, try again in {}s",
+                retry_after.as_secs().max(1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginError {
+    Throttled(Throttled),
+    // deliberately does not say whether it was the username or the password
+    WrongCredentials,
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::Throttled(e) => write!(f, "{e}"),
+            LoginError::WrongCredentials => write!(f, "wrong username or password"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl From<Throttled> for LoginError {
+    fn from(e: Throttled) -> Self {
+        LoginError::Throttled(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleStats {
+    pub failures: u64,
+    pub lockouts: u64,
+}
+
+#[derive(Debug)]
+struct Account {
+    failures: u32,
+    last_failure: SystemTime,
+    locked: bool,
+    locked_until: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+}
+
+// maps smaller than this are never pruned
+const PRUNE_AT_LEAST: usize = 1024;
+
+// drops what keep says no to once the map has grown to prune_at, then waits for it to double
+fn prune<T>(
+    map: &mut HashMap<String, T>,
+    prune_at: &AtomicUsize,
+    mut keep: impl FnMut(&T) -> bool,
+) {
+    // only touched with the map's lock held, the lock orders it
+    if map.len() < prune_at.load(Ordering::Relaxed) {
+        return;
+    }
+    map.retain(|_, value| keep(value));
+    prune_at.store((map.len() * 2).max(PRUNE_AT_LEAST), Ordering::Relaxed);
+}
+
+pub struct LoginGuard<C: Clock = SystemClock> {
+    config: ThrottleConfig,
+    clock: C,
+    accounts: Mutex<HashMap<String, Account>>,
+    sources: Mutex<HashMap<String, Bucket>>,
+    prune_accounts_at: AtomicUsize,
+    prune_sources_at: AtomicUsize,
+    failures: AtomicU64,
+    lockouts: AtomicU64,
+}
+
+impl LoginGuard {
+    pub fn new(config: ThrottleConfig) -> LoginGuard {
+        LoginGuard::with_clock(config, SystemClock)
+    }
+}
+
+impl<C: Clock> LoginGuard<C> {
+    pub fn with_clock(config: ThrottleConfig, clock: C) -> LoginGuard<C> {
+        LoginGuard {
+            config,
+            clock,
+            accounts: Mutex::new(HashMap::new()),
+            sources: Mutex::new(HashMap::new()),
+            prune_accounts_at: AtomicUsize::new(PRUNE_AT_LEAST),
+            prune_sources_at: AtomicUsize::new(PRUNE_AT_LEAST),
+            failures: AtomicU64::new(0),
+            lockouts: AtomicU64::new(0),
+        }
+    }
+
+    // base_delay * 2^(failures - 1), without overflowing for large counts
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.config
+            .base_delay
+            .saturating_mul(1 << doublings)
+            .min(self.config.max_delay)
+    }
+
+    // true once an account no longer slows anyone down, forgetting it changes nothing
+    fn is_idle(&self, account: &Account, now: SystemTime) -> bool {
+        if account.locked {
+            account.locked_until.is_some_and(|until| now >= until)
+        } else {
+            now >= account.last_failure + self.config.forget_after
+        }
+    }
+
+    // true once the bucket has refilled, a new one would be full too
+    fn is_full(&self, bucket: &Bucket, now: SystemTime) -> bool {
+        let missing = f64::from(self.config.source_capacity) - bucket.tokens;
+        let elapsed = now.duration_since(bucket.updated).unwrap_or_default();
+        elapsed.as_secs_f64() >= missing * self.config.source_refill_every.as_secs_f64()
+    }
+
+    // takes a token from the source's bucket
+    fn take_token(&self, source: &str, now: SystemTime) -> Result<(), Throttled> {
+        let capacity = f64::from(self.config.source_capacity);
+        let refill = self.config.source_refill_every.as_secs_f64();
+        let mut sources = self.sources.lock().unwrap();
+        prune(&mut sources, &self.prune_sources_at, |bucket| {
+            !self.is_full(bucket, now)
+        });
+        let bucket = sources.entry(source.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now
+            .duration_since(bucket.updated)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if refill > 0.0 {
+            bucket.tokens = (bucket.tokens + elapsed / refill).min(capacity);
+        } else {
+            bucket.tokens = capacity;
+        }
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let retry_after = Duration::from_secs_f64((1.0 - bucket.tokens) * refill);
+            return Err(Throttled::SourceLimited { retry_after });
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    // checks the account and counts the attempt as a failure up front
+    fn begin(&self, username: &str, now: SystemTime) -> Result<(), Throttled> {
+        let mut accounts = self.accounts.lock().unwrap();
+        prune(&mut accounts, &self.prune_accounts_at, |account| {
+            !self.is_idle(account, now)
+        });
+        if let Some(account) = accounts.get(username) {
+            if self.is_idle(account, now) {
+                accounts.remove(username);
+            } else if account.locked {
+                return Err(Throttled::Locked {
+                    until: account.locked_until,
+                });
+            } else {
+                let allowed_at = account.last_failure + self.delay(account.failures);
+                if now < allowed_at {
+                    let retry_after = allowed_at.duration_since(now).unwrap_or_default();
+                    return Err(Throttled::TooSoon { retry_after });
+                }
+            }
+        }
+        let account = accounts.entry(username.to_string()).or_insert(Account {
+            failures: 0,
+            last_failure: now,
+            locked: false,
+            locked_until: None,
+        });
+        account.failures += 1;
+        account.last_failure = now;
+        if account.failures >= self.config.max_failures {
+            account.locked = true;
+            account.locked_until = self.config.lockout.map(|lockout| now + lockout);
+        }
+        Ok(())
+    }
+
+    // one login attempt: throttling first, then verify (e.g. || user.check_password(password)).
+    // unknown usernames go through here too so they cannot be told apart by the throttling.
+    pub fn attempt(
+        &self,
+        username: &str,
+        source: &str,
+        verify: impl FnOnce() -> bool,
+    ) -> Result<(), LoginError> {
+        let username = username.to_lowercase();
+        let now = self.clock.now();
+        self.take_token(source, now)?;
+        self.begin(&username, now)?;
+        if verify() {
+            // the failure counted in begin was not one
+            self.accounts.lock().unwrap().remove(&username);
+            Ok(())
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            let locked = self
+                .accounts
+                .lock()
+                .unwrap()
+                .get(&username)
+                .is_some_and(|account| {
+                    account.locked && account.failures == self.config.max_failures
+                });
+            if locked {
+                self.lockouts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(LoginError::WrongCredentials)
+        }
+    }
+
+    pub fn is_locked(&self, username: &str) -> bool {
+        let now = self.clock.now();
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(&username.to_lowercase())
+            .is_some_and(|account| {
+                account.locked && account.locked_until.is_none_or(|until| now < until)
+            })
+    }
+
+    // failed attempts in a row
+    pub fn failures(&self, username: &str) -> u32 {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(&username.to_lowercase())
+            .map_or(0, |account| account.failures)
+    }
+
+    // the admin unlock, also forgets the failures. true if there was anything to forget
+    pub fn unlock(&self, username: &str) -> bool {
+        self.accounts
+            .lock()
+            .unwrap()
+            .remove(&username.to_lowercase())
+            .is_some()
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        ThrottleStats {
+            failures: self.failures.load(Ordering::Relaxed),
+            lockouts: self.lockouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn guard(config: ThrottleConfig) -> (Arc<ManualClock>, LoginGuard<Arc<ManualClock>>) {
+        let clock = Arc::new(ManualClock::at_unix(1_700_000_000));
+        (clock.clone(), LoginGuard::with_clock(config, clock))
+    }
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            max_failures: 4,
+            lockout: Some(Duration::from_secs(600)),
+            base_delay: SECOND,
+            max_delay: Duration::from_secs(60),
+            forget_after: Duration::from_secs(3600),
+            source_capacity: 100,
+            source_refill_every: SECOND,
+        }
+    }
+
+    #[test]
+    fn failures_double_the_wait() {
+        let (clock, guard) = guard(config());
+        assert_eq!(
+            guard.attempt("admin", "a", || false),
+            Err(LoginError::WrongCredentials)
+        );
+        assert_eq!(
+            guard.attempt("admin", "a", || true),
+            Err(LoginError::Throttled(Throttled::TooSoon {
+                retry_after: SECOND
+            }))
+        );
+        clock.advance(SECOND);
+        guard.attempt("admin", "a", || false).unwrap_err();
+        clock.advance(SECOND);
+        assert_eq!(
+            guard.attempt("Admin", "a", || false),
+            Err(LoginError::Throttled(Throttled::TooSoon {
+                retry_after: SECOND
+            }))
+        );
+        clock.advance(SECOND);
+        // the right password clears the failures
+        guard.attempt("admin", "a", || true).unwrap();
+        assert_eq!(guard.failures("admin"), 0);
+        guard.attempt("admin", "a", || true).unwrap();
+    }
+
+    #[test]
+    fn locks_after_max_failures_and_unlocks_by_itself() {
+        let (clock, guard) = guard(config());
+        for _ in 0..4 {
+            guard.attempt("admin", "a", || false).unwrap_err();
+            clock.advance(Duration::from_secs(60));
+        }
+        assert!(guard.is_locked("admin"));
+        // even the right password does not get in
+        assert!(matches!(
+            guard.attempt("admin", "a", || true),
+            Err(LoginError::Throttled(Throttled::Locked { until: Some(_) }))
+        ));
+        assert_eq!(
+            guard.stats(),
+            ThrottleStats {
+                failures: 4,
+                lockouts: 1
+            }
+        );
+        // other accounts are not affected
+        guard.attempt("user", "a", || true).unwrap();
+
+        clock.advance(Duration::from_secs(600));
+        assert!(!guard.is_locked("admin"));
+        guard.attempt("admin", "a", || true).unwrap();
+    }
+
+    #[test]
+    fn without_a_lockout_only_an_admin_unlocks() {
+        let (clock, guard) = guard(ThrottleConfig {
+            lockout: None,
+            ..config()
+        });
+        for _ in 0..4 {
+            guard.attempt("admin", "a", || false).unwrap_err();
+            clock.advance(Duration::from_secs(60));
+        }
+        clock.advance(Duration::from_secs(365 * 24 * 60 * 60));
+        assert_eq!(
+            guard.attempt("admin", "a", || true),
+            Err(LoginError::Throttled(Throttled::Locked { until: None }))
+        );
+        assert!(guard.unlock("admin"));
+        guard.attempt("admin", "a", || true).unwrap();
+        assert!(!guard.unlock("admin"));
+    }
+
+    #[test]
+    fn sources_are_rate_limited_across_usernames() {
+        let (clock, guard) = guard(ThrottleConfig {
+            source_capacity: 3,
+            ..config()
+        });
+        for name in ["a", "b", "c"] {
+            guard.attempt(name, "10.0.0.1", || false).unwrap_err();
+        }
+        assert_eq!(
+            guard.attempt("d", "10.0.0.1", || true),
+            Err(LoginError::Throttled(Throttled::SourceLimited {
+                retry_after: SECOND
+            }))
+        );
+        // another source still has its tokens
+        guard.attempt("d", "10.0.0.2", || true).unwrap();
+        clock.advance(SECOND);
+        guard.attempt("d", "10.0.0.1", || true).unwrap();
+        guard.attempt("e", "10.0.0.1", || true).unwrap_err();
+    }
+
+    #[test]
+    fn forgotten_failures_and_full_buckets_are_pruned() {
+        let (clock, guard) = guard(config());
+        guard.attempt("admin", "a", || false).unwrap_err();
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(guard.failures("admin"), 1);
+        // forget_after has passed, the next attempt starts from scratch
+        guard.attempt("admin", "a", || false).unwrap_err();
+        assert_eq!(guard.failures("admin"), 1);
+
+        // a script spraying made up usernames from made up addresses. one short of
+        // PRUNE_AT_LEAST entries, so the attempt after it is the one that prunes
+        for i in 1..PRUNE_AT_LEAST {
+            let source = format!("10.0.{}.{}", i / 256, i % 256);
+            guard
+                .attempt(&format!("user{i}"), &source, || false)
+                .unwrap_err();
+        }
+        assert_eq!(guard.accounts.lock().unwrap().len(), PRUNE_AT_LEAST);
+        assert_eq!(guard.sources.lock().unwrap().len(), PRUNE_AT_LEAST);
+
+        clock.advance(Duration::from_secs(3600));
+        guard.attempt("someone", "b", || false).unwrap_err();
+        assert_eq!(guard.accounts.lock().unwrap().len(), 1);
+        assert_eq!(guard.sources.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn locks_without_an_end_are_never_pruned() {
+        let (clock, guard) = guard(ThrottleConfig {
+            lockout: None,
+            ..config()
+        });
+        for _ in 0..4 {
+            guard.attempt("admin", "a", || false).unwrap_err();
+            clock.advance(Duration::from_secs(60));
+        }
+        for i in 1..PRUNE_AT_LEAST {
+            let source = format!("10.0.{}.{}", i / 256, i % 256);
+            guard
+                .attempt(&format!("user{i}"), &source, || false)
+                .unwrap_err();
+        }
+        assert_eq!(guard.accounts.lock().unwrap().len(), PRUNE_AT_LEAST);
+        clock.advance(Duration::from_secs(365 * 24 * 60 * 60));
+        guard.attempt("someone", "b", || true).unwrap();
+        assert_eq!(guard.accounts.lock().unwrap().len(), 1);
+        assert!(guard.is_locked("admin"));
+    }
+
+    #[test]
+    fn concurrent_guesses_cannot_exceed_the_limit() {
+        let (_, guard) = guard(ThrottleConfig {
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            source_capacity: 1000,
+            ..config()
+        });
+        let guard = Arc::new(guard);
+        let guesses = Arc::new(AtomicU64::new(0));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let guard = Arc::clone(&guard);
+                let guesses = Arc::clone(&guesses);
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let _ = guard.attempt("admin", "a", || {
+                            guesses.fetch_add(1, Ordering::Relaxed);
+                            false
+                        });
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(guesses.load(Ordering::Relaxed), 4);
+        assert_eq!(guard.stats().lockouts, 1);
+        assert!(guard.is_locked("admin"));
+    }
+}
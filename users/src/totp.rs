@@ -0,0 +1,517 @@
+// Time based one time passwords (RFC 6238), the 6 digit codes of authenticator apps.
+//
+// the user and the server share a random secret. both take the number of 30 second steps
+// since the unix epoch, HMAC it with the secret and cut the result down to a few digits.
+// the server accepts the step before and after the current one too, phone clocks drift.
+// a code that was accepted once is never accepted again (last_step), so a code seen over
+// someone's shoulder is useless.
+//
+// the secret is handed to the app as base32 text or as an otpauth:// uri in a QR code.
+// recovery codes are for a lost phone, each works once and only salted, slow hashes of
+// them are kept, the same PBKDF2 as passwords (password.rs).
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    clock::Clock,
+    password::{hash_password_with_rounds, is_hashed, verify_password},
+    secret::{self, Secret},
+};
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 base32 without padding, what authenticator apps expect
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([
+            0, 0, 0, buffer[0], buffer[1], buffer[2], buffer[3], buffer[4],
+        ]);
+        // 8 bits in makes 8/5 characters out, rounded up
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            out.push(BASE32[index as usize] as char);
+        }
+    }
+    out
+}
+
+// accepts lower case, spaces and padding the way people copy secrets around
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32
+            .iter()
+            .position(|&b| b as char == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+// what goes into a uri, everything but the unreserved characters is percent encoded
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// SHA1 is what every authenticator app supports, the others are in the RFC too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Sha1 => write!(f, "SHA1"),
+            Algorithm::Sha256 => write!(f, "SHA256"),
+            Algorithm::Sha512 => write!(f, "SHA512"),
+        }
+    }
+}
+
+fn hmac(algorithm: Algorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+    fn run<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut mac = <M as hmac::digest::KeyInit>::new_from_slice(key)
+            .expect("hmac takes keys of any length");
+        mac.update(message);
+        mac.finalize().into_bytes().to_vec()
+    }
+    match algorithm {
+        Algorithm::Sha1 => run::<Hmac<sha1::Sha1>>(key, message),
+        Algorithm::Sha256 => run::<Hmac<Sha256>>(key, message),
+        Algorithm::Sha512 => run::<Hmac<Sha512>>(key, message),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TotpError {
+    InvalidSecret,
+    InvalidDigits(u32),
+    // a step of 0 seconds, only a file can hold one
+    InvalidPeriod,
+    WrongCode,
+    // the code was right but has been used already
+    Replayed,
+}
+
+impl fmt::Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TotpError::InvalidSecret => write!(f, "the secret is not valid base32"),
+            TotpError::InvalidDigits(digits) => {
+                write!(f, "codes have 6 or 8 digits, not {digits}")
+            }
+            TotpError::InvalidPeriod => write!(f, "the period has to be at least a second"),
+            TotpError::WrongCode => write!(f, "wrong code"),
+            TotpError::Replayed => write!(f, "this code has already been used"),
+        }
+    }
+}
+
+impl std::error::Error for TotpError {}
+
+// deserializing goes through the same checks as new (see TotpRecord)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "TotpRecord")]
+pub struct Totp {
+    // base32, as shown to the user. whoever has it can make codes, so {:?} does not show it
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    secret: Secret<String>,
+    digits: u32,
+    #[serde(default)]
+    algorithm: Algorithm,
+    period: u64,
+    // the step of the last accepted code, codes from it or before are rejected
+    #[serde(default)]
+    last_step: Option<u64>,
+}
+
+// a Totp as it is stored, before it has been checked
+#[derive(Deserialize)]
+struct TotpRecord {
+    secret: String,
+    digits: u32,
+    #[serde(default)]
+    algorithm: Algorithm,
+    period: u64,
+    #[serde(default)]
+    last_step: Option<u64>,
+}
+
+impl TryFrom<TotpRecord> for Totp {
+    type Error = TotpError;
+
+    fn try_from(record: TotpRecord) -> Result<Self, Self::Error> {
+        if record.period == 0 {
+            return Err(TotpError::InvalidPeriod);
+        }
+        let totp = Totp::new(&record.secret, record.digits, record.algorithm)?;
+        Ok(Totp {
+            period: record.period,
+            last_step: record.last_step,
+            ..totp
+        })
+    }
+}
+
+impl Totp {
+    pub const PERIOD: u64 = 30;
+
+    // a new random 160 bit secret, the size RFC 4226 recommends
+    pub fn generate(digits: u32) -> Result<Totp, TotpError> {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        Totp::new(&base32_encode(&secret), digits, Algorithm::Sha1)
+    }
+
+    pub fn new(secret: &str, digits: u32, algorithm: Algorithm) -> Result<Totp, TotpError> {
+        if digits != 6 && digits != 8 {
+            return Err(TotpError::InvalidDigits(digits));
+        }
+        match base32_decode(secret) {
+            Some(bytes) if !bytes.is_empty() => Ok(Totp {
+                secret: Secret::new(base32_encode(&bytes)),
+                digits,
+                algorithm,
+                period: Totp::PERIOD,
+                last_step: None,
+            }),
+            _ => Err(TotpError::InvalidSecret),
+        }
+    }
+
+    pub fn secret(&self) -> &str {
+        self.secret.expose()
+    }
+
+    // otpauth://totp/Issuer:account?secret=...&issuer=Issuer&algorithm=SHA1&digits=6&period=30
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            self.secret.expose(),
+            percent_encode(issuer),
+            self.algorithm,
+            self.digits,
+            self.period
+        )
+    }
+
+    fn code_for_step(&self, step: u64) -> String {
+        let key = base32_decode(self.secret.expose()).expect("checked in new");
+        let hash = hmac(self.algorithm, &key, &step.to_be_bytes());
+        // dynamic truncation (RFC 4226 5.3): the last nibble picks 4 bytes out of the hash
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        let code = binary % 10u32.pow(self.digits);
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+
+    // the code for a time in seconds since the unix epoch
+    pub fn code_at(&self, unix_secs: u64) -> String {
+        self.code_for_step(unix_secs / self.period)
+    }
+
+    pub fn now(&self, clock: &impl Clock) -> String {
+        self.code_at(unix_secs(clock))
+    }
+
+    // accepts the code of the current step and of the one before and after it
+    pub fn verify(&mut self, code: &str, clock: &impl Clock) -> Result<(), TotpError> {
+        let current = unix_secs(clock) / self.period;
+        let code = code.trim();
+        let mut matched = None;
+        // every step is compared, in constant time, so timing says nothing about which matched
+        for step in current.saturating_sub(1)..=current + 1 {
+            let expected = self.code_for_step(step);
+            if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) {
+                matched = Some(step);
+            }
+        }
+        let step = matched.ok_or(TotpError::WrongCode)?;
+        if self.last_step.is_some_and(|last| step <= last) {
+            return Err(TotpError::Replayed);
+        }
+        self.last_step = Some(step);
+        Ok(())
+    }
+}
+
+fn unix_secs(clock: &impl Clock) -> u64 {
+    clock
+        .now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// 80 random bits, 16 base32 characters. a code that gets through replaces the phone, so it
+// has to be as hard to guess as a good password.
+const RECOVERY_CODE_BYTES: usize = 10;
+const RECOVERY_CODE_CHARS: usize = RECOVERY_CODE_BYTES * 8 / 5;
+
+// the codes are random, unlike passwords they cannot be guessed from a list, so fewer rounds
+// than for passwords do. still salted and slow, whoever has a copy of the file has to
+// guess each code on its own. a wrong code is checked against every hash that is left.
+const RECOVERY_ROUNDS: u32 = 100_000;
+
+// as the user may type it: upper case, without the dashes
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    hashes: Vec<String>,
+}
+
+impl RecoveryCodes {
+    // returns the codes to show the user once, only their hashes are kept
+    pub fn generate(count: usize) -> (RecoveryCodes, Vec<String>) {
+        let codes: Vec<String> = (0..count)
+            .map(|_| {
+                let mut bytes = [0u8; RECOVERY_CODE_BYTES];
+                OsRng.fill_bytes(&mut bytes);
+                let code = base32_encode(&bytes).to_ascii_lowercase();
+                // xxxx-xxxx-xxxx-xxxx
+                let groups: Vec<&str> = (0..code.len())
+                    .step_by(4)
+                    .map(|start| &code[start..start + 4])
+                    .collect();
+                groups.join("-")
+            })
+            .collect();
+        let hashes = codes
+            .iter()
+            .map(|code| hash_password_with_rounds(&normalize_recovery_code(code), RECOVERY_ROUNDS))
+            .collect();
+        (RecoveryCodes { hashes }, codes)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.hashes.len()
+    }
+
+    // true if the code was one of them, it is gone afterwards
+    pub fn redeem(&mut self, code: &str) -> bool {
+        let code = normalize_recovery_code(code);
+        // verify_password would compare anything that is not a hash as plaintext
+        match self
+            .hashes
+            .iter()
+            .position(|stored| is_hashed(stored) && verify_password(&code, stored))
+        {
+            Some(index) => {
+                self.hashes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// everything the second factor of one user needs, kept next to the user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecondFactor {
+    pub totp: Totp,
+    pub recovery: RecoveryCodes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verified {
+    Totp,
+    RecoveryCode,
+}
+
+impl SecondFactor {
+    // a code from the app or, failing that, a recovery code. only something shaped like one
+    // is hashed against the codes that are left, a wrong app code costs no rounds
+    pub fn verify(&mut self, input: &str, clock: &impl Clock) -> Result<Verified, TotpError> {
+        let recovery_shaped = normalize_recovery_code(input).len() == RECOVERY_CODE_CHARS;
+        match self.totp.verify(input, clock) {
+            Ok(()) => Ok(Verified::Totp),
+            Err(TotpError::WrongCode) if recovery_shaped && self.recovery.redeem(input) => {
+                Ok(Verified::RecoveryCode)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        let cases = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (plain, encoded) in cases {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZ1"), None);
+    }
+
+    // RFC 6238 appendix B, the seeds are ascii digits of the hash's block size
+    #[test]
+    fn rfc_6238_test_vectors() {
+        let seeds = [
+            (Algorithm::Sha1, "12345678901234567890".to_string()),
+            (Algorithm::Sha256, "1234567890".repeat(3) + "12"),
+            (Algorithm::Sha512, "1234567890".repeat(6) + "1234"),
+        ];
+        let vectors: [(u64, [&str; 3]); 6] = [
+            (59, ["94287082", "46119246", "90693936"]),
+            (1111111109, ["07081804", "68084774", "25091201"]),
+            (1111111111, ["14050471", "67062674", "99943326"]),
+            (1234567890, ["89005924", "91819424", "93441116"]),
+            (2000000000, ["69279037", "90698825", "38618901"]),
+            (20000000000, ["65353130", "77737706", "47863826"]),
+        ];
+        for (time, codes) in vectors {
+            for ((algorithm, seed), code) in seeds.iter().zip(codes) {
+                let totp = Totp::new(&base32_encode(seed.as_bytes()), 8, *algorithm).unwrap();
+                assert_eq!(totp.code_at(time), code, "{algorithm} at {time}");
+                let clock = ManualClock::at_unix(time);
+                assert_eq!(totp.now(&clock), code);
+            }
+        }
+        // 6 digits are the last 6 of the 8
+        let totp = Totp::new(&base32_encode(b"12345678901234567890"), 6, Algorithm::Sha1).unwrap();
+        assert_eq!(totp.code_at(59), "287082");
+    }
+
+    #[test]
+    fn accepts_one_step_either_side_and_never_twice() {
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let mut totp = Totp::generate(6).unwrap();
+        let previous = totp.code_at(1_700_000_000 - 30);
+        let next = totp.code_at(1_700_000_000 + 30);
+        let too_old = totp.code_at(1_700_000_000 - 60);
+
+        assert_eq!(totp.verify(&too_old, &clock), Err(TotpError::WrongCode));
+        totp.verify(&previous, &clock).unwrap();
+        assert_eq!(totp.verify(&previous, &clock), Err(TotpError::Replayed));
+        totp.verify(&next, &clock).unwrap();
+        // the current step is before the one already used
+        let current = totp.now(&clock);
+        assert_eq!(totp.verify(&current, &clock), Err(TotpError::Replayed));
+
+        clock.advance(Duration::from_secs(60));
+        let current = totp.now(&clock);
+        totp.verify(&current, &clock).unwrap();
+    }
+
+    #[test]
+    fn provisioning_uri_has_everything_the_app_needs() {
+        let totp = Totp::new("JBSWY3DPEHPK3PXP", 6, Algorithm::Sha1).unwrap();
+        assert!(!format!("{totp:?}").contains("JBSWY3DPEHPK3PXP"));
+        // stored as it is, the server needs it to check codes
+        let stored = serde_json::to_string(&totp).unwrap();
+        assert!(stored.contains(r#""secret":"JBSWY3DPEHPK3PXP""#));
+        assert_eq!(serde_json::from_str::<Totp>(&stored).unwrap(), totp);
+        assert_eq!(
+            totp.provisioning_uri("Learning Rust", "admin@localhost"),
+            "otpauth://totp/Learning%20Rust:admin%40localhost?secret=JBSWY3DPEHPK3PXP\
+             &issuer=Learning%20Rust&algorithm=SHA1&digits=6&period=30"
+        );
+        assert_eq!(
+            Totp::new("JBSWY3DPEHPK3PXP", 7, Algorithm::Sha1),
+            Err(TotpError::InvalidDigits(7))
+        );
+        assert_eq!(
+            Totp::new("not base32!", 6, Algorithm::Sha1),
+            Err(TotpError::InvalidSecret)
+        );
+    }
+
+    #[test]
+    fn recovery_codes_are_hashed_and_single_use() {
+        let clock = ManualClock::at_unix(1_700_000_000);
+        let (recovery, codes) = RecoveryCodes::generate(10);
+        assert_eq!(codes.len(), 10);
+        let stored = serde_json::to_string(&recovery).unwrap();
+        assert!(codes.iter().all(|code| !stored.contains(code.as_str())));
+        assert!(recovery.hashes.iter().all(|hash| is_hashed(hash)));
+        // 16 characters of base32 are 80 bits
+        assert!(codes
+            .iter()
+            .all(|code| code.len() == 19 && normalize_recovery_code(code).len() == 16));
+
+        let mut factor = SecondFactor {
+            totp: Totp::generate(6).unwrap(),
+            recovery,
+        };
+        // typed back in upper case and without the dash
+        let typed = codes[3].replace('-', "").to_uppercase();
+        assert_eq!(factor.verify(&typed, &clock), Ok(Verified::RecoveryCode));
+        assert_eq!(factor.verify(&codes[3], &clock), Err(TotpError::WrongCode));
+        assert_eq!(factor.recovery.remaining(), 9);
+
+        let code = factor.totp.now(&clock);
+        assert_eq!(factor.verify(&code, &clock), Ok(Verified::Totp));
+        // not shaped like a recovery code, the hashes are not even tried
+        assert_eq!(factor.verify("123456", &clock), Err(TotpError::WrongCode));
+    }
+
+    #[test]
+    fn stored_totp_goes_through_the_same_checks() {
+        let good = Totp::new("JBSWY3DPEHPK3PXP", 6, Algorithm::Sha1).unwrap();
+        let json = serde_json::to_value(&good).unwrap();
+        let back: Totp = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(back, good);
+
+        let with = |field: &str, value: serde_json::Value| {
+            let mut json = json.clone();
+            json[field] = value;
+            serde_json::from_value::<Totp>(json)
+        };
+        // each of these would divide by zero, overflow 10^digits or panic in code_for_step
+        assert!(with("period", 0.into()).is_err());
+        assert!(with("digits", 10.into()).is_err());
+        assert!(with("secret", "not base32!".into()).is_err());
+        assert!(with("secret", "".into()).is_err());
+        assert_eq!(with("period", 60.into()).unwrap().period, 60);
+    }
+}
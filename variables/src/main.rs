@@ -31,6 +31,7 @@ fn main() {
     // return type is the unit type '()'
     // everything in rust returns a type whether you explicitly say it or not.
     // unit type is basically saying nothing
+    #[allow(clippy::let_unit_value)] // binding a unit is the point here
     let _n = {
         let _x = 5;
     };
@@ -2,38 +2,36 @@
 // They live on heap hence slightly slower to access as pointer is involved.
 // They are fast and very similar to C++ vector just safe by default (as long as unsafe methods not used).
 // Queues/Stack etc all use vectors underhood.
-#[derive(Debug, PartialEq)]
-enum Role {
-    Admin,
-    User,
-}
-
-#[derive(Debug, PartialEq)]
-struct User {
-    username: String,
-    email: String,
-    password: String,
-    role: Role,
-}
-
-impl User {
-    fn new(username: &str, email: &str, password: &str, role: Role) -> User {
-        Self {
-            username: username.to_lowercase(),
-            email: email.to_lowercase(),
-            password: password.to_string(),
-            role,
-        }
-    }
-}
+// User and Role come from the shared users crate
+use users::{
+    email::{self, Canonicalization},
+    Role, User,
+};
 
 fn get_users() -> Vec<User> {
-    vec![User::new("user", "user", "password", Role::User)]
+    vec![User::new("user", "user@localhost", "password", Role::User)]
 }
 
 fn main() {
     let mut users = get_users();
-    users.push(User::new("admin", "admin", "password", Role::Admin));
+    users.push(User::new(
+        "admin",
+        "admin@localhost",
+        "password",
+        Role::Admin,
+    ));
+    users.push(User::new(
+        "admin2",
+        "Admin+backup@localhost",
+        "password",
+        Role::Admin,
+    ));
+
+    // admin+backup@ and admin@ are the same inbox, iter borrows so users can still be moved below
+    for (address, same) in email::duplicates(users.iter(), Canonicalization::default()) {
+        let names: Vec<&str> = same.iter().map(|user| user.username.as_str()).collect();
+        println!("{address} is used by {names:?}");
+    }
 
     // into_iter moves unlike iter which gives a reference.
     let _admin_users = users